@@ -0,0 +1,63 @@
+//! Runs a lisp program
+//!
+//! The program is read from the file given as an argument, or from stdin if
+//! there is none. Anything the program wants to show has to be printed with
+//! `display`.
+//!
+//! Pass `--vm` to compile the program to bytecode and run it on the virtual
+//! machine instead of the tree-walking interpreter, or `--disassemble` to
+//! print the bytecode without running it.
+
+use csh_seminar_feb_2021::compiler;
+use csh_seminar_feb_2021::diagnostic::{self, Diagnostic};
+use csh_seminar_feb_2021::eval::{EvalError, Interpreter};
+use csh_seminar_feb_2021::lexer::Lexer;
+use csh_seminar_feb_2021::parser;
+use csh_seminar_feb_2021::vm::Vm;
+use std::io::Read;
+
+fn main() -> std::io::Result<()> {
+    let (flags, paths): (Vec<String>, Vec<String>) = std::env::args()
+        .skip(1)
+        .partition(|arg| arg.starts_with("--"));
+    let (file_name, input) = match paths.into_iter().next() {
+        Some(path) => {
+            let input = std::fs::read_to_string(&path)?;
+            (path, input)
+        }
+        None => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            ("<stdin>".to_string(), input)
+        }
+    };
+
+    // Report every lexing mistake at once, rather than one per run
+    let color = diagnostic::stderr_wants_color();
+    let errors: Vec<_> = Lexer::new(&input).filter_map(Result::err).collect();
+    for error in &errors {
+        eprint!("{}", Diagnostic::from(error).render(&file_name, &input, color));
+    }
+    if !errors.is_empty() {
+        std::process::exit(1);
+    }
+
+    let result = if flags.iter().any(|flag| flag == "--disassemble") {
+        let mut macros = Interpreter::new();
+        parser::parse(&input)
+            .map_err(EvalError::from)
+            .and_then(|exprs| exprs.iter().map(|expr| macros.expand(expr)).collect::<Result<Vec<_>, _>>())
+            .and_then(|exprs| compiler::compile(&exprs))
+            .map(|function| print!("{}", compiler::disassemble(&function)))
+    } else if flags.iter().any(|flag| flag == "--vm") {
+        Vm::new().eval_str(&input).map(drop)
+    } else {
+        Interpreter::new().eval_str(&input).map(drop)
+    };
+    if let Err(error) = result {
+        eprint!("{}", Diagnostic::from(&error).render(&file_name, &input, color));
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Formats lisp files in the canonical style
+//!
+//! With no files, reads code from stdin and prints the formatted code to
+//! stdout. Otherwise each file is formatted in place.
+//!
+//! Pass `--width <columns>` to change the line width from the default 80,
+//! and `--check` to only check whether the files are formatted, without
+//! changing them. With `--check` the exit code is 1 if any file would be
+//! reformatted, which makes it easy to use from a pre-commit hook.
+
+use csh_seminar_feb_2021::diagnostic::{self, Diagnostic};
+use csh_seminar_feb_2021::format::Formatter;
+use std::io::{Read, Write};
+
+const USAGE: &str = "usage: lispfmt [--check] [--width <columns>] [files...]";
+
+fn main() -> std::io::Result<()> {
+    let mut check = false;
+    let mut formatter = Formatter::new();
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--width" => match args.next().and_then(|width| width.parse().ok()) {
+                Some(width) => formatter = formatter.with_width(width),
+                None => usage_error("--width needs a number of columns"),
+            },
+            "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            flag if flag.starts_with("--") => usage_error(&format!("unknown flag `{}`", flag)),
+            path => paths.push(path.to_string()),
+        }
+    }
+
+    let color = diagnostic::stderr_wants_color();
+    let mut failed = false;
+    if paths.is_empty() {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+        match formatter.format(&input) {
+            Ok(formatted) if check => failed = formatted != input,
+            Ok(formatted) => std::io::stdout().write_all(formatted.as_bytes())?,
+            Err(error) => {
+                eprint!("{}", Diagnostic::from(&error).render("<stdin>", &input, color));
+                failed = true;
+            }
+        }
+    }
+
+    for path in &paths {
+        let input = std::fs::read_to_string(path)?;
+        match formatter.format(&input) {
+            Ok(formatted) if formatted == input => (),
+            Ok(_) if check => {
+                println!("would reformat {}", path);
+                failed = true;
+            }
+            Ok(formatted) => std::fs::write(path, formatted)?,
+            Err(error) => {
+                eprint!("{}", Diagnostic::from(&error).render(path, &input, color));
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("error: {}\n{}", message, USAGE);
+    std::process::exit(2);
+}
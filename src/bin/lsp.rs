@@ -0,0 +1,740 @@
+//! A language server for the little lisp
+//!
+//! This speaks the Language Server Protocol (LSP) over stdin and stdout, so
+//! any editor with an LSP client can use it. It gives editors:
+//!
+//! - errors from the lexer and parser, as you type
+//! - semantic highlighting of special forms, procedures, variables and literals
+//! - an outline of the top-level `define`s in a file
+//! - go to definition and hover for variables
+//! - highlighting of the matching paren under the cursor
+//!
+//! Every message is a JSON object after a `Content-Length` header, as the
+//! protocol describes at <https://microsoft.github.io/language-server-protocol/>.
+
+use csh_seminar_feb_2021::diagnostic::Diagnostic;
+use csh_seminar_feb_2021::eval::{BUILTINS, SPECIAL_FORMS};
+use csh_seminar_feb_2021::incremental::Edit;
+use csh_seminar_feb_2021::lexer::{Lexer, Span, Token, TokenType};
+use csh_seminar_feb_2021::parser::{Expr, ExprKind, Parser};
+use csh_seminar_feb_2021::symbol::Symbol;
+use serde_json::{json, Value as Json};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+/// The kinds of semantic token that we tell the editor about, in the order
+/// that the protocol refers to them by
+const TOKEN_TYPES: &[&str] = &["keyword", "function", "variable", "number", "string", "comment", "operator"];
+
+fn main() -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    Server::default().run(stdin.lock(), stdout.lock())
+}
+
+/// Reads one message, or returns `None` if the client has gone away
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "message has no Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[derive(Default)]
+struct Server {
+    /// The text of every open document, by URI
+    documents: HashMap<String, String>,
+    /// Set when the client says `exit`
+    exited: bool,
+}
+
+impl Server {
+    fn run(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        while let Some(message) = read_message(&mut input)? {
+            for reply in self.handle(&message) {
+                write_message(&mut output, &reply)?;
+            }
+            if self.exited {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles one message from the client, returning the messages to send back
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            // Notifications don't get a response
+            None => {
+                return match method {
+                    "exit" => {
+                        self.exited = true;
+                        Vec::new()
+                    }
+                    "textDocument/didOpen" => {
+                        let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                        self.documents.insert(uri.clone(), text.to_string());
+                        vec![self.diagnostics(&uri)]
+                    }
+                    "textDocument/didChange" => {
+                        self.change(&uri, &params["contentChanges"]);
+                        vec![self.diagnostics(&uri)]
+                    }
+                    "textDocument/didClose" => {
+                        self.documents.remove(&uri);
+                        vec![notification("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": [] }))]
+                    }
+                    _ => Vec::new(),
+                };
+            }
+        };
+
+        let text = self.documents.get(&uri).map(String::as_str);
+        let position = &params["position"];
+        let result = match (method, text) {
+            ("initialize", _) => json!({
+                "capabilities": {
+                    "positionEncoding": "utf-16",
+                    "textDocumentSync": { "openClose": true, "change": 2 },
+                    "semanticTokensProvider": {
+                        "legend": { "tokenTypes": TOKEN_TYPES, "tokenModifiers": [] },
+                        "full": true,
+                    },
+                    "documentSymbolProvider": true,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "documentHighlightProvider": true,
+                },
+                "serverInfo": { "name": "lisp-lsp", "version": env!("CARGO_PKG_VERSION") },
+            }),
+            ("shutdown", _) => Json::Null,
+            ("textDocument/semanticTokens/full", Some(text)) => json!({ "data": semantic_tokens(text) }),
+            ("textDocument/documentSymbol", Some(text)) => document_symbols(text),
+            ("textDocument/definition", Some(text)) => definition(text, &uri, position),
+            ("textDocument/hover", Some(text)) => hover(text, position),
+            ("textDocument/documentHighlight", Some(text)) => matching_parens(text, position),
+            // Requests about documents that were never opened have no answer
+            (_, None) if method.starts_with("textDocument/") => Json::Null,
+            _ => {
+                let error = json!({ "code": -32601, "message": format!("unknown method `{}`", method) });
+                return vec![json!({ "jsonrpc": "2.0", "id": id, "error": error })];
+            }
+        };
+        vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })]
+    }
+
+    /// Applies the changes from a `didChange` notification to a document
+    fn change(&mut self, uri: &str, changes: &Json) {
+        let text = match self.documents.get_mut(uri) {
+            Some(text) => text,
+            None => return,
+        };
+        for change in changes.as_array().into_iter().flatten() {
+            let new_text = change["text"].as_str().unwrap_or("");
+            // A change without a range replaces the whole document
+            let range = match change.get("range") {
+                Some(range) => {
+                    // The offsets are always inside the text, but a confused
+                    // client could still send the end before the start
+                    let index = LineIndex::new(text);
+                    let (start, end) = (index.offset(&range["start"]), index.offset(&range["end"]));
+                    start.min(end)..start.max(end)
+                }
+                None => 0..text.len(),
+            };
+            *text = Edit::new(range, new_text).apply(text);
+        }
+    }
+
+    /// Lexes and parses a document, and tells the client what is wrong with it
+    fn diagnostics(&self, uri: &str) -> Json {
+        let text = self.documents.get(uri).map_or("", String::as_str);
+        let index = LineIndex::new(text);
+
+        // Lexing errors don't stop the lexer, so there is no harm in showing
+        // all of them. Parse errors only make sense once the tokens are right.
+        let mut diagnostics: Vec<Diagnostic> = Lexer::new(text)
+            .filter_map(Result::err)
+            .map(|error| Diagnostic::from(&error))
+            .collect();
+        if diagnostics.is_empty() {
+            let error = Parser::new(Lexer::new(text)).find_map(Result::err);
+            diagnostics.extend(error.map(|error| Diagnostic::from(&error)));
+        }
+
+        let diagnostics: Vec<Json> = diagnostics.iter().filter_map(|diagnostic| {
+            let span = diagnostic.labels.iter().find(|label| label.primary)?.span;
+            let mut message = diagnostic.message.clone();
+            for note in &diagnostic.notes {
+                message.push_str(&format!("\nnote: {}", note));
+            }
+            for help in &diagnostic.help {
+                message.push_str(&format!("\nhelp: {}", help));
+            }
+            let related: Vec<Json> = diagnostic.labels.iter()
+                .filter(|label| !label.primary)
+                .map(|label| json!({
+                    "location": { "uri": uri, "range": index.range(label.span) },
+                    "message": label.message,
+                }))
+                .collect();
+            Some(json!({
+                "range": index.range(span),
+                "severity": 1,
+                "source": "lisp",
+                "message": message,
+                "relatedInformation": related,
+            }))
+        }).collect();
+
+        notification("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": diagnostics }))
+    }
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+/// Converts between byte offsets and the line and column positions that LSP uses
+///
+/// LSP counts columns in UTF-16 code units, because that is how JavaScript
+/// strings work. So `"日"` is one column, like it is for us, but `"🦀"` is
+/// two, since it takes two UTF-16 code units to write.
+struct LineIndex<'t> {
+    text: &'t str,
+    /// The byte offset where each line starts
+    line_starts: Vec<usize>,
+}
+
+impl<'t> LineIndex<'t> {
+    fn new(text: &'t str) -> LineIndex<'t> {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        LineIndex { text, line_starts }
+    }
+
+    /// The LSP position of a byte offset, with lines and columns counting from 0
+    fn position(&self, offset: usize) -> Json {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let character: usize = self.text[self.line_starts[line]..offset].chars().map(char::len_utf16).sum();
+        json!({ "line": line, "character": character })
+    }
+
+    fn range(&self, span: Span) -> Json {
+        json!({ "start": self.position(span.start), "end": self.position(span.end) })
+    }
+
+    /// The byte offset of an LSP position
+    ///
+    /// Like the protocol asks, a column past the end of its line means the
+    /// end of that line, and a line past the end of the text means the end
+    /// of the text.
+    fn offset(&self, position: &Json) -> usize {
+        let line = position["line"].as_u64().unwrap_or(0) as usize;
+        let mut character = position["character"].as_u64().unwrap_or(0) as usize;
+        let start = match self.line_starts.get(line) {
+            Some(&start) => start,
+            None => return self.text.len(),
+        };
+        let mut line_end = self.line_starts.get(line + 1).map_or(self.text.len(), |&next| next - 1);
+        if self.text[..line_end].ends_with('\r') {
+            line_end -= 1;
+        }
+        for (index, ch) in self.text[start..line_end].char_indices() {
+            if character < ch.len_utf16() {
+                return start + index;
+            }
+            character -= ch.len_utf16();
+        }
+        line_end
+    }
+}
+
+/// The kind of semantic token that an identifier is
+fn identifier_type(name: &str) -> &'static str {
+    if SPECIAL_FORMS.contains(&name) {
+        "keyword"
+    } else if BUILTINS.iter().any(|builtin| builtin.name == name) {
+        "function"
+    } else {
+        "variable"
+    }
+}
+
+/// Encodes the tokens of a document the way `textDocument/semanticTokens` wants them
+///
+/// Each token becomes five numbers: how many lines down it is from the last
+/// token, its column (counted from the last token if they are on the same
+/// line), its length, its type, and its modifiers. Not every editor handles
+/// tokens that span lines, so those are split up into one token per line.
+fn semantic_tokens(text: &str) -> Vec<usize> {
+    let index = LineIndex::new(text);
+    let mut data = Vec::new();
+    let (mut last_line, mut last_column) = (0, 0);
+    for token in Lexer::new(text).with_comments().filter_map(Result::ok) {
+        let token_type = match &token.token {
+            TokenType::Identifier(ident) => identifier_type(&ident.0),
+            TokenType::Boolean(_) => "keyword",
+            TokenType::Number(_) => "number",
+            TokenType::Str(_) => "string",
+            TokenType::Comment(_) => "comment",
+            TokenType::Quote | TokenType::Quasiquote | TokenType::Unquote | TokenType::UnquoteSplicing => "operator",
+            _ => continue,
+        };
+        let token_type = TOKEN_TYPES.iter().position(|&name| name == token_type).unwrap_or(0);
+
+        let mut offset = token.span.start;
+        for piece in token.source.split('\n') {
+            let position = index.position(offset);
+            let line = position["line"].as_u64().unwrap_or(0) as usize;
+            let column = position["character"].as_u64().unwrap_or(0) as usize;
+            let length: usize = piece.trim_end_matches('\r').chars().map(char::len_utf16).sum();
+            if length > 0 {
+                let delta_column = if line == last_line { column - last_column } else { column };
+                data.extend_from_slice(&[line - last_line, delta_column, length, token_type, 0]);
+                last_line = line;
+                last_column = column;
+            }
+            offset += piece.len() + 1;
+        }
+    }
+    data
+}
+
+/// Finds the top-level `define`s, for the editor's outline of the document
+fn document_symbols(text: &str) -> Json {
+    let index = LineIndex::new(text);
+    let symbols: Vec<Json> = Parser::new(Lexer::new(text))
+        .filter_map(Result::ok)
+        .filter_map(|expr| {
+            let (name, procedure) = defined_name(&expr)?;
+            // The protocol's numbers for a function and a variable
+            let kind = if procedure { 12 } else { 13 };
+            Some(json!({
+                "name": name.to_string(),
+                "kind": kind,
+                "range": index.range(expr.span),
+                "selectionRange": index.range(name.span),
+            }))
+        })
+        .collect();
+    Json::Array(symbols)
+}
+
+/// If the expression is a `define`, the name it defines and whether it is a procedure
+fn defined_name(expr: &Expr) -> Option<(&Expr, bool)> {
+    let items = match &expr.kind {
+        ExprKind::List(items) => items,
+        _ => return None,
+    };
+    match items.as_slice() {
+        [head, target, rest @ ..] if is_symbol(head, Symbol::DEFINE) => match &target.kind {
+            ExprKind::Symbol(_) => {
+                let lambda = rest.first().is_some_and(|value| match &value.kind {
+                    ExprKind::List(items) => items.first().is_some_and(|head| is_symbol(head, Symbol::LAMBDA)),
+                    _ => false,
+                });
+                Some((target, lambda))
+            }
+            ExprKind::List(signature) => signature.first()
+                .filter(|name| matches!(name.kind, ExprKind::Symbol(_)))
+                .map(|name| (name, true)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_symbol(expr: &Expr, name: Symbol) -> bool {
+    matches!(&expr.kind, ExprKind::Symbol(symbol) if *symbol == name)
+}
+
+/// The identifier token under the cursor, if there is one
+fn identifier_at(text: &str, offset: usize) -> Option<Token<'_>> {
+    Lexer::new(text)
+        .filter_map(Result::ok)
+        .take_while(|token| token.span.start <= offset)
+        .find(|token| token.span.end >= offset && matches!(token.token, TokenType::Identifier(_)))
+}
+
+/// The variables bound by one form, like the parameters of a `lambda`
+type Scope = Vec<(Symbol, Span)>;
+
+/// Finds where the variable used at `offset` is defined
+///
+/// We walk down the tree towards `offset`, keeping track of the variables
+/// that each form we pass through binds. When we reach the variable, the
+/// innermost binding with its name is the one it refers to.
+fn find_definition(exprs: &[Expr], offset: usize) -> Option<Span> {
+    let mut scopes = vec![defines_in(exprs)];
+    resolve_body(exprs, offset, &mut scopes)
+}
+
+/// The names defined by the `define`s directly inside a body, which can be
+/// used anywhere in that body
+fn defines_in(body: &[Expr]) -> Scope {
+    body.iter()
+        .filter_map(defined_name)
+        .filter_map(|(name, _)| match &name.kind {
+            ExprKind::Symbol(symbol) => Some((*symbol, name.span)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The variables in a parameter list like `(a b . rest)`, or a lone `args`
+fn parameters(params: &Expr) -> Scope {
+    match &params.kind {
+        ExprKind::Symbol(name) => vec![(*name, params.span)],
+        ExprKind::List(items) => items.iter()
+            .filter_map(|item| match &item.kind {
+                ExprKind::Symbol(name) if *name != Symbol::DOT => Some((*name, item.span)),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn resolve_body(body: &[Expr], offset: usize, scopes: &mut Vec<Scope>) -> Option<Span> {
+    let expr = body.iter().find(|expr| expr.span.start <= offset && offset <= expr.span.end)?;
+    resolve(expr, offset, scopes)
+}
+
+/// Runs `f` with an extra scope, taking it away again afterwards
+fn with_scope<T>(scopes: &mut Vec<Scope>, scope: Scope, f: impl FnOnce(&mut Vec<Scope>) -> T) -> T {
+    scopes.push(scope);
+    let result = f(scopes);
+    scopes.pop();
+    result
+}
+
+fn resolve(expr: &Expr, offset: usize, scopes: &mut Vec<Scope>) -> Option<Span> {
+    let items = match &expr.kind {
+        ExprKind::Symbol(name) => {
+            return scopes.iter().rev()
+                .find_map(|scope| scope.iter().rev().find(|(bound, _)| bound == name))
+                .map(|&(_, span)| span);
+        }
+        ExprKind::List(items) => items,
+        _ => return None,
+    };
+    let head = match items.first() {
+        Some(Expr { kind: ExprKind::Symbol(head), .. }) => *head,
+        _ => return resolve_body(items, offset, scopes),
+    };
+
+    match (head, items.get(1)) {
+        (Symbol::QUOTE, _) => None,
+        (Symbol::LAMBDA, Some(params)) => {
+            let mut scope = parameters(params);
+            scope.extend(defines_in(&items[2..]));
+            with_scope(scopes, scope, |scopes| resolve_body(&items[1..], offset, scopes))
+        }
+        (Symbol::DEFINE, Some(Expr { kind: ExprKind::List(signature), .. })) => {
+            let (name, params) = signature.split_first()?;
+            if name.span.start <= offset && offset <= name.span.end {
+                return resolve(name, offset, scopes);
+            }
+            let mut scope: Scope = params.iter().flat_map(parameters).collect();
+            scope.extend(defines_in(&items[2..]));
+            with_scope(scopes, scope, |scopes| resolve_body(&items[1..], offset, scopes))
+        }
+        (Symbol::LET, Some(bindings)) | (Symbol::LET_STAR, Some(bindings)) | (Symbol::LETREC, Some(bindings)) => {
+            let bindings = match &bindings.kind {
+                ExprKind::List(bindings) => bindings.as_slice(),
+                _ => &[],
+            };
+            let names: Vec<Scope> = bindings.iter()
+                .map(|binding| match &binding.kind {
+                    ExprKind::List(pair) => pair.first().map(parameters).unwrap_or_default(),
+                    _ => Vec::new(),
+                })
+                .collect();
+
+            // Which of the bound names each binding's value can see
+            for (index, binding) in bindings.iter().enumerate() {
+                if !(binding.span.start <= offset && offset <= binding.span.end) {
+                    continue;
+                }
+                let visible = match head {
+                    Symbol::LET => 0,
+                    Symbol::LET_STAR => index,
+                    _ => bindings.len(),
+                };
+                let mut scope: Scope = names[..visible].concat();
+                scope.extend(names[index].iter().copied());
+                return with_scope(scopes, scope, |scopes| match &binding.kind {
+                    ExprKind::List(pair) => resolve_body(pair, offset, scopes),
+                    _ => None,
+                });
+            }
+
+            let mut scope = names.concat();
+            scope.extend(defines_in(&items[2..]));
+            with_scope(scopes, scope, |scopes| resolve_body(&items[2..], offset, scopes))
+        }
+        _ => resolve_body(items, offset, scopes),
+    }
+}
+
+/// Answers `textDocument/definition`: where the variable under the cursor is defined
+fn definition(text: &str, uri: &str, position: &Json) -> Json {
+    let index = LineIndex::new(text);
+    let offset = index.offset(position);
+    if identifier_at(text, offset).is_none() {
+        return Json::Null;
+    }
+    let exprs: Vec<Expr> = Parser::new(Lexer::new(text)).filter_map(Result::ok).collect();
+    match find_definition(&exprs, offset) {
+        Some(span) => json!({ "uri": uri, "range": index.range(span) }),
+        None => Json::Null,
+    }
+}
+
+/// Answers `textDocument/hover`: a short description of the variable under the cursor
+fn hover(text: &str, position: &Json) -> Json {
+    let index = LineIndex::new(text);
+    let offset = index.offset(position);
+    let token = match identifier_at(text, offset) {
+        Some(token) => token,
+        None => return Json::Null,
+    };
+    let name: &str = &token.source;
+
+    let exprs: Vec<Expr> = Parser::new(Lexer::new(text)).filter_map(Result::ok).collect();
+    let contents = if SPECIAL_FORMS.contains(&name) {
+        format!("`{}` is a special form", name)
+    } else if let Some(span) = find_definition(&exprs, offset) {
+        // Show the line that the variable is defined on
+        let line = text.lines().nth(span.start_pos.line - 1).unwrap_or("").trim();
+        format!("```lisp\n{}\n```\ndefined on line {}", line, span.start_pos.line)
+    } else if BUILTINS.iter().any(|builtin| builtin.name == name) {
+        format!("`{}` is a builtin procedure", name)
+    } else {
+        format!("`{}` is not defined", name)
+    };
+    json!({
+        "contents": { "kind": "markdown", "value": contents },
+        "range": index.range(token.span),
+    })
+}
+
+/// Answers `textDocument/documentHighlight` by highlighting the paren under
+/// the cursor and the one that matches it
+fn matching_parens(text: &str, position: &Json) -> Json {
+    let index = LineIndex::new(text);
+    let offset = index.offset(position);
+
+    let mut open: Vec<Span> = Vec::new();
+    let mut pairs = Vec::new();
+    for token in Lexer::new(text).filter_map(Result::ok) {
+        match token.token {
+            TokenType::LeftParen => open.push(token.span),
+            TokenType::RightParen => pairs.extend(open.pop().map(|open| (open, token.span))),
+            _ => (),
+        }
+    }
+
+    // Prefer the paren just after the cursor, like most editors do
+    let touches = |span: Span, at: usize| span.start == at;
+    let pair = pairs.iter()
+        .find(|(open, close)| touches(*open, offset) || touches(*close, offset))
+        .or_else(|| pairs.iter().find(|(open, close)| open.end == offset || close.end == offset));
+    match pair {
+        Some((open, close)) => json!([
+            { "range": index.range(*open), "kind": 1 },
+            { "range": index.range(*close), "kind": 1 },
+        ]),
+        None => Json::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays a scripted client's messages to the server and returns everything it sent back
+    fn session(messages: &[Json]) -> Vec<Json> {
+        let mut input = Vec::new();
+        for message in messages {
+            write_message(&mut input, message).unwrap();
+        }
+        let mut output = Vec::new();
+        Server::default().run(input.as_slice(), &mut output).unwrap();
+
+        let mut output = output.as_slice();
+        std::iter::from_fn(|| read_message(&mut output).unwrap()).collect()
+    }
+
+    fn request(id: u64, method: &str, params: Json) -> Json {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+    }
+
+    fn open(text: &str) -> Json {
+        notification("textDocument/didOpen", json!({
+            "textDocument": { "uri": "file:///test.lisp", "languageId": "lisp", "version": 1, "text": text },
+        }))
+    }
+
+    fn at(method: &str, id: u64, line: usize, character: usize) -> Json {
+        request(id, method, json!({
+            "textDocument": { "uri": "file:///test.lisp" },
+            "position": { "line": line, "character": character },
+        }))
+    }
+
+    #[test]
+    fn test_diagnostics_follow_edits() {
+        let replies = session(&[
+            request(1, "initialize", json!({ "capabilities": {} })),
+            notification("initialized", json!({})),
+            open("(define 🦀 1x)\n(display \"ok\""),
+            notification("textDocument/didChange", json!({
+                "textDocument": { "uri": "file:///test.lisp", "version": 2 },
+                "contentChanges": [
+                    { "range": { "start": { "line": 0, "character": 8 }, "end": { "line": 0, "character": 10 } }, "text": "x" },
+                    { "range": { "start": { "line": 0, "character": 10 }, "end": { "line": 0, "character": 12 } }, "text": "10" },
+                ],
+            })),
+            notification("textDocument/didChange", json!({
+                "textDocument": { "uri": "file:///test.lisp", "version": 3 },
+                "contentChanges": [{ "text": "(define x 10)\n(display x)" }],
+            })),
+            request(2, "shutdown", Json::Null),
+            notification("exit", Json::Null),
+            request(3, "never/answered", Json::Null),
+        ]);
+        assert_eq!(replies.len(), 5);
+        assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+
+        // Both lexing errors are reported, and the crab is two UTF-16 columns wide
+        let diagnostics = replies[1]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0]["range"], json!({ "start": { "line": 0, "character": 8 }, "end": { "line": 0, "character": 10 } }));
+        assert_eq!(diagnostics[1]["range"]["start"], json!({ "line": 0, "character": 11 }));
+
+        // Once those are fixed, the unclosed paren on the next line shows up
+        let diagnostics = replies[2]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["range"]["start"], json!({ "line": 1, "character": 0 }));
+        assert!(diagnostics[0]["message"].as_str().unwrap().contains("help: add a `)`"));
+
+        assert_eq!(replies[3]["params"]["diagnostics"], json!([]));
+        assert_eq!(replies[4], json!({ "jsonrpc": "2.0", "id": 2, "result": null }));
+    }
+
+    #[test]
+    fn test_backwards_change() {
+        let mut server = Server::default();
+        server.documents.insert("file:///test.lisp".to_string(), "(define x 1)".to_string());
+        let change = json!([{ "range": { "start": { "line": 0, "character": 10 }, "end": { "line": 0, "character": 8 } }, "text": "y" }]);
+        server.change("file:///test.lisp", &change);
+        assert_eq!(server.documents["file:///test.lisp"], "(define y1)");
+        // Positions past the end of the text are the end of the text
+        let change = json!([{ "range": { "start": { "line": 5, "character": 0 }, "end": { "line": 0, "character": 11 } }, "text": ")" }]);
+        server.change("file:///test.lisp", &change);
+        assert_eq!(server.documents["file:///test.lisp"], "(define y1))");
+    }
+
+    #[test]
+    fn test_line_index() {
+        let text = "a🦀b\r\n日本\nx";
+        let index = LineIndex::new(text);
+        assert_eq!(index.position(5), json!({ "line": 0, "character": 3 }));
+        assert_eq!(index.position(8), json!({ "line": 1, "character": 0 }));
+        assert_eq!(index.position(text.len()), json!({ "line": 2, "character": 1 }));
+        assert_eq!(index.offset(&json!({ "line": 0, "character": 3 })), 5);
+        assert_eq!(index.offset(&json!({ "line": 1, "character": 1 })), 11);
+        // Past the end of a line, or of the text
+        assert_eq!(index.offset(&json!({ "line": 1, "character": 99 })), 14);
+        assert_eq!(index.offset(&json!({ "line": 0, "character": 99 })), 6);
+        assert_eq!(index.offset(&json!({ "line": 9, "character": 0 })), text.len());
+    }
+
+    #[test]
+    fn test_semantic_tokens() {
+        let data = semantic_tokens("(define x 1) ; hi\n'(car \"a\nb\")");
+        assert_eq!(data, vec![
+            0, 1, 6, 0, 0, // define, a keyword
+            0, 7, 1, 2, 0, // x, a variable
+            0, 2, 1, 3, 0, // 1
+            0, 3, 4, 5, 0, // ; hi
+            1, 0, 1, 6, 0, // '
+            0, 2, 3, 1, 0, // car, a builtin
+            0, 4, 2, 4, 0, // "a
+            1, 0, 2, 4, 0, // b"
+        ]);
+    }
+
+    #[test]
+    fn test_navigation() {
+        let text = "\
+(define (square x) (* x x))
+(define total
+  (let ((x 2) (y x))
+    (square (+ x y))))
+(display (+ total (square 3))";
+        let replies = session(&[
+            open(text),
+            request(1, "textDocument/documentSymbol", json!({ "textDocument": { "uri": "file:///test.lisp" } })),
+            // `x` in the body of `square` is its parameter
+            at("textDocument/definition", 2, 0, 22),
+            // `x` in the value of `y` is not the `x` from the same `let`
+            at("textDocument/definition", 3, 2, 17),
+            at("textDocument/definition", 4, 3, 15),
+            at("textDocument/hover", 5, 3, 6),
+            at("textDocument/hover", 6, 4, 2),
+            at("textDocument/hover", 7, 0, 2),
+            at("textDocument/documentHighlight", 8, 2, 7),
+        ]);
+
+        let symbols = &replies[1]["result"];
+        assert_eq!(symbols[0]["name"], "square");
+        assert_eq!(symbols[0]["kind"], 12);
+        assert_eq!(symbols[1]["name"], "total");
+        assert_eq!(symbols[1]["kind"], 13);
+        assert_eq!(symbols[1]["selectionRange"]["start"], json!({ "line": 1, "character": 8 }));
+        assert_eq!(symbols.as_array().unwrap().len(), 2, "the last line is not finished");
+
+        assert_eq!(replies[2]["result"]["range"]["start"], json!({ "line": 0, "character": 16 }));
+        assert_eq!(replies[3]["result"], Json::Null);
+        assert_eq!(replies[4]["result"]["range"]["start"], json!({ "line": 2, "character": 9 }));
+
+        let hover = |reply: &Json| reply["result"]["contents"]["value"].as_str().unwrap().to_string();
+        assert_eq!(hover(&replies[5]), "```lisp\n(define (square x) (* x x))\n```\ndefined on line 1");
+        assert_eq!(hover(&replies[6]), "`display` is a builtin procedure");
+        assert_eq!(hover(&replies[7]), "`define` is a special form");
+
+        let highlights = replies[8]["result"].as_array().unwrap();
+        assert_eq!(highlights[0]["range"]["start"], json!({ "line": 2, "character": 7 }));
+        assert_eq!(highlights[1]["range"]["start"], json!({ "line": 2, "character": 19 }));
+    }
+}
@@ -0,0 +1,298 @@
+//! An interactive prompt for the little lisp
+//!
+//! Type an expression and the REPL (Read, Eval, Print, Loop) will run it and
+//! print the result. An expression can be spread over as many lines as you
+//! like: the REPL keeps reading until every `(` has been closed.
+//!
+//! Everything you enter is saved to a history file, which is
+//! `$LISP_HISTORY` if that is set and `~/.lisp_history` otherwise.
+//! Type `:help` to see the other commands.
+
+use csh_seminar_feb_2021::diagnostic::Diagnostic;
+use csh_seminar_feb_2021::eval::{Interpreter, Value};
+use csh_seminar_feb_2021::gc;
+use csh_seminar_feb_2021::lexer::Lexer;
+use csh_seminar_feb_2021::parser::{self, Expr, ExprKind, Parser};
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+const HELP: &str = "\
+Enter any expression to evaluate it. Expressions may span several lines.
+
+Commands:
+  :help            show this message
+  :tokens <code>   show the tokens that the lexer reads from <code>
+  :ast <code>      show the tree that the parser builds from <code>
+  :expand <code>   show <code> with every macro in it expanded
+  :history         show everything entered so far
+  :gc              collect garbage and show what the collector has done
+  :quit            leave the REPL (so does Ctrl-D)";
+
+fn main() -> io::Result<()> {
+    let history = History::load(history_path());
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut repl = Repl { interpreter: Interpreter::new(), history, output: stdout.lock() };
+    repl.run(stdin.lock())
+}
+
+/// Where to keep the history file, if we can find somewhere for it
+fn history_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("LISP_HISTORY") {
+        return Some(PathBuf::from(path));
+    }
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".lisp_history"))
+}
+
+struct Repl<W> {
+    interpreter: Interpreter,
+    history: History,
+    output: W,
+}
+
+impl<W: Write> Repl<W> {
+    fn run(&mut self, mut input: impl BufRead) -> io::Result<()> {
+        writeln!(self.output, "Welcome to the little lisp! Type :help for help.")?;
+        let mut buffer = String::new();
+        loop {
+            let prompt = if buffer.is_empty() { "lisp> " } else { "  ... " };
+            write!(self.output, "{}", prompt)?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(self.output)?;
+                return Ok(());
+            }
+            buffer.push_str(&line);
+
+            // Keep reading lines until the expression is finished
+            if parser::needs_more_input(&buffer) {
+                continue;
+            }
+            let entry = std::mem::take(&mut buffer);
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+
+            self.history.add(entry);
+            if entry == ":quit" {
+                return Ok(());
+            }
+            self.handle(entry)?;
+        }
+    }
+
+    /// Runs a command or evaluates some code
+    fn handle(&mut self, entry: &str) -> io::Result<()> {
+        if !entry.starts_with(':') {
+            return self.evaluate(entry);
+        }
+
+        let (command, code) = entry.split_at(entry.find(char::is_whitespace).unwrap_or(entry.len()));
+        let code = code.trim();
+        match command {
+            ":help" => writeln!(self.output, "{}", HELP),
+            ":tokens" => {
+                for token in Lexer::new(code).with_comments() {
+                    match token {
+                        Ok(token) => writeln!(self.output, "{:<12} {:?}  at {}", token.source, token.token, token.span)?,
+                        Err(error) => write!(self.output, "{}", Diagnostic::from(&error).render("<repl>", code, false))?,
+                    }
+                }
+                Ok(())
+            }
+            ":ast" => {
+                for expr in Parser::new(Lexer::new(code)) {
+                    match expr {
+                        Ok(expr) => write_tree(&mut self.output, &expr, 0)?,
+                        Err(error) => write!(self.output, "{}", Diagnostic::from(&error).render("<repl>", code, false))?,
+                    }
+                }
+                Ok(())
+            }
+            ":expand" => {
+                for expr in Parser::new(Lexer::new(code)) {
+                    match expr.map_err(Into::into).and_then(|expr| self.interpreter.expand(&expr)) {
+                        Ok(expr) => writeln!(self.output, "{}", expr)?,
+                        Err(error) => write!(self.output, "{}", Diagnostic::from(&error).render("<repl>", code, false))?,
+                    }
+                }
+                Ok(())
+            }
+            ":history" => {
+                for (number, entry) in self.history.entries.iter().enumerate() {
+                    writeln!(self.output, "{:>4}  {}", number + 1, entry.replace('\n', "\n      "))?;
+                }
+                Ok(())
+            }
+            ":gc" => {
+                let collected = gc::collect();
+                let stats = gc::stats();
+                writeln!(self.output, "collected {} objects just now", collected)?;
+                writeln!(
+                    self.output,
+                    "{} objects allocated, {} still alive, {} collected in {} collections",
+                    stats.allocated, stats.live, stats.collected, stats.collections
+                )
+            }
+            other => writeln!(self.output, "unknown command `{}`, type :help for help", other),
+        }
+    }
+
+    /// Evaluates every expression in the entry, printing each result
+    ///
+    /// An error stops the rest of the entry from running, but anything
+    /// defined before the error is kept.
+    fn evaluate(&mut self, code: &str) -> io::Result<()> {
+        for expr in Parser::new(Lexer::new(code)) {
+            let result = expr.map_err(Into::into).and_then(|expr| self.interpreter.eval(&expr));
+            match result {
+                Ok(Value::Unspecified) => (),
+                Ok(value) => writeln!(self.output, "{}", value)?,
+                Err(error) => {
+                    write!(self.output, "{}", Diagnostic::from(&error).render("<repl>", code, false))?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prints an expression tree with one node per line, indenting children under their parents
+fn write_tree(output: &mut impl Write, expr: &Expr, depth: usize) -> io::Result<()> {
+    let indent = "  ".repeat(depth);
+    let span = format!("{}-{}", expr.span.start_pos, expr.span.end_pos);
+    match &expr.kind {
+        ExprKind::List(items) => {
+            writeln!(output, "{}List  {}", indent, span)?;
+            for item in items {
+                write_tree(output, item, depth + 1)?;
+            }
+            Ok(())
+        }
+        ExprKind::Symbol(name) => writeln!(output, "{}Symbol {}  {}", indent, name, span),
+        ExprKind::Number(number) => writeln!(output, "{}Number {}  {}", indent, number, span),
+        ExprKind::Str(_) => writeln!(output, "{}Str {}  {}", indent, expr, span),
+        ExprKind::Bool(_) => writeln!(output, "{}Bool {}  {}", indent, expr, span),
+    }
+}
+
+/// Everything entered into the REPL, kept in memory and saved to a file
+///
+/// In the file each entry starts on a new line. The extra lines of an entry
+/// that spans several lines are indented by one space, which can never be
+/// the start of an entry since entries are trimmed:
+///
+/// ```text
+/// (+ 1 2)
+/// (define (f x)
+///    (* x 2))
+/// ```
+struct History {
+    path: Option<PathBuf>,
+    entries: Vec<String>,
+}
+
+impl History {
+    /// Reads the history file, if there is one
+    fn load(path: Option<PathBuf>) -> History {
+        let contents = path.as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .unwrap_or_default();
+        let mut entries: Vec<String> = Vec::new();
+        for line in contents.lines() {
+            match (line.strip_prefix(' '), entries.last_mut()) {
+                (Some(continued), Some(entry)) => {
+                    entry.push('\n');
+                    entry.push_str(continued);
+                }
+                _ => entries.push(line.to_string()),
+            }
+        }
+        History { path, entries }
+    }
+
+    /// Remembers an entry, appending it to the history file straight away
+    /// so that nothing is lost if the REPL is killed
+    fn add(&mut self, entry: &str) {
+        self.entries.push(entry.to_string());
+        if let Some(path) = &self.path {
+            let saved = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", entry.replace('\n', "\n ")));
+            if let Err(error) = saved {
+                eprintln!("warning: could not save history to {}: {}", path.display(), error);
+                self.path = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_session(input: &str, history: History) -> (String, History) {
+        let mut repl = Repl { interpreter: Interpreter::new(), history, output: Vec::new() };
+        repl.run(input.as_bytes()).unwrap();
+        (String::from_utf8(repl.output).unwrap(), repl.history)
+    }
+
+    #[test]
+    fn test_multi_line_input_and_errors() {
+        let input = "(define (square x)\n  (* x x))\n(square 12) (undefined)\n(square\n 3)\n";
+        let (output, _) = run_session(input, History { path: None, entries: Vec::new() });
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines, vec![
+            "Welcome to the little lisp! Type :help for help.",
+            "lisp>   ... lisp> 144",
+            "error: unbound variable `undefined`",
+            " --> <repl>:1:14",
+            "  |",
+            "1 | (square 12) (undefined)",
+            "  |              ^^^^^^^^^ not defined anywhere",
+            "  |",
+            "  = help: define `undefined` before using it",
+            "lisp>   ... 9",
+            "lisp> ",
+        ]);
+    }
+
+    #[test]
+    fn test_commands() {
+        let input = ":tokens (a 1)\n:ast '(b)\n:nope\n:quit\n(never evaluated)\n";
+        let (output, history) = run_session(input, History { path: None, entries: Vec::new() });
+        assert!(output.contains("a            Identifier(Ident(\"a\"))  at 1:2"), "{}", output);
+        assert!(output.contains("List  1:1-1:5\n  Symbol quote  1:1-1:2\n  List  1:2-1:5\n    Symbol b  1:3-1:4"), "{}", output);
+        assert!(output.contains("unknown command `:nope`"));
+        assert_eq!(history.entries.len(), 4);
+
+        let input = "(defmacro unless (c . body) `(if ,c #f (begin ,@body)))\n:expand (unless #f 1 2)\n";
+        let (output, _) = run_session(input, History { path: None, entries: Vec::new() });
+        assert!(output.contains("(if #f #f (begin 1 2))"), "{}", output);
+
+        let input = "(define (f) (define (g) g) g)\n(f)\n:gc\n";
+        let (output, _) = run_session(input, History { path: None, entries: Vec::new() });
+        assert!(output.contains("collected 2 objects just now"), "{}", output);
+    }
+
+    #[test]
+    fn test_history_file() {
+        let path = std::env::temp_dir().join(format!("lisp-history-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut history = History::load(Some(path.clone()));
+        history.add("(+ 1 2)");
+        history.add("(define (f x)\n  (* x 2))");
+
+        let reloaded = History::load(Some(path.clone()));
+        assert_eq!(reloaded.entries, vec!["(+ 1 2)", "(define (f x)\n  (* x 2))"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,176 +1,89 @@
-//! A Lexer for a little lisp-like language
+//! Prints the tokens of a lisp file, one per line
 //!
-//! We will create a `Lexer` struct which takes in a string and then
-//! iterates over the tokens that it recognizes. The Lexer will capture
-//! information such as the type of each token, the location (span) where it came
-//! from in the input string, and any add-on data that each token may require.
+//! This is a handy way to see exactly what the `Lexer` makes of some input.
 
-use std::io::BufRead;
+use csh_seminar_feb_2021::diagnostic::{self, Diagnostic};
+use csh_seminar_feb_2021::highlight;
+use csh_seminar_feb_2021::lexer::Lexer;
+use csh_seminar_feb_2021::stream::{StreamError, StreamLexer};
+use std::io::{BufRead, BufReader, Read};
 
-fn main() -> Result<(), String> {
-    let stdin = std::io::stdin();
-    let mut lines = stdin.lock().lines();
-    while let Some(Ok(line)) = lines.next() {
-        let lexer = Lexer::new(&line)?;
-        for token in lexer {
-            println!("{:?}", token);
-        }
-    }
-
-    Ok(())
-}
-
-#[derive(Debug)]
-pub struct Span {
-    start: usize,
-    end: usize,
-}
-
-#[derive(Debug)]
-pub struct Ident<'a>(&'a str);
-
-#[derive(Debug)]
-pub enum TokenType<'a> {
-    LeftParen,
-    RightParen,
-    Identifier(Ident<'a>),
-}
-
-#[derive(Debug)]
-pub struct Token<'a> {
-    /// The slice of the input string that this token was parsed from
-    source: &'a str,
-    /// The type of token that this is
-    token: TokenType<'a>,
-    /// The starting and ending indices of this token
-    span: Span,
-}
-
-/// A Lexer that will take an input string and return Tokens of that input
+/// Lexes the file given as an argument, or all of stdin if there is none
 ///
-/// Tokens are a way to simplify an input string. Instead of remembering
-/// every single character of the input, we just remember whether it was
-/// a parenthesis, or an identifier (e.g. a function name) or some kind of
-/// literal (like a number or string).
+/// Pass `--comments` to print comments as well as the other tokens, or
+/// `--lossless` to print every comment and every bit of whitespace too.
 ///
-/// This Lexer returns tokens that reference the original string that they
-/// were read from. The lifetime `'a` represents the scope where the input
-/// string lives. Therefore, this lexer and any tokens it produces may not
-/// outlive the original string.
+/// Pass `--stream` to lex the input as it is read, rather than reading it
+/// all into memory first. This works for inputs of any size, but errors
+/// are printed without the line of code they are about.
 ///
-/// Think about the memory of the program in terms of the stack frames of the functions:
-///
-/// ```text
-/// +-----------main------------------+
-/// | ...                             |
-/// +-----get_some_string()-----------+
-/// |                                 |
-/// | let input = " ( + 1 2 ) "       |  <-- Think of lifetime 'a as this stack frame
-/// |                                 |
-/// +---lex_that_string(s: &'a str)---+
-/// ```
-struct Lexer<'a> {
-    /// The input string that we are lexing tokens from
-    input: &'a str,
-    /// The index into the string that has been lexed so far
-    ///
-    /// For example, here is where the offset will point at the beginning
-    /// of lexing and after lexing each token in the input string:
-    ///
-    /// ```text
-    /// input:     "the quick brown fox jumped over the lazy dog"
-    /// offset: 0   ^   ^     ^     ^   ^      ^    ^   ^    ^
-    /// offset: 4   ----|     |     |   |      |    |   |    |
-    /// offset: 10  ----------|     |   |      |    |   |    |
-    /// offset: 16  ----------------|   |      |    |   |    |
-    /// offset: 20  --------------------|      |    |   |    |
-    /// offset: 27  ---------------------------|    |   |    |
-    /// offset: 32  --------------------------------|   |    |
-    /// offset: 36  ------------------------------------|    |
-    /// offset: 41  -----------------------------------------|
-    /// ```
-    offset: usize,
-}
-
-impl<'a> Lexer<'a> {
-    pub fn new(input: &'a str) -> Result<Lexer<'a>, String> {
-        if !input.is_ascii() {
-            return Err("Lexer can only read ascii input".to_string());
-        }
-        Ok(Lexer {
-            input,
-            offset: 0,
-        })
+/// Pass `--highlight` to print the input with syntax highlighting instead
+/// of printing its tokens, or `--html` to print it as a highlighted web page.
+fn main() -> std::io::Result<()> {
+    let (flags, paths): (Vec<String>, Vec<String>) = std::env::args()
+        .skip(1)
+        .partition(|arg| arg.starts_with("--"));
+    let keep_comments = flags.iter().any(|flag| flag == "--comments");
+    let lossless = flags.iter().any(|flag| flag == "--lossless");
+    if flags.iter().any(|flag| flag == "--stream") {
+        return match paths.into_iter().next() {
+            Some(path) => stream(&path, BufReader::new(std::fs::File::open(&path)?), keep_comments, lossless),
+            None => stream("<stdin>", std::io::stdin().lock(), keep_comments, lossless),
+        };
     }
-}
-
-impl<'a> Iterator for Lexer<'a> {
-    type Item = Token<'a>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if (&self.input[self.offset..]).is_empty() {
-            return None;
+    let (file_name, input) = match paths.into_iter().next() {
+        Some(path) => {
+            let input = std::fs::read_to_string(&path)?;
+            (path, input)
         }
-
-        // Ignore whitespace characters
-        while &self.input[self.offset..self.offset+1] == " " {
-            self.offset += 1;
-            if self.offset >= self.input.len() { return None; }
+        None => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            ("<stdin>".to_string(), input)
         }
+    };
 
-        // Easy cases: check if the first character is '(' or ')'
-        let ch = &self.input[self.offset..self.offset+1];
-        match ch {
-            "(" | ")" => {
-                let source = &self.input[self.offset..self.offset+1];
-                let token = if ch == "(" { TokenType::LeftParen } else { TokenType::RightParen };
-                let span = Span { start: self.offset, end: self.offset + 1 };
-                self.offset += 1;
-                return Some(Token {
-                    source,
-                    token,
-                    span,
-                })
-            }
-            // Anything else needs to be collected as an identifier
-            _other => (),
-        }
+    if flags.iter().any(|flag| flag == "--highlight") {
+        print!("{}", highlight::to_ansi(&input));
+        return Ok(());
+    }
+    if flags.iter().any(|flag| flag == "--html") {
+        print!("{}", highlight::to_html(&input));
+        return Ok(());
+    }
 
-        // We are looking for an identifier, iterate to the end of it
-        let mut end_offset = self.offset;
-        let slice = &self.input[self.offset..];
-        for ch in slice.chars() {
-            if !ch.is_alphabetic() {
-                break;
-            }
-            end_offset += 1;
+    let mut lexer = Lexer::new(&input);
+    if lossless {
+        lexer = lexer.lossless();
+    } else if keep_comments {
+        lexer = lexer.with_comments();
+    }
+    let color = diagnostic::stderr_wants_color();
+    for token in lexer {
+        match token {
+            Ok(token) => println!("{:?}", token),
+            Err(error) => eprint!("{}", Diagnostic::from(&error).render(&file_name, &input, color)),
         }
-
-        let source = &self.input[self.offset..end_offset];
-        let ident = Ident(source);
-        let token = Token {
-            source,
-            token: TokenType::Identifier(ident),
-            span: Span { start: self.offset, end: end_offset }
-        };
-
-        self.offset = end_offset + 1;
-        Some(token)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    #[test]
-    fn test_lexer() {
-        let input = "  ( one two )  ";
-        println!("String: \"{}\"", input);
-        let lexer = Lexer::new(input).unwrap();
+    Ok(())
+}
 
-        for token in lexer {
-            println!("{:?}", token);
+/// Lexes a file bit by bit, printing each token as soon as it is read
+fn stream(file_name: &str, reader: impl BufRead, keep_comments: bool, lossless: bool) -> std::io::Result<()> {
+    let mut lexer = StreamLexer::new(reader);
+    if lossless {
+        lexer = lexer.lossless();
+    } else if keep_comments {
+        lexer = lexer.with_comments();
+    }
+    for token in lexer {
+        match token {
+            Ok(token) => println!("{:?}", token),
+            Err(StreamError::Lex(error)) => eprintln!("{}: error: {}", error.span().in_file(file_name), error),
+            Err(StreamError::Io(error)) => return Err(error),
         }
     }
+    Ok(())
 }
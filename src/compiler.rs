@@ -0,0 +1,740 @@
+//! A compiler that turns the tree from the parser into bytecode for the `vm`
+//!
+//! The tree-walking interpreter in `eval` looks at the tree again every
+//! time it runs a piece of code. In a loop that means finding the same
+//! special forms, looking up the same variable names in the same hash
+//! maps, and following the same pointers, over and over.
+//!
+//! Compiling does all of that work once. Each function becomes a flat list
+//! of simple instructions for a "stack machine", which keeps the values it
+//! is working on in a stack:
+//!
+//! ```text
+//! (+ 1 (* x 2))        get-global +       stack: +
+//!                      constant 0  ; 1    stack: + 1
+//!                      get-global *       stack: + 1 *
+//!                      get-local 0        stack: + 1 * x
+//!                      constant 1  ; 2    stack: + 1 * x 2
+//!                      call 2             stack: + 1 (x*2)
+//!                      call 2             stack: (1+x*2)
+//! ```
+//!
+//! Variables are sorted out at compile time as well. A local variable is
+//! just a numbered slot in the stack, and a global is looked up by its
+//! interned `Symbol`. A closure keeps the variables it uses from outside
+//! functions as "upvalues", which the `vm` moves off the stack once the
+//! function they belong to returns. A `Symbol::global_alias` that a macro
+//! made is never a local, so it is always the global that it is an alias of.
+
+use crate::eval::{self, EvalError, Value};
+use crate::lexer::{Position, Span};
+use crate::parser::{Expr, ExprKind};
+use crate::symbol::Symbol;
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+use std::rc::Rc;
+
+/// A single instruction for the `vm`
+///
+/// Every instruction is a small, fixed size, with any numbers it needs
+/// stored right inside it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Pushes a value from the constants pool
+    Constant(u32),
+    /// Pushes the value that `define`, `set!` and friends return
+    Unspecified,
+    /// Pushes a local variable, counted from the first slot of the current call
+    GetLocal(u32),
+    /// Pops the top of the stack into a local variable
+    SetLocal(u32),
+    /// Pushes a variable that the current closure captured from outside
+    GetUpvalue(u32),
+    /// Pops the top of the stack into a captured variable
+    SetUpvalue(u32),
+    GetGlobal(Symbol),
+    /// Pops the top of the stack into a global variable, creating it if needed
+    DefineGlobal(Symbol),
+    /// Pops the top of the stack into a global variable that already exists
+    SetGlobal(Symbol),
+    /// Pushes another copy of the value on top of the stack
+    Dup,
+    Pop,
+    /// Removes this many locals from under the value on top of the stack,
+    /// at the end of a `let`
+    EndScope(u32),
+    /// Carries on from the instruction with this index
+    Jump(u32),
+    /// Pops the top of the stack, and jumps if it is `#f`
+    JumpIfFalse(u32),
+    /// Calls a procedure with this many arguments, which are on top of the
+    /// stack with the procedure just below them
+    Call(u32),
+    /// Like `Call`, but reuses the current call's part of the stack, since
+    /// there is nothing left to do after it returns
+    TailCall(u32),
+    /// Pushes a new closure of one of the chunk's functions
+    Closure(u32),
+    /// Returns the value on top of the stack from the current call
+    Return,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Constant(index) => write!(f, "constant {}", index),
+            Op::Unspecified => write!(f, "unspecified"),
+            Op::GetLocal(slot) => write!(f, "get-local {}", slot),
+            Op::SetLocal(slot) => write!(f, "set-local {}", slot),
+            Op::GetUpvalue(index) => write!(f, "get-upvalue {}", index),
+            Op::SetUpvalue(index) => write!(f, "set-upvalue {}", index),
+            Op::GetGlobal(name) => write!(f, "get-global {}", name),
+            Op::DefineGlobal(name) => write!(f, "define-global {}", name),
+            Op::SetGlobal(name) => write!(f, "set-global {}", name),
+            Op::Dup => write!(f, "dup"),
+            Op::Pop => write!(f, "pop"),
+            Op::EndScope(count) => write!(f, "end-scope {}", count),
+            Op::Jump(target) => write!(f, "jump {}", target),
+            Op::JumpIfFalse(target) => write!(f, "jump-if-false {}", target),
+            Op::Call(count) => write!(f, "call {}", count),
+            Op::TailCall(count) => write!(f, "tail-call {}", count),
+            Op::Closure(index) => write!(f, "closure {}", index),
+            Op::Return => write!(f, "return"),
+        }
+    }
+}
+
+/// The compiled code of one function
+#[derive(Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    /// The part of the program that each instruction came from, for error messages
+    pub spans: Vec<Span>,
+    pub constants: Vec<Value>,
+    /// The functions defined inside this one, which `Closure` makes closures of
+    pub functions: Vec<Rc<Function>>,
+}
+
+/// A compiled `lambda`, or the whole program at the top level
+#[derive(Default)]
+pub struct Function {
+    pub name: Option<Symbol>,
+    /// The number of parameters, not counting a rest parameter
+    pub arity: usize,
+    /// Whether any extra arguments are collected into a list, like `(lambda (a . rest) ...)`
+    pub variadic: bool,
+    /// Where each upvalue of a new closure comes from, in the function that makes it
+    pub captures: Vec<Capture>,
+    pub chunk: Chunk,
+}
+
+/// Where a closure finds a variable from an outer function when it is made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    /// A local variable of the function making the closure
+    Local(u32),
+    /// One of the upvalues of the function making the closure
+    Upvalue(u32),
+}
+
+/// Compiles a whole program, which the `vm` can then run
+///
+/// Top-level `define`s make global variables, so a program can be compiled
+/// and run in pieces, like in a REPL.
+pub fn compile(exprs: &[Expr]) -> Result<Rc<Function>, EvalError> {
+    let mut compiler = Compiler { states: vec![State::new(None, 0, false)] };
+    let start = Span { start: 0, end: 0, start_pos: Position::START, end_pos: Position::START };
+    let end = exprs.last().map_or(start, |expr| expr.span);
+    for (index, expr) in exprs.iter().enumerate() {
+        compiler.expr(expr, false)?;
+        if index + 1 < exprs.len() {
+            compiler.emit(Op::Pop, expr.span);
+        }
+    }
+    if exprs.is_empty() {
+        compiler.emit(Op::Unspecified, end);
+    }
+    compiler.emit(Op::Return, end);
+    let state = compiler.states.pop().expect("the top level is never popped early");
+    Ok(Rc::new(state.function))
+}
+
+/// Compiles a program that makes a single procedure, which is how the `vm`
+/// makes the procedures from `defmacro`
+pub fn compile_procedure(
+    name: Symbol,
+    params: Vec<Symbol>,
+    rest: Option<Symbol>,
+    body: &[Expr],
+    span: Span,
+) -> Result<Rc<Function>, EvalError> {
+    let mut compiler = Compiler { states: vec![State::new(None, 0, false)] };
+    compiler.lambda(Some(name), params, rest, body, span)?;
+    compiler.emit(Op::Return, span);
+    let state = compiler.states.pop().expect("the top level is never popped early");
+    Ok(Rc::new(state.function))
+}
+
+/// A local variable, which lives in a slot on the stack
+struct Local {
+    name: Symbol,
+    slot: u32,
+    /// How many scopes deep the variable was declared
+    depth: usize,
+}
+
+/// What the compiler needs to know about a function while compiling it
+struct State {
+    function: Function,
+    locals: Vec<Local>,
+    depth: usize,
+    /// How many values this function's call will have on the stack at the
+    /// current instruction, which is where the next local will go
+    stack: u32,
+}
+
+impl State {
+    fn new(name: Option<Symbol>, arity: usize, variadic: bool) -> State {
+        State {
+            function: Function { name, arity, variadic, ..Function::default() },
+            locals: Vec::new(),
+            depth: 0,
+            stack: 0,
+        }
+    }
+
+    fn resolve_local(&self, name: Symbol) -> Option<u32> {
+        self.locals.iter().rev().find(|local| local.name == name).map(|local| local.slot)
+    }
+}
+
+struct Compiler {
+    /// The function being compiled, after every function it is inside of
+    states: Vec<State>,
+}
+
+impl Compiler {
+    fn state(&mut self) -> &mut State {
+        self.states.last_mut().expect("there is always a function being compiled")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state().function.chunk
+    }
+
+    /// Adds an instruction, keeping track of how it changes the size of the stack
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        let state = self.state();
+        match op {
+            Op::Constant(_) | Op::Unspecified | Op::GetLocal(_) | Op::GetUpvalue(_) | Op::GetGlobal(_) | Op::Closure(_) | Op::Dup => {
+                state.stack += 1
+            }
+            Op::SetLocal(_) | Op::SetUpvalue(_) | Op::DefineGlobal(_) | Op::SetGlobal(_) | Op::Pop | Op::JumpIfFalse(_) => {
+                state.stack -= 1
+            }
+            Op::EndScope(count) | Op::Call(count) | Op::TailCall(count) => state.stack -= count,
+            Op::Jump(_) | Op::Return => (),
+        }
+        let chunk = &mut state.function.chunk;
+        chunk.code.push(op);
+        chunk.spans.push(span);
+        chunk.code.len() - 1
+    }
+
+    /// Points a jump that was emitted earlier at the next instruction
+    fn patch_jump(&mut self, jump: usize) {
+        let chunk = self.chunk();
+        let target = index(chunk.code.len());
+        match &mut chunk.code[jump] {
+            Op::Jump(to) | Op::JumpIfFalse(to) => *to = target,
+            _ => unreachable!("only jumps are patched"),
+        }
+    }
+
+    fn constant(&mut self, value: Value, span: Span) {
+        let chunk = self.chunk();
+        chunk.constants.push(value);
+        let constant = index(chunk.constants.len() - 1);
+        self.emit(Op::Constant(constant), span);
+    }
+
+    /// Makes the value on top of the stack into a local variable
+    fn declare(&mut self, name: Symbol) {
+        let state = self.state();
+        let (slot, depth) = (state.stack - 1, state.depth);
+        state.locals.push(Local { name, slot, depth });
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().depth += 1;
+    }
+
+    /// Forgets the variables of the innermost scope, and takes them off the stack
+    fn end_scope(&mut self, span: Span) {
+        let state = self.state();
+        state.depth -= 1;
+        let depth = state.depth;
+        let count = state.locals.iter().rev().take_while(|local| local.depth > depth).count();
+        state.locals.truncate(state.locals.len() - count);
+        if count > 0 {
+            self.emit(Op::EndScope(index(count)), span);
+        }
+    }
+
+    /// Finds a variable in the function at `level`, or one of the functions
+    /// it is inside of, adding upvalues on the way so that it can be reached
+    fn resolve_upvalue(&mut self, level: usize, name: Symbol) -> Option<u32> {
+        let outer = level.checked_sub(1)?;
+        let capture = match self.states[outer].resolve_local(name) {
+            Some(slot) => Capture::Local(slot),
+            None => Capture::Upvalue(self.resolve_upvalue(outer, name)?),
+        };
+        let captures = &mut self.states[level].function.captures;
+        let upvalue = captures.iter().position(|&existing| existing == capture).unwrap_or_else(|| {
+            captures.push(capture);
+            captures.len() - 1
+        });
+        Some(index(upvalue))
+    }
+
+    fn get_variable(&mut self, name: Symbol, span: Span) {
+        let level = self.states.len() - 1;
+        let op = match self.state().resolve_local(name) {
+            Some(slot) => Op::GetLocal(slot),
+            None => match self.resolve_upvalue(level, name) {
+                Some(upvalue) => Op::GetUpvalue(upvalue),
+                None => Op::GetGlobal(name.aliased().unwrap_or(name)),
+            },
+        };
+        self.emit(op, span);
+    }
+
+    fn expr(&mut self, expr: &Expr, tail: bool) -> Result<(), EvalError> {
+        let items = match &expr.kind {
+            ExprKind::Symbol(name) => {
+                self.get_variable(*name, expr.span);
+                return Ok(());
+            }
+            ExprKind::List(items) => items,
+            _ => {
+                self.constant(Value::from_expr(expr), expr.span);
+                return Ok(());
+            }
+        };
+
+        let (head, args) = match items.split_first() {
+            Some(split) => split,
+            None => return Err(EvalError::BadSyntax { form: "()", reason: "cannot evaluate an empty list", span: expr.span }),
+        };
+        if let ExprKind::Symbol(name) = &head.kind {
+            match *name {
+                Symbol::QUOTE => return self.quote(args, expr.span),
+                Symbol::IF => return self.if_form(args, tail, expr.span),
+                Symbol::COND => return self.cond(args, tail, expr.span),
+                Symbol::DEFINE => return self.define(args, expr.span),
+                Symbol::SET => return self.set(args, expr.span),
+                Symbol::LAMBDA => return self.lambda_form(args, expr.span),
+                Symbol::BEGIN => return self.begin(args, tail, expr.span),
+                Symbol::LET => return self.let_form(args, tail, expr.span),
+                Symbol::LET_STAR => return self.let_star(args, tail, expr.span),
+                Symbol::LETREC => return self.letrec(args, tail, expr.span),
+                _ => (),
+            }
+        }
+
+        self.expr(head, false)?;
+        for arg in args {
+            self.expr(arg, false)?;
+        }
+        let count = index(args.len());
+        self.emit(if tail { Op::TailCall(count) } else { Op::Call(count) }, expr.span);
+        Ok(())
+    }
+
+    /// Compiles the body of a `lambda` or `let`, leaving the value of the last expression
+    ///
+    /// Every name that the body `define`s is made into a local up front, so
+    /// that procedures defined next to each other can call each other.
+    fn body(&mut self, body: &[Expr], tail: bool, span: Span) -> Result<(), EvalError> {
+        let mut defined = Vec::new();
+        defined_names(body, &mut defined);
+        for name in defined {
+            self.emit(Op::Unspecified, span);
+            self.declare(name);
+        }
+        self.sequence(body, tail, span)
+    }
+
+    /// Compiles each expression in turn, keeping only the value of the last one
+    fn sequence(&mut self, body: &[Expr], tail: bool, span: Span) -> Result<(), EvalError> {
+        if body.is_empty() {
+            self.emit(Op::Unspecified, span);
+        }
+        for (index, expr) in body.iter().enumerate() {
+            let last = index + 1 == body.len();
+            self.expr(expr, tail && last)?;
+            if !last {
+                self.emit(Op::Pop, expr.span);
+            }
+        }
+        Ok(())
+    }
+
+    fn quote(&mut self, args: &[Expr], span: Span) -> Result<(), EvalError> {
+        match args {
+            [datum] => {
+                self.constant(Value::from_expr(datum), span);
+                Ok(())
+            }
+            _ => Err(EvalError::BadSyntax { form: "quote", reason: "expected exactly one expression", span }),
+        }
+    }
+
+    fn if_form(&mut self, args: &[Expr], tail: bool, span: Span) -> Result<(), EvalError> {
+        let (condition, then, otherwise) = match args {
+            [condition, then] => (condition, then, None),
+            [condition, then, otherwise] => (condition, then, Some(otherwise)),
+            _ => return Err(EvalError::BadSyntax { form: "if", reason: "expected a condition and one or two branches", span }),
+        };
+        self.expr(condition, false)?;
+        let to_otherwise = self.emit(Op::JumpIfFalse(0), span);
+        self.expr(then, tail)?;
+        let to_end = self.emit(Op::Jump(0), span);
+
+        // Only one of the branches runs, so the stack is the same size at the start of each
+        self.state().stack -= 1;
+        self.patch_jump(to_otherwise);
+        match otherwise {
+            Some(otherwise) => self.expr(otherwise, tail)?,
+            None => {
+                self.emit(Op::Unspecified, span);
+            }
+        }
+        self.patch_jump(to_end);
+        Ok(())
+    }
+
+    fn cond(&mut self, args: &[Expr], tail: bool, span: Span) -> Result<(), EvalError> {
+        let mut to_end = Vec::new();
+        let mut has_else = false;
+        for (condition, body) in eval::parse_clauses(args, span)? {
+            let condition = match condition {
+                Some(condition) => condition,
+                None => {
+                    self.sequence(body, tail, span)?;
+                    has_else = true;
+                    break;
+                }
+            };
+            self.expr(condition, false)?;
+            if body.is_empty() {
+                // The value of the condition is the value of the `cond`, so keep a copy of it
+                self.emit(Op::Dup, span);
+                let to_next = self.emit(Op::JumpIfFalse(0), span);
+                to_end.push(self.emit(Op::Jump(0), span));
+                self.patch_jump(to_next);
+                self.emit(Op::Pop, span);
+            } else {
+                let to_next = self.emit(Op::JumpIfFalse(0), span);
+                self.sequence(body, tail, span)?;
+                to_end.push(self.emit(Op::Jump(0), span));
+                // Like `if`, the next clause starts with the stack as it was before this one's body
+                self.state().stack -= 1;
+                self.patch_jump(to_next);
+            }
+        }
+        if !has_else {
+            self.emit(Op::Unspecified, span);
+        }
+        for jump in to_end {
+            self.patch_jump(jump);
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, args: &[Expr], span: Span) -> Result<(), EvalError> {
+        let bad_syntax = |reason| EvalError::BadSyntax { form: "define", reason, span };
+        let (target, rest) = args.split_first().ok_or_else(|| bad_syntax("expected a name"))?;
+        let name = match &target.kind {
+            ExprKind::Symbol(name) => {
+                match rest {
+                    [value] => self.expr(value, false)?,
+                    _ => return Err(bad_syntax("expected exactly one value")),
+                }
+                *name
+            }
+            // The procedure shorthand: the params are everything after the name
+            ExprKind::List(signature) => {
+                let (name, params) = match signature.split_first() {
+                    Some((Expr { kind: ExprKind::Symbol(name), .. }, params)) => (*name, params),
+                    _ => return Err(bad_syntax("expected a procedure name")),
+                };
+                let (params, rest_param) = eval::parse_params(params, span)?;
+                self.lambda(Some(name), params, rest_param, rest, span)?;
+                name
+            }
+            _ => return Err(bad_syntax("expected a name")),
+        };
+
+        // Names defined in a body were already made into locals by `body`
+        let level = self.states.len() - 1;
+        let state = self.state();
+        let depth = state.depth;
+        let local = state.locals.iter().rev().find(|local| local.name == name && local.depth == depth);
+        let op = match local {
+            Some(local) => Op::SetLocal(local.slot),
+            None if level == 0 && depth == 0 => Op::DefineGlobal(name),
+            None => return Err(bad_syntax("can only be used at the start of a body")),
+        };
+        self.emit(op, span);
+        self.emit(Op::Unspecified, span);
+        Ok(())
+    }
+
+    fn set(&mut self, args: &[Expr], span: Span) -> Result<(), EvalError> {
+        let (target, value) = match args {
+            [target, value] => (target, value),
+            _ => return Err(EvalError::BadSyntax { form: "set!", reason: "expected a name and a value", span }),
+        };
+        let name = match &target.kind {
+            ExprKind::Symbol(name) => *name,
+            _ => return Err(EvalError::BadSyntax { form: "set!", reason: "expected a name", span }),
+        };
+        self.expr(value, false)?;
+        let level = self.states.len() - 1;
+        let op = match self.state().resolve_local(name) {
+            Some(slot) => Op::SetLocal(slot),
+            None => match self.resolve_upvalue(level, name) {
+                Some(upvalue) => Op::SetUpvalue(upvalue),
+                None => Op::SetGlobal(name.aliased().unwrap_or(name)),
+            },
+        };
+        self.emit(op, target.span);
+        self.emit(Op::Unspecified, span);
+        Ok(())
+    }
+
+    fn lambda_form(&mut self, args: &[Expr], span: Span) -> Result<(), EvalError> {
+        let (params, body) = match args.split_first() {
+            Some(split) => split,
+            None => return Err(EvalError::BadSyntax { form: "lambda", reason: "expected parameters and a body", span }),
+        };
+        let (params, rest) = match &params.kind {
+            ExprKind::List(params) => eval::parse_params(params, span)?,
+            ExprKind::Symbol(rest) => (Vec::new(), Some(*rest)),
+            _ => return Err(EvalError::BadSyntax { form: "lambda", reason: "expected a list of parameters", span }),
+        };
+        self.lambda(None, params, rest, body, span)
+    }
+
+    /// Compiles a function, and pushes a closure of it
+    fn lambda(
+        &mut self,
+        name: Option<Symbol>,
+        params: Vec<Symbol>,
+        rest: Option<Symbol>,
+        body: &[Expr],
+        span: Span,
+    ) -> Result<(), EvalError> {
+        if body.is_empty() {
+            return Err(EvalError::BadSyntax { form: "lambda", reason: "expected a body", span });
+        }
+        self.states.push(State::new(name, params.len(), rest.is_some()));
+        // The arguments are already on the stack when the function starts
+        for param in params.into_iter().chain(rest) {
+            self.state().stack += 1;
+            self.declare(param);
+        }
+        let compiled = self.body(body, true, span);
+        self.emit(Op::Return, span);
+        let state = self.states.pop().expect("the function was just pushed");
+        compiled?;
+
+        let chunk = self.chunk();
+        chunk.functions.push(Rc::new(state.function));
+        let function = index(chunk.functions.len() - 1);
+        self.emit(Op::Closure(function), span);
+        Ok(())
+    }
+
+    fn begin(&mut self, args: &[Expr], tail: bool, span: Span) -> Result<(), EvalError> {
+        self.sequence(args, tail, span)
+    }
+
+    /// `let`, where every value is computed before any of the names exist
+    fn let_form(&mut self, args: &[Expr], tail: bool, span: Span) -> Result<(), EvalError> {
+        let (bindings, body) = eval::parse_bindings("let", args, span)?;
+        for (_, value) in &bindings {
+            self.expr(value, false)?;
+        }
+        self.begin_scope();
+        // The values are already on the stack in the order of the names
+        let first = self.state().stack - index(bindings.len());
+        for (offset, (name, _)) in bindings.iter().enumerate() {
+            let state = self.state();
+            let (slot, depth) = (first + index(offset), state.depth);
+            state.locals.push(Local { name: *name, slot, depth });
+        }
+        self.body(body, tail, span)?;
+        self.end_scope(span);
+        Ok(())
+    }
+
+    /// `let*`, where each value can see the names before it
+    fn let_star(&mut self, args: &[Expr], tail: bool, span: Span) -> Result<(), EvalError> {
+        let (bindings, body) = eval::parse_bindings("let*", args, span)?;
+        self.begin_scope();
+        for (name, value) in bindings {
+            self.expr(value, false)?;
+            self.declare(name);
+        }
+        self.body(body, tail, span)?;
+        self.end_scope(span);
+        Ok(())
+    }
+
+    /// `letrec`, where every value can see every name
+    fn letrec(&mut self, args: &[Expr], tail: bool, span: Span) -> Result<(), EvalError> {
+        let (bindings, body) = eval::parse_bindings("letrec", args, span)?;
+        self.begin_scope();
+        let mut slots = Vec::new();
+        for (name, _) in &bindings {
+            self.emit(Op::Unspecified, span);
+            self.declare(*name);
+            slots.push(self.state().stack - 1);
+        }
+        for ((_, value), slot) in bindings.iter().zip(slots) {
+            self.expr(value, false)?;
+            self.emit(Op::SetLocal(slot), span);
+        }
+        self.body(body, tail, span)?;
+        self.end_scope(span);
+        Ok(())
+    }
+}
+
+/// Collects the names that a body `define`s, including inside of `begin`s
+fn defined_names(body: &[Expr], names: &mut Vec<Symbol>) {
+    for expr in body {
+        let items = match &expr.kind {
+            ExprKind::List(items) => items.as_slice(),
+            _ => continue,
+        };
+        let name = match items {
+            [Expr { kind: ExprKind::Symbol(Symbol::BEGIN), .. }, rest @ ..] => {
+                defined_names(rest, names);
+                continue;
+            }
+            [Expr { kind: ExprKind::Symbol(Symbol::DEFINE), .. }, target, ..] => match &target.kind {
+                ExprKind::Symbol(name) => *name,
+                ExprKind::List(signature) => match signature.first() {
+                    Some(Expr { kind: ExprKind::Symbol(name), .. }) => *name,
+                    _ => continue,
+                },
+                _ => continue,
+            },
+            _ => continue,
+        };
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+}
+
+/// Turns a count or position into an instruction operand
+fn index(value: usize) -> u32 {
+    u32::try_from(value).expect("too many instructions, constants or locals in one function")
+}
+
+/// Lists the instructions of a function and every function inside of it
+///
+/// Each instruction is shown with the line and column of the code that it
+/// came from, and constants are shown next to the instructions that use them.
+pub fn disassemble(function: &Function) -> String {
+    let mut output = String::new();
+    disassemble_into(&mut output, function, "<script>");
+    output
+}
+
+fn disassemble_into(output: &mut String, function: &Function, unnamed: &'static str) {
+    let name = function.name.map_or(unnamed, Symbol::as_str);
+    let _ = writeln!(output, "== {} ==", name);
+    let chunk = &function.chunk;
+    for (index, (op, span)) in chunk.code.iter().zip(&chunk.spans).enumerate() {
+        let line = format!("{:>4}  {:>7}  {}", index, span.start_pos.to_string(), op);
+        let _ = match op {
+            Op::Constant(constant) => writeln!(output, "{:<36}; {}", line, chunk.constants[*constant as usize]),
+            Op::Closure(closure) => {
+                let name = chunk.functions[*closure as usize].name.map_or("<lambda>", Symbol::as_str);
+                writeln!(output, "{:<36}; {}", line, name)
+            }
+            _ => writeln!(output, "{}", line),
+        };
+    }
+    for function in &chunk.functions {
+        output.push('\n');
+        disassemble_into(output, function, "<lambda>");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn compile_str(input: &str) -> Result<Rc<Function>, EvalError> {
+        compile(&parse(input).unwrap())
+    }
+
+    #[test]
+    fn test_instructions_are_small() {
+        assert!(std::mem::size_of::<Op>() <= 8);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let function = compile_str("(define (add-one x) (+ x 1))\n(add-one 2)").unwrap();
+        assert_eq!(disassemble(&function), "\
+== <script> ==
+   0      1:1  closure 0            ; add-one
+   1      1:1  define-global add-one
+   2      1:1  unspecified
+   3      1:1  pop
+   4      2:2  get-global add-one
+   5     2:10  constant 0           ; 2
+   6      2:1  call 1
+   7      2:1  return
+
+== add-one ==
+   0     1:22  get-global +
+   1     1:24  get-local 0
+   2     1:26  constant 0           ; 1
+   3     1:21  tail-call 2
+   4      1:1  return
+");
+    }
+
+    #[test]
+    fn test_locals_and_upvalues() {
+        let function = compile_str("(lambda (a) (let ((b 1)) (lambda () (set! a b))))").unwrap();
+        let outer = &function.chunk.functions[0];
+        assert_eq!((outer.arity, outer.variadic), (1, false));
+        let inner = &outer.chunk.functions[0];
+        assert_eq!(inner.captures, vec![Capture::Local(1), Capture::Local(0)]);
+        assert_eq!(inner.chunk.code[..2], [Op::GetUpvalue(0), Op::SetUpvalue(1)]);
+
+        let function = compile_str("(lambda (f) (lambda () (lambda () f)))").unwrap();
+        let middle = &function.chunk.functions[0].chunk.functions[0];
+        assert_eq!(middle.captures, vec![Capture::Local(0)]);
+        assert_eq!(middle.chunk.functions[0].captures, vec![Capture::Upvalue(0)]);
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let error = compile_str("(define (f) (if (g) (define x 1)))").err().unwrap();
+        assert!(matches!(error, EvalError::BadSyntax { form: "define", .. }));
+        let error = compile_str("(let ((x)) x)").err().unwrap();
+        assert!(matches!(error, EvalError::BadSyntax { form: "let", .. }));
+        assert!(compile_str("(lambda (x))").is_err());
+    }
+}
@@ -0,0 +1,338 @@
+//! Friendly error messages that show the code they are about
+//!
+//! An error like `unbound variable` is not much help on its own. A
+//! `Diagnostic` remembers where the problem is, and renders it like rustc
+//! does, with the offending line of code and some carets underneath it:
+//!
+//! ```text
+//! error: unbound variable `y`
+//!  --> main.lisp:1:20
+//!   |
+//! 1 | (define (f x) (+ x y))
+//!   |                    ^ not defined anywhere
+//!   |
+//!   = help: define `y` before using it
+//! ```
+//!
+//! The primary label (`^`) marks where the problem is. Secondary labels
+//! (`-`) can point out other places that help to explain it.
+
+use crate::eval::EvalError;
+use crate::lexer::{LexError, Span};
+use crate::parser::ParseError;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    /// The ANSI color code used for this severity: bold red or bold yellow
+    fn color(self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+        }
+    }
+}
+
+/// Some text attached to a span of the code
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    /// Primary labels are underlined with `^`, secondary ones with `-`
+    pub primary: bool,
+}
+
+/// A message about a problem in the code, and everything needed to explain it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Vec<String>,
+}
+
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Error, message.into())
+    }
+
+    pub fn warning(message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, message.into())
+    }
+
+    fn new(severity: Severity, message: String) -> Diagnostic {
+        Diagnostic { severity, message, labels: Vec::new(), notes: Vec::new(), help: Vec::new() }
+    }
+
+    /// Marks the place where the problem is
+    pub fn with_primary(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        self.labels.push(Label { span, message: message.into(), primary: true });
+        self
+    }
+
+    /// Points out another place that helps to explain the problem
+    pub fn with_secondary(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        self.labels.push(Label { span, message: message.into(), primary: false });
+        self
+    }
+
+    /// Adds some extra information, shown as `= note: ...`
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Adds a suggestion for fixing the problem, shown as `= help: ...`
+    pub fn with_help(mut self, help: impl Into<String>) -> Diagnostic {
+        self.help.push(help.into());
+        self
+    }
+
+    /// Renders the diagnostic, showing the lines of `source` that it points at
+    ///
+    /// With `color` turned on, the output includes ANSI escape codes to
+    /// color it in like a terminal compiler would.
+    pub fn render(&self, file_name: &str, source: &str, color: bool) -> String {
+        let paint = |code: &'static str| if color { code } else { "" };
+        let (blue, bold, reset) = (paint(BLUE), paint(BOLD), paint(RESET));
+        let severity = paint(self.severity.color());
+
+        let mut out = String::new();
+        let _ = writeln!(out, "{}{}{}: {}{}{}", severity, self.severity.name(), reset, bold, self.message, reset);
+
+        // Show each line that has a label on it, in order
+        let mut labels: Vec<&Label> = self.labels.iter().collect();
+        labels.sort_by_key(|label| (label.span.start, !label.primary));
+        let lines: Vec<&str> = source.lines().collect();
+        let last_line = labels.iter().map(|label| label.span.end_pos.line).max().unwrap_or(1);
+        let gutter = " ".repeat(last_line.to_string().len());
+
+        let location = labels.iter()
+            .find(|label| label.primary)
+            .or_else(|| labels.first())
+            .map(|label| label.span.in_file(file_name).to_string())
+            .unwrap_or_else(|| file_name.to_string());
+        let _ = writeln!(out, "{}{}-->{} {}", gutter, blue, reset, location);
+
+        if !labels.is_empty() {
+            let _ = writeln!(out, "{} {}|{}", gutter, blue, reset);
+        }
+        let mut previous_line = None;
+        for label in &labels {
+            let marker_color = if label.primary { severity } else { blue };
+            let marker = if label.primary { '^' } else { '-' };
+            let start = label.span.start_pos;
+            let end = label.span.end_pos;
+
+            // Each label gets its source lines and its own underline. A label
+            // that covers several lines is underlined on its first and last.
+            let mut underlines = Vec::new();
+            if start.line == end.line {
+                underlines.push((start.line, start.column, end.column.max(start.column + 1), true));
+            } else {
+                let first_line_len = lines.get(start.line - 1).map_or(0, |line| line.chars().count());
+                underlines.push((start.line, start.column, first_line_len.max(start.column) + 1, false));
+                let indent = lines.get(end.line - 1)
+                    .map_or(0, |line| line.chars().take_while(|ch| ch.is_whitespace()).count());
+                underlines.push((end.line, indent + 1, end.column.max(indent + 2), true));
+            }
+
+            for (line_number, from, to, with_message) in underlines {
+                if let Some(previous) = previous_line {
+                    if line_number > previous + 1 {
+                        let _ = writeln!(out, "{}{}...{}", gutter, blue, reset);
+                    }
+                }
+                let text = lines.get(line_number - 1).copied().unwrap_or("");
+                if previous_line != Some(line_number) {
+                    let _ = writeln!(out, "{}{:>width$} |{} {}", blue, line_number, reset, expand_tabs(text), width = gutter.len());
+                }
+                previous_line = Some(line_number);
+
+                // Work out where the underline goes once tabs have been expanded
+                let before: String = text.chars().take(from - 1).collect();
+                let underlined: String = text.chars().skip(from - 1).take(to - from).collect();
+                let padding = expand_tabs(&before).chars().count();
+                let width = expand_tabs(&underlined).chars().count().max(1);
+                let message = if with_message && !label.message.is_empty() {
+                    format!(" {}", label.message)
+                } else {
+                    String::new()
+                };
+                let _ = writeln!(
+                    out,
+                    "{} {}|{} {}{}{}{}{}",
+                    gutter, blue, reset,
+                    " ".repeat(padding),
+                    marker_color, marker.to_string().repeat(width), message, reset,
+                );
+            }
+        }
+
+        if !self.notes.is_empty() || !self.help.is_empty() {
+            if !labels.is_empty() {
+                let _ = writeln!(out, "{} {}|{}", gutter, blue, reset);
+            }
+            for note in &self.notes {
+                let _ = writeln!(out, "{} {}={} {}note{}: {}", gutter, blue, reset, bold, reset, note);
+            }
+            for help in &self.help {
+                let _ = writeln!(out, "{} {}={} {}help{}: {}", gutter, blue, reset, bold, reset, help);
+            }
+        }
+        out
+    }
+}
+
+/// Whether diagnostics written to stderr should be colored in
+///
+/// Color is used when stderr is a terminal, unless `NO_COLOR` is set.
+pub fn stderr_wants_color() -> bool {
+    use std::io::IsTerminal;
+    std::env::var_os("NO_COLOR").is_none() && std::io::stderr().is_terminal()
+}
+
+/// Replaces tabs with four spaces, so that the underlines line up the same
+/// way no matter how the terminal shows tabs
+fn expand_tabs(text: &str) -> String {
+    text.replace('\t', "    ")
+}
+
+impl From<&LexError> for Diagnostic {
+    fn from(error: &LexError) -> Diagnostic {
+        let diagnostic = Diagnostic::error(error.to_string());
+        let span = error.span();
+        match error {
+            LexError::UnexpectedChar { .. } => diagnostic.with_primary(span, "not allowed here"),
+            LexError::UnterminatedString { .. } => diagnostic
+                .with_primary(span, "this string is never closed")
+                .with_help("add a `\"` at the end of the string"),
+            LexError::InvalidEscape { .. } => diagnostic
+                .with_primary(span, "unknown escape")
+                .with_note("the escapes are `\\n`, `\\t`, `\\r`, `\\\"`, `\\\\` and `\\u{...}`"),
+            LexError::InvalidNumber { .. } => diagnostic.with_primary(span, "not a valid number"),
+            LexError::NumberOverflow { .. } => diagnostic
+                .with_primary(span, "too large")
+                .with_note("integers must fit in 64 bits"),
+            LexError::UnterminatedComment { .. } => diagnostic
+                .with_primary(span, "this comment is never closed")
+                .with_help("add a `|#` for every `#|`"),
+            LexError::MissingDatum { .. } => diagnostic.with_primary(span, "nothing after this to comment out"),
+        }
+    }
+}
+
+impl From<&ParseError> for Diagnostic {
+    fn from(error: &ParseError) -> Diagnostic {
+        match error {
+            ParseError::Lex(error) => error.into(),
+            ParseError::UnclosedParen { open } => Diagnostic::error("unclosed list")
+                .with_primary(*open, "this `(` is never closed")
+                .with_help("add a `)` to close the list"),
+            ParseError::UnexpectedCloseParen { span } => Diagnostic::error(error.to_string())
+                .with_primary(*span, "this `)` has no `(`")
+                .with_help("remove it, or add a `(` where the list should start"),
+            ParseError::MissingQuotedExpr { prefix } => Diagnostic::error(error.to_string())
+                .with_primary(*prefix, "nothing to quote after this"),
+        }
+    }
+}
+
+impl From<&EvalError> for Diagnostic {
+    fn from(error: &EvalError) -> Diagnostic {
+        let diagnostic = Diagnostic::error(error.to_string());
+        let span = error.span();
+        match error {
+            EvalError::Parse(error) => error.into(),
+            EvalError::UnboundVariable { name, .. } => diagnostic
+                .with_primary(span, "not defined anywhere")
+                .with_help(format!("define `{}` before using it", name)),
+            EvalError::NotAProcedure { .. } => diagnostic
+                .with_primary(span, "called here")
+                .with_note("the first item of a list is called as a procedure, use `'` to make a list of data"),
+            EvalError::WrongArgCount { .. } => diagnostic.with_primary(span, "in this call"),
+            EvalError::TypeMismatch { .. } => diagnostic.with_primary(span, "in this call"),
+            EvalError::DivisionByZero { .. } | EvalError::IntegerOverflow { .. } => {
+                diagnostic.with_primary(span, "in this calculation")
+            }
+            EvalError::BadSyntax { .. } => diagnostic.with_primary(span, ""),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::Interpreter;
+    use crate::lexer::Lexer;
+
+    #[test]
+    fn test_render_single_line() {
+        let source = "(define x 1)\n(define (f x) (+ x y))\n(f x)\n";
+        let error = Interpreter::new().eval_str(source).unwrap_err();
+        let rendered = Diagnostic::from(&error)
+            .with_note("variables are looked up where the procedure was defined")
+            .render("main.lisp", source, false);
+        assert_eq!(rendered, "\
+error: unbound variable `y`
+ --> main.lisp:2:20
+  |
+2 | (define (f x) (+ x y))
+  |                    ^ not defined anywhere
+  |
+  = note: variables are looked up where the procedure was defined
+  = help: define `y` before using it
+");
+    }
+
+    #[test]
+    fn test_render_labels_on_many_lines() {
+        let source = "(define (f x)\n\t(g x)\n  (h\n   x))\n";
+        let spans: Vec<_> = Lexer::new(source).map(|token| token.unwrap().span).collect();
+        let (define, g, h_list) = (spans[1], spans[7], spans[10].to(spans[13]));
+        let rendered = Diagnostic::warning("something is odd")
+            .with_primary(g, "first")
+            .with_secondary(define, "second")
+            .with_secondary(h_list, "spans lines")
+            .render("f.lisp", source, false);
+        assert_eq!(rendered, "\
+warning: something is odd
+ --> f.lisp:2:3
+  |
+1 | (define (f x)
+  |  ------ second
+2 |     (g x)
+  |      ^ first
+3 |   (h
+  |   --
+4 |    x))
+  |    -- spans lines
+");
+    }
+
+    #[test]
+    fn test_render_with_color() {
+        let source = "(a))";
+        let error = crate::parser::parse(source).unwrap_err();
+        let rendered = Diagnostic::from(&error).render("f.lisp", source, true);
+        assert!(rendered.starts_with("\x1b[1;31merror\x1b[0m: \x1b[1munexpected `)` with no matching `(`\x1b[0m\n"));
+        assert!(rendered.contains("\x1b[1;31m^ this `)` has no `(`\x1b[0m"));
+    }
+}
@@ -0,0 +1,1175 @@
+//! A tree-walking interpreter for the little lisp
+//!
+//! "Tree-walking" means that we run a program by looking directly at the
+//! `Expr` tree that the parser gave us. To evaluate `(+ 1 (* 2 3))` we
+//! evaluate each item in the list, which means evaluating `(* 2 3)` first,
+//! and then call the `+` procedure with the results.
+//!
+//! Variables live in environments. An environment is a table of names and
+//! values, plus a link to the environment it was created inside of. When a
+//! name is not found in one table we look in the next one out:
+//!
+//! ```text
+//! (define x 1)                 global:  x = 1, f = <procedure>
+//! (define (f y)                   ^
+//!   (let ((z 3))                  |
+//!     (+ x y z)))              f's call:  y = 2
+//! (f 2)                           ^
+//!                                 |
+//!                              let:  z = 3   <-- `x` is looked up from here
+//! ```
+//!
+//! Because a `lambda` remembers the environment it was created in, this
+//! gives us closures for free.
+
+use crate::gc;
+use crate::lexer::{Number, Span};
+use crate::macros::{self, Expander, Runtime};
+use crate::parser::{self, Expr, ExprKind, ParseError};
+use crate::symbol::Symbol;
+use crate::vm::Closure;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// A value that a lisp program can compute
+#[derive(Clone)]
+pub enum Value {
+    /// The empty list, `()`
+    Nil,
+    Bool(bool),
+    Number(Number),
+    Str(Rc<str>),
+    /// A symbol is a name used as data, such as the result of `'hello`
+    Symbol(Symbol),
+    /// A cons cell. Lists are chains of these ending in `Nil`
+    Pair(Rc<Pair>),
+    /// A procedure written in lisp with `lambda`
+    Procedure(Rc<Lambda>),
+    /// A procedure built into the interpreter, such as `+`
+    Builtin(Builtin),
+    /// A procedure compiled to bytecode, which only the `vm` can call
+    Closure(Rc<Closure>),
+    /// What `define`, `set!` and friends return, since they are only run for their effect
+    Unspecified,
+}
+
+impl Value {
+    /// Everything except `#f` counts as true, even `0` and `()`
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Bool(false))
+    }
+
+    pub fn cons(car: Value, cdr: Value) -> Value {
+        Value::Pair(gc::alloc(Pair(car, cdr)))
+    }
+
+    /// Builds a proper list out of the given values
+    pub fn list(items: Vec<Value>) -> Value {
+        items.into_iter().rev().fold(Value::Nil, |list, item| Value::cons(item, list))
+    }
+
+    /// Turns a piece of code into data, which is what `quote` does
+    ///
+    /// A `Symbol::global_alias` that a macro put in the code becomes the
+    /// plain name again, since as data it is just a name.
+    pub fn from_expr(expr: &Expr) -> Value {
+        match &expr.kind {
+            ExprKind::List(items) => Value::list(items.iter().map(Value::from_expr).collect()),
+            ExprKind::Symbol(name) => Value::Symbol(name.aliased().unwrap_or(*name)),
+            ExprKind::Number(number) => Value::Number(*number),
+            ExprKind::Str(string) => Value::Str(string.as_str().into()),
+            ExprKind::Bool(boolean) => Value::Bool(*boolean),
+        }
+    }
+
+    /// A short description of what kind of value this is, for error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "empty list",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::Str(_) => "string",
+            Value::Symbol(_) => "symbol",
+            Value::Pair(_) => "pair",
+            Value::Procedure(_) | Value::Builtin(_) | Value::Closure(_) => "procedure",
+            Value::Unspecified => "unspecified value",
+        }
+    }
+}
+
+/// Displays the value the way it would be written in code, so strings have quotes
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "()"),
+            Value::Bool(true) => write!(f, "#t"),
+            Value::Bool(false) => write!(f, "#f"),
+            Value::Number(number) => write!(f, "{}", number),
+            Value::Str(string) => parser::write_string_literal(f, string),
+            Value::Symbol(name) => write!(f, "{}", name),
+            Value::Pair(pair) => {
+                write!(f, "({}", pair.0)?;
+                let mut rest = &pair.1;
+                loop {
+                    match rest {
+                        Value::Nil => break,
+                        Value::Pair(pair) => {
+                            write!(f, " {}", pair.0)?;
+                            rest = &pair.1;
+                        }
+                        other => {
+                            write!(f, " . {}", other)?;
+                            break;
+                        }
+                    }
+                }
+                write!(f, ")")
+            }
+            Value::Procedure(lambda) => match &lambda.name {
+                Some(name) => write!(f, "#<procedure {}>", name),
+                None => write!(f, "#<procedure>"),
+            },
+            Value::Builtin(builtin) => write!(f, "#<builtin {}>", builtin.name),
+            Value::Closure(closure) => match closure.function.name {
+                Some(name) => write!(f, "#<procedure {}>", name),
+                None => write!(f, "#<procedure>"),
+            },
+            Value::Unspecified => write!(f, "#<unspecified>"),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+/// A cons cell, with its car and its cdr
+pub struct Pair(pub Value, pub Value);
+
+/// Frees the rest of a list one pair at a time
+///
+/// Left to itself, dropping a pair would drop its cdr, which would drop the
+/// next cdr, and so on, which runs out of stack on a long enough list. So
+/// instead we take each cdr out before it gets dropped, for as long as
+/// nothing else is using it.
+impl Drop for Pair {
+    fn drop(&mut self) {
+        let mut rest = std::mem::replace(&mut self.1, Value::Nil);
+        while let Value::Pair(pair) = rest {
+            rest = match Rc::try_unwrap(pair) {
+                Ok(mut pair) => std::mem::replace(&mut pair.1, Value::Nil),
+                Err(_) => break,
+            };
+        }
+    }
+}
+
+/// A procedure made by `lambda`, along with the environment it closes over
+pub struct Lambda {
+    /// The name it was defined with, if any, to make error messages nicer
+    pub name: Option<Symbol>,
+    pub params: Vec<Symbol>,
+    /// With `(lambda args ...)`, every argument is collected into a list named `args`
+    pub rest: Option<Symbol>,
+    pub body: Rc<[Expr]>,
+    pub env: Env,
+}
+
+/// A procedure that is implemented in Rust
+#[derive(Clone, Copy)]
+pub struct Builtin {
+    pub name: &'static str,
+    /// Called with the evaluated arguments and the span of the whole call
+    pub func: fn(&[Value], Span) -> Result<Value, EvalError>,
+}
+
+/// A shared, mutable link to an environment
+///
+/// Many closures may share the same environment, and `set!` may change it,
+/// so environments live behind an `Rc<RefCell<..>>`. A closure and the
+/// environment it was defined in often point to each other, so the `gc`
+/// keeps track of environments too.
+#[derive(Clone)]
+pub struct Env(pub(crate) Rc<RefCell<Scope>>);
+
+pub(crate) struct Scope {
+    pub(crate) vars: HashMap<Symbol, Value>,
+    pub(crate) parent: Option<Env>,
+}
+
+impl Env {
+    /// An empty environment with no parent
+    pub fn new() -> Env {
+        Env(gc::alloc(RefCell::new(Scope { vars: HashMap::new(), parent: None })))
+    }
+
+    /// A new, empty environment inside of this one
+    pub fn extend(&self) -> Env {
+        Env(gc::alloc(RefCell::new(Scope { vars: HashMap::new(), parent: Some(self.clone()) })))
+    }
+
+    /// Creates (or replaces) a variable in this environment
+    pub fn define(&self, name: Symbol, value: Value) {
+        self.0.borrow_mut().vars.insert(name, value);
+    }
+
+    /// Finds the value of a variable, looking outwards through the parents
+    ///
+    /// A `Symbol::global_alias` is found in the outermost environment, under
+    /// the name it is an alias of.
+    pub fn lookup(&self, name: Symbol) -> Option<Value> {
+        let mut env = self.clone();
+        loop {
+            let parent = {
+                let scope = env.0.borrow();
+                if let Some(value) = scope.vars.get(&name) {
+                    return Some(value.clone());
+                }
+                match scope.parent.clone() {
+                    Some(parent) => parent,
+                    None => return scope.vars.get(&name.aliased()?).cloned(),
+                }
+            };
+            env = parent;
+        }
+    }
+
+    /// Changes an existing variable, returning false if there is no such variable
+    pub fn set(&self, name: Symbol, value: Value) -> bool {
+        let mut env = self.clone();
+        loop {
+            let parent = {
+                let mut scope = env.0.borrow_mut();
+                if let Some(slot) = scope.vars.get_mut(&name) {
+                    *slot = value;
+                    return true;
+                }
+                match scope.parent.clone() {
+                    Some(parent) => parent,
+                    None => match name.aliased().and_then(|name| scope.vars.get_mut(&name)) {
+                        Some(slot) => {
+                            *slot = value;
+                            return true;
+                        }
+                        None => return false,
+                    },
+                }
+            };
+            env = parent;
+        }
+    }
+}
+
+impl Default for Env {
+    fn default() -> Env {
+        Env::new()
+    }
+}
+
+/// Everything that can go wrong while running a program
+///
+/// Every error carries the span of the code that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// The program could not be parsed in the first place
+    Parse(ParseError),
+    UnboundVariable { name: String, span: Span },
+    /// Something that is not a procedure was called like one
+    NotAProcedure { value: String, span: Span },
+    WrongArgCount { expected: usize, variadic: bool, got: usize, span: Span },
+    TypeMismatch { expected: &'static str, found: &'static str, span: Span },
+    DivisionByZero { span: Span },
+    IntegerOverflow { span: Span },
+    /// A special form like `if` or `let` that is not written correctly
+    BadSyntax { form: &'static str, reason: &'static str, span: Span },
+}
+
+impl EvalError {
+    /// The part of the program that this error is about
+    pub fn span(&self) -> Span {
+        match self {
+            EvalError::Parse(error) => error.span(),
+            EvalError::UnboundVariable { span, .. }
+            | EvalError::NotAProcedure { span, .. }
+            | EvalError::WrongArgCount { span, .. }
+            | EvalError::TypeMismatch { span, .. }
+            | EvalError::DivisionByZero { span }
+            | EvalError::IntegerOverflow { span }
+            | EvalError::BadSyntax { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::Parse(error) => write!(f, "{}", error),
+            EvalError::UnboundVariable { name, .. } => write!(f, "unbound variable `{}`", name),
+            EvalError::NotAProcedure { value, .. } => write!(f, "`{}` is not a procedure", value),
+            EvalError::WrongArgCount { expected, variadic, got, .. } => {
+                let at_least = if *variadic { "at least " } else { "" };
+                let plural = if *expected == 1 { "" } else { "s" };
+                write!(f, "expected {}{} argument{}, got {}", at_least, expected, plural, got)
+            }
+            EvalError::TypeMismatch { expected, found, .. } => {
+                write!(f, "expected a {}, found a {}", expected, found)
+            }
+            EvalError::DivisionByZero { .. } => write!(f, "division by zero"),
+            EvalError::IntegerOverflow { .. } => write!(f, "integer overflow"),
+            EvalError::BadSyntax { form, reason, .. } => write!(f, "bad `{}`: {}", form, reason),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+impl From<ParseError> for EvalError {
+    fn from(error: ParseError) -> EvalError {
+        EvalError::Parse(error)
+    }
+}
+
+/// An interpreter, which holds on to the global environment between runs
+///
+/// ```
+/// use csh_seminar_feb_2021::eval::Interpreter;
+///
+/// let mut interpreter = Interpreter::new();
+/// interpreter.eval_str("(define (square x) (* x x))").unwrap();
+/// let result = interpreter.eval_str("(square 12)").unwrap();
+/// assert_eq!(result.to_string(), "144");
+/// ```
+pub struct Interpreter {
+    globals: Env,
+    macros: Expander,
+}
+
+impl Interpreter {
+    /// A new interpreter with all of the builtin procedures defined
+    pub fn new() -> Interpreter {
+        let globals = Env::new();
+        for builtin in builtins::ALL {
+            globals.define(Symbol::intern(builtin.name), Value::Builtin(*builtin));
+        }
+        for (name, builtin) in macros::quasiquote_builtins() {
+            globals.define(*name, Value::Builtin(*builtin));
+        }
+        Interpreter { globals, macros: Expander::new() }
+    }
+
+    pub fn globals(&self) -> &Env {
+        &self.globals
+    }
+
+    /// Parses and runs every expression in the input, returning the value of the last one
+    pub fn eval_str(&mut self, input: &str) -> Result<Value, EvalError> {
+        let mut result = Value::Unspecified;
+        for expr in parser::Parser::new(crate::lexer::Lexer::new(input)) {
+            result = self.eval(&expr?)?;
+        }
+        Ok(result)
+    }
+
+    /// Runs a single top-level expression, after expanding any macros in it
+    pub fn eval(&mut self, expr: &Expr) -> Result<Value, EvalError> {
+        let expr = self.expand(expr)?;
+        eval(&expr, &self.globals)
+    }
+
+    /// Expands every macro in a top-level expression, remembering any macros it defines
+    pub fn expand(&mut self, expr: &Expr) -> Result<Expr, EvalError> {
+        self.macros.expand(expr, &mut self.globals)
+    }
+}
+
+/// The interpreter makes the procedures from `defmacro` in its global environment
+impl Runtime for Env {
+    fn procedure(&mut self, name: Symbol, params: Vec<Symbol>, rest: Option<Symbol>, body: &[Expr], span: Span) -> Result<Value, EvalError> {
+        make_lambda(Some(name), params, rest, body, self, span)
+    }
+
+    fn apply(&mut self, procedure: &Value, args: &[Value], span: Span) -> Result<Value, EvalError> {
+        apply(procedure, args, span)
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+/// The names of the special forms, which look like procedure calls but
+/// decide for themselves which of their arguments to evaluate
+pub const SPECIAL_FORMS: &[&str] = &[
+    "quote",
+    "quasiquote",
+    "if",
+    "cond",
+    "define",
+    "set!",
+    "lambda",
+    "begin",
+    "let",
+    "let*",
+    "letrec",
+    "defmacro",
+    "define-syntax",
+    "syntax-rules",
+    "macroexpand-1",
+    "macroexpand",
+];
+
+/// Every procedure that is built into the interpreter
+pub const BUILTINS: &[Builtin] = builtins::ALL;
+
+/// What is left to do to evaluate an expression, once everything except
+/// a call in tail position is done
+///
+/// A call is in "tail position" when its value is the value of the whole
+/// expression, like the last expression in a body or either branch of an
+/// `if`. Nothing is left to do after such a call returns, so instead of
+/// making the call (and using up more of the Rust stack) we hand it back
+/// to `finish`, which makes it once the current expression is out of the way.
+enum Tail {
+    Done(Value),
+    Call(Rc<Lambda>, Vec<Value>, Span),
+}
+
+/// Evaluates an expression in the given environment
+///
+/// Calls in tail position don't use up any stack, so a loop written as a
+/// recursive procedure can run for as long as it likes.
+pub fn eval(expr: &Expr, env: &Env) -> Result<Value, EvalError> {
+    finish(eval_tail(expr, env)?)
+}
+
+/// Makes tail calls one after the other, until one of them gives a value
+fn finish(mut tail: Tail) -> Result<Value, EvalError> {
+    loop {
+        match tail {
+            Tail::Done(value) => return Ok(value),
+            Tail::Call(lambda, args, span) => {
+                let env = bind_arguments(&lambda, &args, span)?;
+                tail = eval_body_tail(&lambda.body, &env)?;
+            }
+        }
+    }
+}
+
+/// Evaluates an expression, except for a call in tail position
+fn eval_tail(expr: &Expr, env: &Env) -> Result<Tail, EvalError> {
+    let items = match &expr.kind {
+        ExprKind::Symbol(name) => {
+            return env.lookup(*name).map(Tail::Done).ok_or_else(|| EvalError::UnboundVariable {
+                name: name.to_string(),
+                span: expr.span,
+            });
+        }
+        ExprKind::Number(number) => return Ok(Tail::Done(Value::Number(*number))),
+        ExprKind::Str(string) => return Ok(Tail::Done(Value::Str(string.as_str().into()))),
+        ExprKind::Bool(boolean) => return Ok(Tail::Done(Value::Bool(*boolean))),
+        ExprKind::List(items) => items,
+    };
+
+    let (head, args) = match items.split_first() {
+        Some(split) => split,
+        None => return Err(EvalError::BadSyntax { form: "()", reason: "cannot evaluate an empty list", span: expr.span }),
+    };
+
+    // Special forms get to look at their arguments before (or instead of)
+    // evaluating them. Everything else is a procedure call.
+    if let ExprKind::Symbol(name) = &head.kind {
+        match *name {
+            Symbol::QUOTE => return eval_quote(args, expr.span).map(Tail::Done),
+            Symbol::IF => return eval_if(args, env, expr.span),
+            Symbol::COND => return eval_cond(args, env, expr.span),
+            Symbol::DEFINE => return eval_define(args, env, expr.span).map(Tail::Done),
+            Symbol::SET => return eval_set(args, env, expr.span).map(Tail::Done),
+            Symbol::LAMBDA => return eval_lambda(None, args, env, expr.span).map(Tail::Done),
+            Symbol::BEGIN => return eval_body_tail(args, env),
+            Symbol::LET => return eval_let(args, env, expr.span),
+            Symbol::LET_STAR => return eval_let_star(args, env, expr.span),
+            Symbol::LETREC => return eval_letrec(args, env, expr.span),
+            _ => (),
+        }
+    }
+
+    let procedure = eval(head, env)?;
+    let args = args.iter().map(|arg| eval(arg, env)).collect::<Result<Vec<_>, _>>()?;
+    match procedure {
+        Value::Procedure(lambda) => Ok(Tail::Call(lambda, args, expr.span)),
+        procedure => apply(&procedure, &args, expr.span).map(Tail::Done),
+    }
+}
+
+/// Calls a procedure with some already-evaluated arguments
+pub fn apply(procedure: &Value, args: &[Value], span: Span) -> Result<Value, EvalError> {
+    match procedure {
+        Value::Builtin(builtin) => (builtin.func)(args, span),
+        Value::Procedure(lambda) => finish(Tail::Call(lambda.clone(), args.to_vec(), span)),
+        other => Err(EvalError::NotAProcedure { value: other.to_string(), span }),
+    }
+}
+
+/// Makes the environment for a call to `lambda`, with each parameter bound to its argument
+fn bind_arguments(lambda: &Lambda, args: &[Value], span: Span) -> Result<Env, EvalError> {
+    let expected = lambda.params.len();
+    let variadic = lambda.rest.is_some();
+    if args.len() < expected || (!variadic && args.len() > expected) {
+        return Err(EvalError::WrongArgCount { expected, variadic, got: args.len(), span });
+    }
+
+    let env = lambda.env.extend();
+    for (param, arg) in lambda.params.iter().zip(args) {
+        env.define(*param, arg.clone());
+    }
+    if let Some(rest) = lambda.rest {
+        env.define(rest, Value::list(args[expected..].to_vec()));
+    }
+    Ok(env)
+}
+
+/// Evaluates each expression in turn, leaving the last one in tail position
+fn eval_body_tail(body: &[Expr], env: &Env) -> Result<Tail, EvalError> {
+    let (last, rest) = match body.split_last() {
+        Some(split) => split,
+        None => return Ok(Tail::Done(Value::Unspecified)),
+    };
+    for expr in rest {
+        eval(expr, env)?;
+    }
+    eval_tail(last, env)
+}
+
+fn eval_quote(args: &[Expr], span: Span) -> Result<Value, EvalError> {
+    match args {
+        [datum] => Ok(Value::from_expr(datum)),
+        _ => Err(EvalError::BadSyntax { form: "quote", reason: "expected exactly one expression", span }),
+    }
+}
+
+/// `(if condition then)` or `(if condition then else)`
+fn eval_if(args: &[Expr], env: &Env, span: Span) -> Result<Tail, EvalError> {
+    let (condition, then, otherwise) = match args {
+        [condition, then] => (condition, then, None),
+        [condition, then, otherwise] => (condition, then, Some(otherwise)),
+        _ => return Err(EvalError::BadSyntax { form: "if", reason: "expected a condition and one or two branches", span }),
+    };
+    if eval(condition, env)?.is_truthy() {
+        eval_tail(then, env)
+    } else {
+        otherwise.map_or(Ok(Tail::Done(Value::Unspecified)), |otherwise| eval_tail(otherwise, env))
+    }
+}
+
+/// `(cond (condition body...) ... (else body...))`, which runs the body of
+/// the first clause whose condition is true
+///
+/// A clause with no body gives the value of its condition instead.
+fn eval_cond(args: &[Expr], env: &Env, span: Span) -> Result<Tail, EvalError> {
+    for (condition, body) in parse_clauses(args, span)? {
+        let condition = match condition {
+            Some(condition) => eval(condition, env)?,
+            None => return eval_body_tail(body, env),
+        };
+        if condition.is_truthy() {
+            return match body {
+                [] => Ok(Tail::Done(condition)),
+                body => eval_body_tail(body, env),
+            };
+        }
+    }
+    Ok(Tail::Done(Value::Unspecified))
+}
+
+/// The clauses of a `cond`, where the condition of the `else` clause is `None`
+pub(crate) type Clauses<'e> = Vec<(Option<&'e Expr>, &'e [Expr])>;
+
+/// Reads the `(condition body...)` clauses of a `cond`
+pub(crate) fn parse_clauses(args: &[Expr], span: Span) -> Result<Clauses<'_>, EvalError> {
+    let bad_syntax = |reason| EvalError::BadSyntax { form: "cond", reason, span };
+    args.iter()
+        .enumerate()
+        .map(|(index, clause)| {
+            let (condition, body) = match &clause.kind {
+                ExprKind::List(items) => items.split_first().ok_or_else(|| bad_syntax("each clause needs a condition"))?,
+                _ => return Err(bad_syntax("each clause must be a list")),
+            };
+            match condition.kind {
+                ExprKind::Symbol(Symbol::ELSE) if index + 1 < args.len() => Err(bad_syntax("`else` must be the last clause")),
+                ExprKind::Symbol(Symbol::ELSE) if body.is_empty() => Err(bad_syntax("expected a body after `else`")),
+                ExprKind::Symbol(Symbol::ELSE) => Ok((None, body)),
+                _ => Ok((Some(condition), body)),
+            }
+        })
+        .collect()
+}
+
+/// `(define name value)` or `(define (name params...) body...)`
+fn eval_define(args: &[Expr], env: &Env, span: Span) -> Result<Value, EvalError> {
+    let bad_syntax = |reason| EvalError::BadSyntax { form: "define", reason, span };
+    let (target, rest) = args.split_first().ok_or_else(|| bad_syntax("expected a name"))?;
+    match &target.kind {
+        ExprKind::Symbol(name) => {
+            let value = match rest {
+                [value] => eval(value, env)?,
+                _ => return Err(bad_syntax("expected exactly one value")),
+            };
+            env.define(*name, value);
+        }
+        // The procedure shorthand: the params are everything after the name
+        ExprKind::List(signature) => {
+            let (name, params) = match signature.split_first() {
+                Some((Expr { kind: ExprKind::Symbol(name), .. }, params)) => (name, params),
+                _ => return Err(bad_syntax("expected a procedure name")),
+            };
+            let (params, rest_param) = parse_params(params, span)?;
+            let lambda = make_lambda(Some(*name), params, rest_param, rest, env, span)?;
+            env.define(*name, lambda);
+        }
+        _ => return Err(bad_syntax("expected a name")),
+    }
+    Ok(Value::Unspecified)
+}
+
+/// `(set! name value)`, which changes a variable that already exists
+fn eval_set(args: &[Expr], env: &Env, span: Span) -> Result<Value, EvalError> {
+    let (target, value) = match args {
+        [target, value] => (target, value),
+        _ => return Err(EvalError::BadSyntax { form: "set!", reason: "expected a name and a value", span }),
+    };
+    let name = match &target.kind {
+        ExprKind::Symbol(name) => name,
+        _ => return Err(EvalError::BadSyntax { form: "set!", reason: "expected a name", span }),
+    };
+    let value = eval(value, env)?;
+    if env.set(*name, value) {
+        Ok(Value::Unspecified)
+    } else {
+        Err(EvalError::UnboundVariable { name: name.to_string(), span: target.span })
+    }
+}
+
+/// `(lambda (params...) body...)` or `(lambda args body...)`
+fn eval_lambda(name: Option<Symbol>, args: &[Expr], env: &Env, span: Span) -> Result<Value, EvalError> {
+    let (params, body) = match args.split_first() {
+        Some(split) => split,
+        None => return Err(EvalError::BadSyntax { form: "lambda", reason: "expected parameters and a body", span }),
+    };
+    let (params, rest) = match &params.kind {
+        ExprKind::List(params) => parse_params(params, span)?,
+        ExprKind::Symbol(rest) => (Vec::new(), Some(*rest)),
+        _ => return Err(EvalError::BadSyntax { form: "lambda", reason: "expected a list of parameters", span }),
+    };
+    make_lambda(name, params, rest, body, env, span)
+}
+
+/// Makes a procedure out of parameters and a body that have already been read
+pub(crate) fn make_lambda(
+    name: Option<Symbol>,
+    params: Vec<Symbol>,
+    rest: Option<Symbol>,
+    body: &[Expr],
+    env: &Env,
+    span: Span,
+) -> Result<Value, EvalError> {
+    if body.is_empty() {
+        return Err(EvalError::BadSyntax { form: "lambda", reason: "expected a body", span });
+    }
+    Ok(Value::Procedure(gc::alloc(Lambda { name, params, rest, body: body.into(), env: env.clone() })))
+}
+
+/// Reads a parameter list, where `. rest` collects any remaining arguments
+pub(crate) fn parse_params(params: &[Expr], span: Span) -> Result<(Vec<Symbol>, Option<Symbol>), EvalError> {
+    let bad_syntax = EvalError::BadSyntax { form: "lambda", reason: "parameters must be names", span };
+    let mut names = Vec::new();
+    let mut rest = None;
+    let mut iter = params.iter();
+    while let Some(param) = iter.next() {
+        match &param.kind {
+            ExprKind::Symbol(name) if *name == Symbol::DOT => {
+                rest = match (iter.next(), iter.next()) {
+                    (Some(Expr { kind: ExprKind::Symbol(rest), .. }), None) => Some(*rest),
+                    _ => return Err(bad_syntax),
+                };
+            }
+            ExprKind::Symbol(name) => names.push(*name),
+            _ => return Err(bad_syntax),
+        }
+    }
+    Ok((names, rest))
+}
+
+/// The names and value expressions from the start of a `let`
+pub(crate) type Bindings<'e> = Vec<(Symbol, &'e Expr)>;
+
+/// Reads the `((name value) ...)` bindings at the start of `let` and friends,
+/// returning them along with the body that follows
+pub(crate) fn parse_bindings<'e>(form: &'static str, args: &'e [Expr], span: Span) -> Result<(Bindings<'e>, &'e [Expr]), EvalError> {
+    let bad_syntax = |reason| EvalError::BadSyntax { form, reason, span };
+    let (bindings, body) = args.split_first().ok_or_else(|| bad_syntax("expected bindings and a body"))?;
+    if body.is_empty() {
+        return Err(bad_syntax("expected a body"));
+    }
+    let bindings = match &bindings.kind {
+        ExprKind::List(bindings) => bindings,
+        _ => return Err(bad_syntax("expected a list of bindings")),
+    };
+    let bindings = bindings.iter()
+        .map(|binding| match &binding.kind {
+            ExprKind::List(pair) => match pair.as_slice() {
+                [Expr { kind: ExprKind::Symbol(name), .. }, value] => Ok((*name, value)),
+                _ => Err(bad_syntax("each binding must be a name and a value")),
+            },
+            _ => Err(bad_syntax("each binding must be a name and a value")),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((bindings, body))
+}
+
+/// `(let ((name value) ...) body...)`, where every value is evaluated outside the new scope
+fn eval_let(args: &[Expr], env: &Env, span: Span) -> Result<Tail, EvalError> {
+    let (bindings, body) = parse_bindings("let", args, span)?;
+    let scope = env.extend();
+    for (name, value) in bindings {
+        scope.define(name, eval(value, env)?);
+    }
+    eval_body_tail(body, &scope)
+}
+
+/// `(let* ((name value) ...) body...)`, where each value can see the names before it
+fn eval_let_star(args: &[Expr], env: &Env, span: Span) -> Result<Tail, EvalError> {
+    let (bindings, body) = parse_bindings("let*", args, span)?;
+    let mut scope = env.clone();
+    for (name, value) in bindings {
+        let value = eval(value, &scope)?;
+        scope = scope.extend();
+        scope.define(name, value);
+    }
+    eval_body_tail(body, &scope.extend())
+}
+
+/// `(letrec ((name value) ...) body...)`, where every value can see every name,
+/// so that procedures can call each other recursively
+fn eval_letrec(args: &[Expr], env: &Env, span: Span) -> Result<Tail, EvalError> {
+    let (bindings, body) = parse_bindings("letrec", args, span)?;
+    let scope = env.extend();
+    for (name, _) in &bindings {
+        scope.define(*name, Value::Unspecified);
+    }
+    for (name, value) in &bindings {
+        let value = eval(value, &scope)?;
+        scope.define(*name, value);
+    }
+    eval_body_tail(body, &scope)
+}
+
+/// The procedures that every program starts out with
+mod builtins {
+    use super::{Builtin, EvalError, Pair, Value};
+    use crate::lexer::{Number, Span};
+    use std::cmp::Ordering;
+    use std::rc::Rc;
+
+    pub const ALL: &[Builtin] = &[
+        Builtin { name: "+", func: add },
+        Builtin { name: "-", func: subtract },
+        Builtin { name: "*", func: multiply },
+        Builtin { name: "/", func: divide },
+        Builtin { name: "=", func: |args, span| compare(args, span, Ordering::is_eq) },
+        Builtin { name: "<", func: |args, span| compare(args, span, Ordering::is_lt) },
+        Builtin { name: ">", func: |args, span| compare(args, span, Ordering::is_gt) },
+        Builtin { name: "<=", func: |args, span| compare(args, span, Ordering::is_le) },
+        Builtin { name: ">=", func: |args, span| compare(args, span, Ordering::is_ge) },
+        Builtin { name: "not", func: |args, span| Ok(Value::Bool(!one(args, span)?.is_truthy())) },
+        Builtin { name: "cons", func: |args, span| {
+            let (car, cdr) = two(args, span)?;
+            Ok(Value::cons(car.clone(), cdr.clone()))
+        } },
+        Builtin { name: "car", func: |args, span| Ok(pair(one(args, span)?, span)?.0.clone()) },
+        Builtin { name: "cdr", func: |args, span| Ok(pair(one(args, span)?, span)?.1.clone()) },
+        Builtin { name: "list", func: |args, _| Ok(Value::list(args.to_vec())) },
+        Builtin { name: "append", func: append },
+        Builtin { name: "null?", func: |args, span| Ok(Value::Bool(matches!(one(args, span)?, Value::Nil))) },
+        Builtin { name: "pair?", func: |args, span| Ok(Value::Bool(matches!(one(args, span)?, Value::Pair(_)))) },
+        Builtin { name: "number?", func: |args, span| Ok(Value::Bool(matches!(one(args, span)?, Value::Number(_)))) },
+        Builtin { name: "string?", func: |args, span| Ok(Value::Bool(matches!(one(args, span)?, Value::Str(_)))) },
+        Builtin { name: "symbol?", func: |args, span| Ok(Value::Bool(matches!(one(args, span)?, Value::Symbol(_)))) },
+        Builtin { name: "procedure?", func: |args, span| {
+            Ok(Value::Bool(matches!(one(args, span)?, Value::Procedure(_) | Value::Builtin(_) | Value::Closure(_))))
+        } },
+        Builtin { name: "eq?", func: |args, span| {
+            let (a, b) = two(args, span)?;
+            Ok(Value::Bool(is_eq(a, b)))
+        } },
+        Builtin { name: "equal?", func: |args, span| {
+            let (a, b) = two(args, span)?;
+            Ok(Value::Bool(is_equal(a, b)))
+        } },
+        Builtin { name: "string-append", func: |args, span| {
+            let mut result = String::new();
+            for arg in args {
+                match arg {
+                    Value::Str(string) => result.push_str(string),
+                    other => return Err(mismatch("string", other, span)),
+                }
+            }
+            Ok(Value::Str(result.into()))
+        } },
+        Builtin { name: "display", func: |args, span| {
+            match one(args, span)? {
+                Value::Str(string) => print!("{}", string),
+                other => print!("{}", other),
+            }
+            Ok(Value::Unspecified)
+        } },
+        Builtin { name: "newline", func: |args, span| {
+            none(args, span)?;
+            println!();
+            Ok(Value::Unspecified)
+        } },
+    ];
+
+    fn mismatch(expected: &'static str, found: &Value, span: Span) -> EvalError {
+        EvalError::TypeMismatch { expected, found: found.type_name(), span }
+    }
+
+    fn arg_count(args: &[Value], expected: usize, span: Span) -> Result<(), EvalError> {
+        if args.len() == expected {
+            Ok(())
+        } else {
+            Err(EvalError::WrongArgCount { expected, variadic: false, got: args.len(), span })
+        }
+    }
+
+    fn none(args: &[Value], span: Span) -> Result<(), EvalError> {
+        arg_count(args, 0, span)
+    }
+
+    fn one(args: &[Value], span: Span) -> Result<&Value, EvalError> {
+        arg_count(args, 1, span)?;
+        Ok(&args[0])
+    }
+
+    fn two(args: &[Value], span: Span) -> Result<(&Value, &Value), EvalError> {
+        arg_count(args, 2, span)?;
+        Ok((&args[0], &args[1]))
+    }
+
+    fn pair(value: &Value, span: Span) -> Result<&Rc<Pair>, EvalError> {
+        match value {
+            Value::Pair(pair) => Ok(pair),
+            other => Err(mismatch("pair", other, span)),
+        }
+    }
+
+    /// `(append list ...)` joins lists together. The last one isn't copied,
+    /// and doesn't even need to be a list.
+    fn append(args: &[Value], span: Span) -> Result<Value, EvalError> {
+        let (last, lists) = match args.split_last() {
+            Some(split) => split,
+            None => return Ok(Value::Nil),
+        };
+        let mut items = Vec::new();
+        for list in lists {
+            let mut rest = list;
+            while let Value::Pair(pair) = rest {
+                items.push(pair.0.clone());
+                rest = &pair.1;
+            }
+            if !matches!(rest, Value::Nil) {
+                return Err(mismatch("list", list, span));
+            }
+        }
+        Ok(items.into_iter().rev().fold(last.clone(), |list, item| Value::cons(item, list)))
+    }
+
+    fn number(value: &Value, span: Span) -> Result<Number, EvalError> {
+        match value {
+            Value::Number(number) => Ok(*number),
+            other => Err(mismatch("number", other, span)),
+        }
+    }
+
+    fn as_float(number: Number) -> f64 {
+        match number {
+            Number::Integer(integer) => integer as f64,
+            Number::Float(float) => float,
+        }
+    }
+
+    /// Applies an operation to two numbers, staying with integers when both are integers
+    fn arithmetic(
+        a: Number,
+        b: Number,
+        span: Span,
+        integer_op: fn(i64, i64) -> Option<i64>,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Result<Number, EvalError> {
+        match (a, b) {
+            (Number::Integer(a), Number::Integer(b)) => integer_op(a, b)
+                .map(Number::Integer)
+                .ok_or(EvalError::IntegerOverflow { span }),
+            (a, b) => Ok(Number::Float(float_op(as_float(a), as_float(b)))),
+        }
+    }
+
+    /// Folds an operation over all of the arguments, starting from `first`
+    fn fold(
+        first: Number,
+        args: &[Value],
+        span: Span,
+        integer_op: fn(i64, i64) -> Option<i64>,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Result<Value, EvalError> {
+        let mut result = first;
+        for arg in args {
+            result = arithmetic(result, number(arg, span)?, span, integer_op, float_op)?;
+        }
+        Ok(Value::Number(result))
+    }
+
+    fn add(args: &[Value], span: Span) -> Result<Value, EvalError> {
+        fold(Number::Integer(0), args, span, i64::checked_add, |a, b| a + b)
+    }
+
+    fn multiply(args: &[Value], span: Span) -> Result<Value, EvalError> {
+        fold(Number::Integer(1), args, span, i64::checked_mul, |a, b| a * b)
+    }
+
+    /// `(- x)` negates `x`, and `(- x y z)` subtracts `y` and `z` from `x`
+    fn subtract(args: &[Value], span: Span) -> Result<Value, EvalError> {
+        match args {
+            [] => Err(EvalError::WrongArgCount { expected: 1, variadic: true, got: 0, span }),
+            [only] => fold(Number::Integer(0), std::slice::from_ref(only), span, i64::checked_sub, |a, b| a - b),
+            [first, rest @ ..] => fold(number(first, span)?, rest, span, i64::checked_sub, |a, b| a - b),
+        }
+    }
+
+    /// `(/ x)` is `1/x`, and `(/ x y z)` divides `x` by `y` and then `z`
+    ///
+    /// Dividing integers gives an integer when the division is exact, and a
+    /// float otherwise, so `(/ 6 3)` is `2` but `(/ 1 2)` is `0.5`.
+    fn divide(args: &[Value], span: Span) -> Result<Value, EvalError> {
+        let (mut result, rest) = match args {
+            [] => return Err(EvalError::WrongArgCount { expected: 1, variadic: true, got: 0, span }),
+            [only] => (Number::Integer(1), std::slice::from_ref(only)),
+            [first, rest @ ..] => (number(first, span)?, rest),
+        };
+        for arg in rest {
+            let divisor = number(arg, span)?;
+            result = match (result, divisor) {
+                (_, Number::Integer(0)) => return Err(EvalError::DivisionByZero { span }),
+                // `i64::MIN / -1` doesn't fit, and neither does its remainder
+                (Number::Integer(a), Number::Integer(b)) => match (a.checked_rem(b), a.checked_div(b)) {
+                    (Some(0), Some(quotient)) => Number::Integer(quotient),
+                    (Some(_), _) => Number::Float(a as f64 / b as f64),
+                    (None, _) => return Err(EvalError::IntegerOverflow { span }),
+                },
+                (a, b) => Number::Float(as_float(a) / as_float(b)),
+            };
+        }
+        Ok(Value::Number(result))
+    }
+
+    /// Checks that every neighboring pair of arguments is in the right order
+    fn compare(args: &[Value], span: Span, ok: fn(Ordering) -> bool) -> Result<Value, EvalError> {
+        let numbers = args.iter().map(|arg| number(arg, span)).collect::<Result<Vec<_>, _>>()?;
+        let result = numbers.windows(2).all(|pair| {
+            let ordering = match (pair[0], pair[1]) {
+                // Compare integers exactly, since large ones lose precision as floats
+                (Number::Integer(a), Number::Integer(b)) => Some(a.cmp(&b)),
+                (a, b) => as_float(a).partial_cmp(&as_float(b)),
+            };
+            // Nothing is in order with NaN, not even NaN itself
+            ordering.is_some_and(ok)
+        });
+        Ok(Value::Bool(result))
+    }
+
+    /// `eq?` compares numbers, booleans and symbols by value and everything else by identity
+    pub fn is_eq(a: &Value, b: &Value) -> bool {
+        match (a, b) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => Rc::ptr_eq(a, b),
+            (Value::Pair(a), Value::Pair(b)) => Rc::ptr_eq(a, b),
+            (Value::Procedure(a), Value::Procedure(b)) => Rc::ptr_eq(a, b),
+            (Value::Builtin(a), Value::Builtin(b)) => a.name == b.name,
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::Unspecified, Value::Unspecified) => true,
+            _ => false,
+        }
+    }
+
+    /// `equal?` also looks inside of strings and lists
+    ///
+    /// Lists are compared one pair at a time, so that a long list doesn't run out of stack.
+    pub fn is_equal(mut a: &Value, mut b: &Value) -> bool {
+        loop {
+            match (a, b) {
+                (Value::Str(a), Value::Str(b)) => return a == b,
+                (Value::Pair(pair_a), Value::Pair(pair_b)) => {
+                    if !is_equal(&pair_a.0, &pair_b.0) {
+                        return false;
+                    }
+                    a = &pair_a.1;
+                    b = &pair_b.1;
+                }
+                (a, b) => return is_eq(a, b),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(input: &str) -> Result<String, EvalError> {
+        Interpreter::new().eval_str(input).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn test_arithmetic_and_lists() {
+        assert_eq!(run("(+ 1 2 (* 3 4))").unwrap(), "15");
+        assert_eq!(run("(- 10 1 2)").unwrap(), "7");
+        assert_eq!(run("(- 5)").unwrap(), "-5");
+        assert_eq!(run("(/ 6 3)").unwrap(), "2");
+        assert_eq!(run("(/ 1 2)").unwrap(), "0.5");
+        assert_eq!(run("(+ 1 0.5)").unwrap(), "1.5");
+        assert_eq!(run("(< 1 2 3)").unwrap(), "#t");
+        assert_eq!(run("(cons 1 (list 2 \"three\" 'four))").unwrap(), "(1 2 \"three\" four)");
+        assert_eq!(run("(cons 1 2)").unwrap(), "(1 . 2)");
+        assert_eq!(run("'(a (b c) #f)").unwrap(), "(a (b c) #f)");
+        assert_eq!(run("(equal? '(1 (2)) (list 1 (list 2)))").unwrap(), "#t");
+        assert_eq!(run("(eq? 'abc (car '(abc)))").unwrap(), "#t");
+        assert_eq!(run("(eq? 'abc 'abd)").unwrap(), "#f");
+        assert_eq!(run("(append '(1 2) '() (list 3) 4)").unwrap(), "(1 2 3 . 4)");
+        assert_eq!(run("(append)").unwrap(), "()");
+        assert!(matches!(run("(append 1 '(2))").unwrap_err(), EvalError::TypeMismatch { expected: "list", .. }));
+    }
+
+    #[test]
+    fn test_define_lambda_and_closures() {
+        let program = "
+            (define (make-counter)
+              (define count 0)
+              (lambda ()
+                (set! count (+ count 1))
+                count))
+            (define counter (make-counter))
+            (counter)
+            (counter)
+            (counter)";
+        assert_eq!(run(program).unwrap(), "3");
+
+        let program = "
+            (define (fact n) (if (= n 0) 1 (* n (fact (- n 1)))))
+            (fact 20)";
+        assert_eq!(run(program).unwrap(), "2432902008176640000");
+
+        assert_eq!(run("((lambda args args) 1 2 3)").unwrap(), "(1 2 3)");
+        assert_eq!(run("((lambda (a . rest) rest) 1 2 3)").unwrap(), "(2 3)");
+        assert_eq!(run("(begin (define x 1) (set! x (+ x 1)) x)").unwrap(), "2");
+    }
+
+    #[test]
+    fn test_long_lists() {
+        // Dropping or comparing a long list doesn't recurse down the whole list
+        let numbers = || Value::list((0..1_000_000).map(|i| Value::Number(Number::Integer(i))).collect());
+        let (a, b) = (numbers(), numbers());
+        assert!(builtins::is_equal(&a, &b));
+        drop((a, b));
+
+        let program = "
+            (define (build n acc) (if (= n 0) acc (build (- n 1) (cons n acc))))
+            (define xs (build 100000 '()))
+            (define ys (build 100000 '()))
+            (define same (equal? xs ys))
+            (set! xs #f)
+            same";
+        assert_eq!(run(program).unwrap(), "#t");
+    }
+
+    #[test]
+    fn test_let_forms() {
+        assert_eq!(run("(define x 1) (let ((x 2) (y x)) (+ x y))").unwrap(), "3");
+        assert_eq!(run("(define x 1) (let* ((x 2) (y x)) (+ x y))").unwrap(), "4");
+        let program = "
+            (letrec ((even? (lambda (n) (if (= n 0) #t (odd? (- n 1)))))
+                     (odd? (lambda (n) (if (= n 0) #f (even? (- n 1))))))
+              (even? 100))";
+        assert_eq!(run(program).unwrap(), "#t");
+    }
+
+    #[test]
+    fn test_cond() {
+        assert_eq!(run("(cond ((< 2 1) 'a) ((< 1 2) 'b) (else 'c))").unwrap(), "b");
+        assert_eq!(run("(cond (#f 'a) (else 'c))").unwrap(), "c");
+        assert_eq!(run("(cond ((+ 1 2)) (else 'c))").unwrap(), "3");
+        assert_eq!(run("(cond (#f 'a))").unwrap(), "#<unspecified>");
+        assert_eq!(run("(define x 0) (cond (#t (set! x 1) (+ x 1)))").unwrap(), "2");
+        assert!(matches!(run("(cond (else 1) (#t 2))").unwrap_err(), EvalError::BadSyntax { form: "cond", .. }));
+        assert!(matches!(run("(cond (else))").unwrap_err(), EvalError::BadSyntax { form: "cond", .. }));
+        assert!(matches!(run("(cond ())").unwrap_err(), EvalError::BadSyntax { form: "cond", .. }));
+        assert!(matches!(run("(cond 1)").unwrap_err(), EvalError::BadSyntax { form: "cond", .. }));
+    }
+
+    #[test]
+    fn test_tail_calls_run_in_constant_space() {
+        // Each call goes through `cond`, `let`, `begin` and `if` before the next one
+        let program = "
+            (define (loop i acc)
+              (cond ((= i 0) acc)
+                    (else (let ((next (- i 1)))
+                            (begin (if #t (loop next (+ acc 1))))))))
+            (loop 1000000 0)";
+        assert_eq!(run(program).unwrap(), "1000000");
+
+        let program = "
+            (define (even? n) (if (= n 0) #t (odd? (- n 1))))
+            (define (odd? n) (let* ((m n)) (if (= m 0) #f (even? (- m 1)))))
+            (even? 100001)";
+        assert_eq!(run(program).unwrap(), "#f");
+
+        let program = "
+            (letrec ((count (lambda (n) (if (= n 0) 'done (count (- n 1))))))
+              (count 100000))";
+        assert_eq!(run(program).unwrap(), "done");
+    }
+
+    #[test]
+    fn test_errors_have_spans() {
+        let input = "(define (f x) (+ x y))\n(f 1)";
+        let error = run(input).unwrap_err();
+        assert_eq!(error, EvalError::UnboundVariable { name: "y".to_string(), span: error.span() });
+        assert_eq!(&input[error.span().start..error.span().end], "y");
+
+        let input = "(+ 1 \"two\")";
+        let error = run(input).unwrap_err();
+        assert!(matches!(error, EvalError::TypeMismatch { expected: "number", found: "string", .. }));
+        assert_eq!(error.span().start, 0);
+
+        let error = run("(define (f x) x) (f)").unwrap_err();
+        assert_eq!(error.to_string(), "expected 1 argument, got 0");
+        assert_eq!(error.span().start_pos.column, 18);
+
+        assert!(matches!(run("(1 2)").unwrap_err(), EvalError::NotAProcedure { .. }));
+        assert!(matches!(run("(/ 1 0)").unwrap_err(), EvalError::DivisionByZero { .. }));
+        assert!(matches!(run("(* 9223372036854775807 2)").unwrap_err(), EvalError::IntegerOverflow { .. }));
+        assert!(matches!(run("(- -9223372036854775807 2)").unwrap_err(), EvalError::IntegerOverflow { .. }));
+        assert!(matches!(run("(/ -9223372036854775808 -1)").unwrap_err(), EvalError::IntegerOverflow { .. }));
+        assert_eq!(run("(/ -9223372036854775808 1)").unwrap(), "-9223372036854775808");
+        assert!(matches!(run("(if)").unwrap_err(), EvalError::BadSyntax { form: "if", .. }));
+        assert!(matches!(run("(set! nope 1)").unwrap_err(), EvalError::UnboundVariable { .. }));
+        assert!(matches!(run("(f").unwrap_err(), EvalError::Parse(_)));
+    }
+}
@@ -0,0 +1,449 @@
+//! Reprints lisp code in one canonical style
+//!
+//! The formatter reads the code with a lossless `Lexer`, so it knows about
+//! every comment, and builds a small tree out of the tokens. Then it prints
+//! that tree back out, putting each list on one line if it fits and
+//! breaking it over several lines if it doesn't:
+//!
+//! ```text
+//! (define (fact n)
+//!   (if (= n 0)
+//!       1
+//!       (* n (fact (- n 1)))))
+//! ```
+//!
+//! Forms with a body, like `define` and `let`, indent their body by two
+//! spaces. Other lists line their arguments up under the first one.
+//!
+//! Comments stay where they were: a comment at the end of a line stays at
+//! the end of that line, and a comment on its own line stays on its own
+//! line. A single blank line between two expressions is kept too. Only the
+//! layout changes, so formatting code that is already formatted gives back
+//! exactly the same code.
+
+use crate::lexer::{CommentKind, Lexer, Span, TokenType};
+use crate::parser::ParseError;
+
+/// Forms that have a body, and how many arguments come before the body
+///
+/// These arguments stay on the first line, and the body is indented by
+/// two spaces rather than lined up with them.
+const BODY_FORMS: &[(&str, usize)] = &[
+    ("begin", 0),
+    ("case", 1),
+    ("cond", 0),
+    ("define", 1),
+    ("define-syntax", 1),
+    ("defmacro", 2),
+    ("do", 2),
+    ("lambda", 1),
+    ("let", 1),
+    ("let*", 1),
+    ("letrec", 1),
+    ("syntax-rules", 1),
+    ("unless", 1),
+    ("when", 1),
+];
+
+/// How deep to indent the body of a form like `define`
+const BODY_INDENT: usize = 2;
+
+/// A piece of code, as the formatter sees it
+#[derive(Debug)]
+struct Node<'a> {
+    kind: NodeKind<'a>,
+    /// Whether there was a blank line before this node in the input
+    blank_before: bool,
+}
+
+#[derive(Debug)]
+enum NodeKind<'a> {
+    /// A symbol, number, string or boolean, which is printed exactly as it was written
+    Atom { source: &'a str, symbol: bool },
+    List(Vec<Node<'a>>),
+    /// An expression after a quote-like prefix such as `'` or `,@`
+    Quoted { prefix: &'a str, quoted: Box<Node<'a>> },
+    /// Any kind of comment, which is also printed exactly as it was written
+    Comment {
+        source: &'a str,
+        /// Whether the comment was on the same line as the code before it
+        trailing: bool,
+        /// Line comments run to the end of the line, so nothing can follow them
+        line: bool,
+    },
+}
+
+impl Node<'_> {
+    fn is_comment(&self) -> bool {
+        matches!(self.kind, NodeKind::Comment { .. })
+    }
+
+    /// Prints the node on a single line, if it can be
+    ///
+    /// Comments and atoms with newlines in them (like multi-line strings)
+    /// can't be squeezed onto one line with anything else.
+    fn flat(&self) -> Option<String> {
+        match &self.kind {
+            NodeKind::Atom { source, .. } if !source.contains('\n') => Some(source.to_string()),
+            NodeKind::Atom { .. } | NodeKind::Comment { .. } => None,
+            NodeKind::Quoted { prefix, quoted } => quoted.flat().map(|quoted| format!("{}{}", prefix, quoted)),
+            NodeKind::List(items) => {
+                let items = items.iter().map(Node::flat).collect::<Option<Vec<_>>>()?;
+                Some(format!("({})", items.join(" ")))
+            }
+        }
+    }
+}
+
+/// An unfinished part of the tree, while we are still reading its tokens
+enum Frame<'a> {
+    List { open: Span, items: Vec<Node<'a>>, blank_before: bool },
+    Prefix { prefix: &'a str, span: Span, blank_before: bool },
+}
+
+/// Reads the tokens of the input into a list of top-level nodes
+fn read(source: &str) -> Result<Vec<Node<'_>>, ParseError> {
+    let mut top = Vec::new();
+    let mut frames: Vec<Frame> = Vec::new();
+    // The number of newlines since the last token that wasn't whitespace,
+    // or `None` at the very start of the input
+    let mut newlines = None;
+
+    for token in Lexer::new(source).lossless() {
+        let token = token?;
+        // A comment that was taken out of the list that this token closes
+        let mut moved = None;
+        // The text of the token, borrowed from the input rather than the token
+        let text = &source[token.span.start..token.span.end];
+        let blank_before = newlines.is_some_and(|newlines| newlines >= 2);
+        let mut node = match token.token {
+            TokenType::Whitespace => {
+                *newlines.get_or_insert(0) += text.matches('\n').count();
+                continue;
+            }
+            TokenType::LeftParen => {
+                frames.push(Frame::List { open: token.span, items: Vec::new(), blank_before });
+                newlines = Some(0);
+                continue;
+            }
+            TokenType::RightParen => match frames.pop() {
+                Some(Frame::List { mut items, blank_before, .. }) => {
+                    // A line comment right after the last item would push the
+                    // `)` onto a line of its own, so it goes after the `)` instead
+                    if let [.., code, Node { kind: NodeKind::Comment { trailing: true, line: true, .. }, .. }] = items.as_slice() {
+                        if !code.is_comment() {
+                            moved = items.pop();
+                        }
+                    }
+                    Node { kind: NodeKind::List(items), blank_before }
+                }
+                Some(Frame::Prefix { span, .. }) => return Err(ParseError::MissingQuotedExpr { prefix: span }),
+                None => return Err(ParseError::UnexpectedCloseParen { span: token.span }),
+            },
+            TokenType::Quote | TokenType::Quasiquote | TokenType::Unquote | TokenType::UnquoteSplicing => {
+                frames.push(Frame::Prefix { prefix: text, span: token.span, blank_before });
+                newlines = Some(0);
+                continue;
+            }
+            TokenType::Comment(comment) => {
+                let trailing = newlines == Some(0);
+                let line = comment.kind == CommentKind::Line;
+                // A comment between a prefix and its expression is moved in
+                // front of the prefix, into the list that the prefix is in
+                let source = if line { text.trim_end() } else { text };
+                let comment = Node { kind: NodeKind::Comment { source, trailing, line }, blank_before };
+                let items = frames.iter_mut().rev().find_map(|frame| match frame {
+                    Frame::List { items, .. } => Some(items),
+                    Frame::Prefix { .. } => None,
+                });
+                items.unwrap_or(&mut top).push(comment);
+                newlines = Some(0);
+                continue;
+            }
+            TokenType::Error(error) => return Err(error.into()),
+            TokenType::Identifier(_) => Node { kind: NodeKind::Atom { source: text, symbol: true }, blank_before },
+            TokenType::Number(_) | TokenType::Str(_) | TokenType::Boolean(_) => {
+                Node { kind: NodeKind::Atom { source: text, symbol: false }, blank_before }
+            }
+        };
+        newlines = Some(0);
+
+        // Wrap the finished node in any prefixes that were waiting for it
+        while let Some(Frame::Prefix { prefix, blank_before, .. }) = frames.last() {
+            let blank_before = *blank_before;
+            node = Node { kind: NodeKind::Quoted { prefix, quoted: Box::new(node) }, blank_before };
+            frames.pop();
+        }
+        let items = match frames.last_mut() {
+            Some(Frame::List { items, .. }) => items,
+            _ => &mut top,
+        };
+        items.push(node);
+        items.extend(moved);
+    }
+
+    match frames.pop() {
+        Some(Frame::List { open, .. }) => Err(ParseError::UnclosedParen { open }),
+        Some(Frame::Prefix { span, .. }) => Err(ParseError::MissingQuotedExpr { prefix: span }),
+        None => Ok(top),
+    }
+}
+
+/// Formats lisp code
+///
+/// ```
+/// use csh_seminar_feb_2021::format::Formatter;
+///
+/// let formatted = Formatter::new().with_width(20).format("(define (square x) (* x x))").unwrap();
+/// assert_eq!(formatted, "(define (square x)\n  (* x x))\n");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Formatter {
+    width: usize,
+}
+
+impl Formatter {
+    /// A formatter that keeps lines to at most 80 characters, where it can
+    pub fn new() -> Formatter {
+        Formatter { width: 80 }
+    }
+
+    /// Sets the line width that the formatter tries to stay within
+    ///
+    /// Long atoms and deep nesting can still make a line longer than this,
+    /// since there is no way to break them up.
+    pub fn with_width(mut self, width: usize) -> Formatter {
+        self.width = width;
+        self
+    }
+
+    /// Reprints the code in the canonical style
+    ///
+    /// Code that can't be parsed is left alone, and the error is returned instead.
+    pub fn format(&self, source: &str) -> Result<String, ParseError> {
+        let nodes = read(source)?;
+        let mut printer = Printer { output: String::new(), column: 0, width: self.width };
+        for (index, node) in nodes.iter().enumerate() {
+            match node.kind {
+                NodeKind::Comment { trailing: true, .. } if index > 0 => printer.write(" "),
+                _ if index > 0 => printer.newline(0, node.blank_before),
+                _ => (),
+            }
+            printer.write_node(node);
+        }
+        if !printer.output.is_empty() {
+            printer.output.push('\n');
+        }
+        Ok(printer.output)
+    }
+}
+
+impl Default for Formatter {
+    fn default() -> Formatter {
+        Formatter::new()
+    }
+}
+
+/// Formats lisp code with the default settings
+pub fn format(source: &str) -> Result<String, ParseError> {
+    Formatter::new().format(source)
+}
+
+struct Printer {
+    output: String,
+    /// The column that the next character will be written at, counting from 0
+    column: usize,
+    width: usize,
+}
+
+impl Printer {
+    fn write(&mut self, text: &str) {
+        self.output.push_str(text);
+        self.column = match text.rfind('\n') {
+            Some(newline) => text[newline + 1..].chars().count(),
+            None => self.column + text.chars().count(),
+        };
+    }
+
+    /// Starts a new line indented to `indent`, with a blank line before it if asked
+    fn newline(&mut self, indent: usize, blank: bool) {
+        self.output.push('\n');
+        if blank {
+            self.output.push('\n');
+        }
+        self.output.push_str(&" ".repeat(indent));
+        self.column = indent;
+    }
+
+    fn write_node(&mut self, node: &Node) {
+        match &node.kind {
+            NodeKind::Atom { source, .. } | NodeKind::Comment { source, .. } => self.write(source),
+            NodeKind::Quoted { prefix, quoted } => {
+                self.write(prefix);
+                self.write_node(quoted);
+            }
+            NodeKind::List(items) => match node.flat() {
+                Some(flat) if self.column + flat.chars().count() <= self.width => self.write(&flat),
+                _ => self.write_list(items),
+            },
+        }
+    }
+
+    /// Writes a list over several lines
+    ///
+    /// The first few items go on the same line as the `(`, and the rest go
+    /// on lines of their own, all at the same indent.
+    fn write_list(&mut self, items: &[Node]) {
+        let (first_line, indent) = self.layout(items);
+        self.write("(");
+
+        let mut code_items = 0;
+        let mut after_comment = false;
+        for (index, item) in items.iter().enumerate() {
+            let same_line = match item.kind {
+                NodeKind::Comment { trailing, .. } => trailing,
+                _ => !after_comment && code_items < first_line,
+            };
+            if !same_line {
+                self.newline(indent, item.blank_before && index > 0);
+            } else if index > 0 || item.is_comment() {
+                self.write(" ");
+            }
+            self.write_node(item);
+
+            if item.is_comment() {
+                after_comment = true;
+            } else {
+                code_items += 1;
+            }
+        }
+
+        // Nothing can follow a line comment on the same line, not even a `)`
+        if let Some(NodeKind::Comment { line: true, .. }) = items.last().map(|item| &item.kind) {
+            self.newline(indent, false);
+        }
+        self.write(")");
+    }
+
+    /// Decides how many items go on the first line of a list, and how far
+    /// to indent the others
+    fn layout(&self, items: &[Node]) -> (usize, usize) {
+        let open = self.column;
+        let head = items.iter().find(|item| !item.is_comment()).map(|item| &item.kind);
+        match head {
+            Some(NodeKind::Atom { source, symbol: true }) => {
+                let body_form = BODY_FORMS.iter().find(|(name, _)| name == source);
+                match body_form {
+                    Some(&(_, arguments)) => {
+                        // A named `let` has the name as an extra argument before the body
+                        let named_let = *source == "let" && matches!(
+                            items.iter().filter(|item| !item.is_comment()).nth(1).map(|item| &item.kind),
+                            Some(NodeKind::Atom { symbol: true, .. })
+                        );
+                        (1 + arguments + named_let as usize, open + BODY_INDENT)
+                    }
+                    // A procedure call, with its arguments lined up after the name
+                    None => (2, open + 1 + source.chars().count() + 1),
+                }
+            }
+            // A list of data, with every item lined up under the first
+            _ => (1, open + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Formats the input, checking that formatting the output again changes nothing
+    fn format_width(input: &str, width: usize) -> String {
+        let formatter = Formatter::new().with_width(width);
+        let formatted = formatter.format(input).unwrap();
+        assert_eq!(formatter.format(&formatted).unwrap(), formatted, "formatting is not idempotent");
+        formatted
+    }
+
+    #[test]
+    fn test_indentation() {
+        let input = "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1)))))  (fact   5)";
+        assert_eq!(format_width(input, 80), "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1)))))\n(fact 5)\n");
+        assert_eq!(format_width(input, 30), "\
+(define (fact n)
+  (if (= n 0)
+      1
+      (* n (fact (- n 1)))))
+(fact 5)
+");
+
+        let input = "(let loop ((i 0) (acc '())) (if (> i 10) acc (loop (+ i 1) (cons i acc))))\n'(10 20 30 40 50 60 70 80)";
+        assert_eq!(format_width(input, 24), "\
+(let loop ((i 0)
+           (acc '()))
+  (if (> i 10)
+      acc
+      (loop (+ i 1)
+            (cons i acc))))
+'(10
+  20
+  30
+  40
+  50
+  60
+  70
+  80)
+");
+    }
+
+    #[test]
+    fn test_comments() {
+        let input = "\
+; Squares a number
+(define (square x) ; the argument
+  #| multiply it
+     by itself |#
+  (* x
+     x))
+
+
+#;(old code)
+(square 3);done";
+        assert_eq!(format_width(input, 80), "\
+; Squares a number
+(define (square x) ; the argument
+  #| multiply it
+     by itself |#
+  (* x x))
+
+#;(old code)
+(square 3) ;done
+");
+
+        // A trailing comment at the end of a list goes after the `)`, so the
+        // `)` can stay with the last item
+        assert_eq!(format_width("(list 1 ; one\n      )", 80), "(list 1) ; one\n");
+        assert_eq!(format_width("(define (f x)\n  (g x ; why\n  ))", 80), "(define (f x) (g x)) ; why\n");
+        assert_eq!(format_width("(a (b ; c\n) d)", 80), "(a (b) ; c\n   d)\n");
+        assert_eq!(format_width("'(1 ; one\n)", 80), "'(1) ; one\n");
+        // but a comment on a line of its own still needs the `)` on the next line
+        assert_eq!(format_width("(list 1\n ; one\n)", 80), "(list 1\n      ; one\n      )\n");
+        assert_eq!(format_width("( ; first\n a b)", 80), "( ; first\n   a\n   b)\n");
+        assert_eq!(format_width("'; why\nx", 80), "; why\n'x\n");
+    }
+
+    #[test]
+    fn test_strings_and_blank_lines() {
+        let input = "(display \"two\nlines\")\n\n\n\n(newline)\n\n";
+        assert_eq!(format_width(input, 80), "(display \"two\nlines\")\n\n(newline)\n");
+        assert_eq!(format_width("", 80), "");
+        assert_eq!(format_width("  ; just a comment  \n", 80), "; just a comment\n");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(format("(a (b)"), Err(ParseError::UnclosedParen { .. })));
+        assert!(matches!(format("a)"), Err(ParseError::UnexpectedCloseParen { .. })));
+        assert!(matches!(format("(a ')"), Err(ParseError::MissingQuotedExpr { .. })));
+        assert!(matches!(format("(a 1x)"), Err(ParseError::Lex(_))));
+    }
+}
@@ -0,0 +1,439 @@
+//! A garbage collector for the values that lisp programs make
+//!
+//! Most values are freed by reference counting: every pair, procedure and
+//! environment is an `Rc`, and it goes away as soon as nothing points to
+//! it. That works until something points back to itself:
+//!
+//! ```text
+//! (define (make-loop)          make-loop's call:  loop = <procedure loop>
+//!   (define (loop) (loop))                           ^            |
+//!   loop)                                            |  env       |
+//!                                                    +------------+
+//! ```
+//!
+//! The procedure `loop` keeps the environment it was made in, and that
+//! environment keeps `loop`. Even once the program has forgotten about
+//! both of them, each one still has a reference, so neither is freed.
+//! `set!` can tie the same kind of knot, and so can closures in the `vm`.
+//!
+//! So every value that could be part of a cycle is also put on the heap,
+//! and every so often the heap is traced with mark and sweep:
+//!
+//! 1. Find the roots, which are the objects that something outside of the
+//!    heap points to. Those are the interpreter's global environment, and
+//!    the environments and values in use by the evaluator on the stack.
+//!    We find them by counting: if an object has more references than
+//!    there are other objects pointing to it, someone else has one.
+//! 2. Mark everything that can be reached from a root.
+//! 3. Sweep away everything that wasn't marked. It is only reachable from
+//!    itself, so we empty it out, which breaks the cycle and lets reference
+//!    counting free the rest.
+
+use crate::eval::{Lambda, Pair, Scope, Value};
+use crate::vm::{Closure, Upvalue};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+/// The fewest new objects between two collections
+const MIN_THRESHOLD: usize = 1000;
+
+thread_local! {
+    /// Every value is an `Rc`, which can't be shared between threads, so each thread gets its own heap
+    static HEAP: RefCell<Heap> = RefCell::new(Heap::new());
+}
+
+/// Numbers about what the garbage collector has been up to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// How many objects have been put on the heap so far
+    pub allocated: usize,
+    /// How many objects on the heap are still alive right now
+    pub live: usize,
+    /// How many times the collector has run
+    pub collections: usize,
+    /// How many objects the collector found that were only reachable from
+    /// themselves, which reference counting alone would have leaked
+    pub collected: usize,
+}
+
+/// Runs the collector now, returning how many objects it found to be garbage
+///
+/// There's no need to call this, since the heap collects on its own once
+/// enough new objects have been made, but it's handy for tests and for
+/// seeing what a program leaves behind.
+pub fn collect() -> usize {
+    // Take the objects out first so that freeing garbage can't find the heap borrowed
+    let objects = HEAP.with(|heap| std::mem::take(&mut heap.borrow_mut().objects));
+    let (survivors, garbage) = trace(objects);
+    let collected = garbage.len();
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.threshold = survivors.len().max(MIN_THRESHOLD);
+        heap.since_collection = 0;
+        heap.objects.extend(survivors);
+        heap.stats.collections += 1;
+        heap.stats.collected += collected;
+    });
+    drop(garbage);
+    collected
+}
+
+/// The collector's statistics for this thread
+pub fn stats() -> Stats {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        Stats { live: heap.objects.iter().filter(|object| object.is_alive()).count(), ..heap.stats }
+    })
+}
+
+/// Puts a new value on the heap, collecting garbage first if it's time to
+pub(crate) fn alloc<T: Managed>(value: T) -> Rc<T> {
+    let due = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.since_collection >= heap.threshold
+    });
+    if due {
+        collect();
+    }
+    let value = Rc::new(value);
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.push(T::object(&value));
+        heap.since_collection += 1;
+        heap.stats.allocated += 1;
+    });
+    value
+}
+
+/// A kind of value that can be part of a cycle, and so lives on the heap
+pub(crate) trait Managed: Sized {
+    fn object(this: &Rc<Self>) -> Object;
+}
+
+impl Managed for Pair {
+    fn object(this: &Rc<Self>) -> Object {
+        Object::Pair(Rc::downgrade(this))
+    }
+}
+
+impl Managed for Lambda {
+    fn object(this: &Rc<Self>) -> Object {
+        Object::Lambda(Rc::downgrade(this))
+    }
+}
+
+impl Managed for RefCell<Scope> {
+    fn object(this: &Rc<Self>) -> Object {
+        Object::Scope(Rc::downgrade(this))
+    }
+}
+
+impl Managed for Closure {
+    fn object(this: &Rc<Self>) -> Object {
+        Object::Closure(Rc::downgrade(this))
+    }
+}
+
+impl Managed for RefCell<Upvalue> {
+    fn object(this: &Rc<Self>) -> Object {
+        Object::Upvalue(Rc::downgrade(this))
+    }
+}
+
+/// Every object that has been put on the heap, along with how often to collect
+struct Heap {
+    objects: Vec<Object>,
+    since_collection: usize,
+    /// How many new objects to wait for before collecting again, which grows
+    /// with the heap so that collecting takes about the same time per object
+    threshold: usize,
+    stats: Stats,
+}
+
+impl Heap {
+    fn new() -> Heap {
+        Heap { objects: Vec::new(), since_collection: 0, threshold: MIN_THRESHOLD, stats: Stats::default() }
+    }
+}
+
+/// An object on the heap
+///
+/// The heap only keeps weak references, so that reference counting can
+/// still free objects right away when they aren't part of a cycle.
+pub(crate) enum Object {
+    Pair(Weak<Pair>),
+    Lambda(Weak<Lambda>),
+    Scope(Weak<RefCell<Scope>>),
+    Closure(Weak<Closure>),
+    Upvalue(Weak<RefCell<Upvalue>>),
+}
+
+impl Object {
+    fn is_alive(&self) -> bool {
+        match self {
+            Object::Pair(weak) => weak.strong_count() > 0,
+            Object::Lambda(weak) => weak.strong_count() > 0,
+            Object::Scope(weak) => weak.strong_count() > 0,
+            Object::Closure(weak) => weak.strong_count() > 0,
+            Object::Upvalue(weak) => weak.strong_count() > 0,
+        }
+    }
+
+    /// A strong reference to the object, if it's still alive
+    fn upgrade(&self) -> Option<Live> {
+        Some(match self {
+            Object::Pair(weak) => Live::Pair(weak.upgrade()?),
+            Object::Lambda(weak) => Live::Lambda(weak.upgrade()?),
+            Object::Scope(weak) => Live::Scope(weak.upgrade()?),
+            Object::Closure(weak) => Live::Closure(weak.upgrade()?),
+            Object::Upvalue(weak) => Live::Upvalue(weak.upgrade()?),
+        })
+    }
+}
+
+/// An object on the heap that is held on to while it is traced
+enum Live {
+    Pair(Rc<Pair>),
+    Lambda(Rc<Lambda>),
+    Scope(Rc<RefCell<Scope>>),
+    Closure(Rc<Closure>),
+    Upvalue(Rc<RefCell<Upvalue>>),
+}
+
+/// Where an object is in memory, which tells objects apart
+type Address = usize;
+
+fn address<T>(rc: &Rc<T>) -> Address {
+    Rc::as_ptr(rc) as *const () as Address
+}
+
+impl Live {
+    fn address(&self) -> Address {
+        match self {
+            Live::Pair(rc) => address(rc),
+            Live::Lambda(rc) => address(rc),
+            Live::Scope(rc) => address(rc),
+            Live::Closure(rc) => address(rc),
+            Live::Upvalue(rc) => address(rc),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Live::Pair(rc) => Rc::strong_count(rc),
+            Live::Lambda(rc) => Rc::strong_count(rc),
+            Live::Scope(rc) => Rc::strong_count(rc),
+            Live::Closure(rc) => Rc::strong_count(rc),
+            Live::Upvalue(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    fn downgrade(&self) -> Object {
+        match self {
+            Live::Pair(rc) => Object::Pair(Rc::downgrade(rc)),
+            Live::Lambda(rc) => Object::Lambda(Rc::downgrade(rc)),
+            Live::Scope(rc) => Object::Scope(Rc::downgrade(rc)),
+            Live::Closure(rc) => Object::Closure(Rc::downgrade(rc)),
+            Live::Upvalue(rc) => Object::Upvalue(Rc::downgrade(rc)),
+        }
+    }
+
+    /// The addresses of the objects this one points to, one for each reference
+    ///
+    /// Returns `None` if the object is borrowed right now and can't be looked
+    /// inside, in which case it's safest to treat it as a root.
+    fn children(&self) -> Option<Vec<Address>> {
+        let mut children = Vec::new();
+        match self {
+            Live::Pair(pair) => {
+                value_child(&pair.0, &mut children);
+                value_child(&pair.1, &mut children);
+            }
+            Live::Lambda(lambda) => children.push(address(&lambda.env.0)),
+            Live::Scope(scope) => {
+                let scope = scope.try_borrow().ok()?;
+                for value in scope.vars.values() {
+                    value_child(value, &mut children);
+                }
+                if let Some(parent) = &scope.parent {
+                    children.push(address(&parent.0));
+                }
+            }
+            Live::Closure(closure) => children.extend(closure.upvalues.iter().map(address)),
+            Live::Upvalue(upvalue) => {
+                if let Upvalue::Closed(value) = &*upvalue.try_borrow().ok()? {
+                    value_child(value, &mut children);
+                }
+            }
+        }
+        Some(children)
+    }
+
+    /// Takes everything out of an object that was found to be garbage,
+    /// so that the cycle it is part of is broken
+    ///
+    /// Every cycle has to go through an environment or an upvalue, since
+    /// those are the only objects that can be changed after they are made.
+    fn clear(&self) -> Vec<Value> {
+        match self {
+            Live::Scope(scope) => match scope.try_borrow_mut() {
+                Ok(mut scope) => scope.vars.drain().map(|(_, value)| value).collect(),
+                Err(_) => Vec::new(),
+            },
+            Live::Upvalue(upvalue) => match upvalue.try_borrow_mut().as_deref_mut() {
+                Ok(Upvalue::Closed(value)) => vec![std::mem::replace(value, Value::Unspecified)],
+                _ => Vec::new(),
+            },
+            Live::Pair(_) | Live::Lambda(_) | Live::Closure(_) => Vec::new(),
+        }
+    }
+}
+
+fn value_child(value: &Value, children: &mut Vec<Address>) {
+    match value {
+        Value::Pair(pair) => children.push(address(pair)),
+        Value::Procedure(lambda) => children.push(address(lambda)),
+        Value::Closure(closure) => children.push(address(closure)),
+        _ => (),
+    }
+}
+
+/// Marks and sweeps the objects, returning the ones that survived and
+/// whatever was taken out of the ones that didn't
+///
+/// The garbage is handed back rather than dropped here, so that the caller
+/// can free it once it's done with the heap.
+fn trace(objects: Vec<Object>) -> (Vec<Object>, Vec<Live>) {
+    // Objects that reference counting already freed are simply forgotten
+    let live: Vec<Live> = objects.iter().filter_map(Object::upgrade).collect();
+    drop(objects);
+    let index: HashMap<Address, usize> = live.iter().enumerate().map(|(i, object)| (object.address(), i)).collect();
+    let children: Vec<Option<Vec<usize>>> = live.iter()
+        .map(|object| {
+            let children = object.children()?;
+            Some(children.iter().filter_map(|child| index.get(child).copied()).collect())
+        })
+        .collect();
+
+    // Count the references that come from outside of the heap. `live` has one of them itself.
+    let mut outside: Vec<usize> = live.iter().map(|object| object.strong_count() - 1).collect();
+    for children in children.iter().flatten() {
+        for &child in children {
+            outside[child] -= 1;
+        }
+    }
+
+    let mut marked = vec![false; live.len()];
+    let mut pending: Vec<usize> = (0..live.len())
+        .filter(|&i| outside[i] > 0 || children[i].is_none())
+        .collect();
+    while let Some(i) = pending.pop() {
+        if !std::mem::replace(&mut marked[i], true) {
+            pending.extend(children[i].iter().flatten());
+        }
+    }
+
+    let mut survivors = Vec::new();
+    let mut garbage = Vec::new();
+    let mut contents = Vec::new();
+    for (object, marked) in live.into_iter().zip(marked) {
+        if marked {
+            survivors.push(object.downgrade());
+        } else {
+            contents.extend(object.clear());
+            garbage.push(object);
+        }
+    }
+    // Free the contents of the garbage first, while the garbage itself is still alive
+    drop(contents);
+    (survivors, garbage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::Interpreter;
+    use crate::symbol::Symbol;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_collects_cycles() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("(define (make-loop) (define (loop) (loop)) loop)").unwrap();
+        let lambda = match interpreter.eval_str("(make-loop)").unwrap() {
+            Value::Procedure(lambda) => lambda,
+            other => panic!("expected a procedure, got {}", other),
+        };
+        collect();
+        let procedure = Rc::downgrade(&lambda);
+        assert_eq!(procedure.strong_count(), 2, "a cycle is not garbage while something outside of it is using it");
+
+        // Reference counting alone can't free the procedure, since its environment still has it
+        drop(lambda);
+        assert_eq!(procedure.strong_count(), 1);
+        assert!(collect() >= 2);
+        assert!(procedure.upgrade().is_none());
+
+        // A cycle made with `set!`, with a list in the middle of it
+        let cell = match interpreter.eval_str("(let ((self #f)) (set! self (list (lambda () self))) self)").unwrap() {
+            Value::Pair(pair) => Rc::downgrade(&pair),
+            other => panic!("expected a pair, got {}", other),
+        };
+        assert_eq!(cell.strong_count(), 1);
+        collect();
+        assert!(cell.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_keeps_reachable_values() {
+        let mut interpreter = Interpreter::new();
+        let program = "
+            (define (make-counter)
+              (define count 0)
+              (define (next) (set! count (+ count 1)) count)
+              next)
+            (define counter (make-counter))
+            (define saved (list counter (make-counter)))
+            (counter)";
+        interpreter.eval_str(program).unwrap();
+        let held = interpreter.eval_str("(make-counter)").unwrap();
+        collect();
+        assert_eq!(interpreter.eval_str("(counter)").unwrap().to_string(), "2");
+        assert_eq!(interpreter.eval_str("((car (cdr saved)))").unwrap().to_string(), "1");
+        interpreter.globals().define(Symbol::intern("held"), held);
+        assert_eq!(interpreter.eval_str("(held)").unwrap().to_string(), "1");
+    }
+
+    #[test]
+    fn test_collects_while_running() {
+        let before = stats();
+        let program = "
+            (define (churn n)
+              (if (= n 0)
+                  'done
+                  (begin
+                    (let ((self #f)) (set! self (lambda () self)))
+                    (churn (- n 1)))))
+            (churn 20000)";
+        assert_eq!(Interpreter::new().eval_str(program).unwrap().to_string(), "done");
+        let after = stats();
+        assert!(after.collections > before.collections);
+        assert!(after.collected - before.collected > 10000, "{:?}", after);
+        assert!(after.allocated - before.allocated > 20000);
+        assert!(after.live < 5 * MIN_THRESHOLD, "{:?}", after);
+    }
+
+    #[test]
+    fn test_collects_vm_closures() {
+        let mut vm = Vm::new();
+        let closure = match vm.eval_str("(define (make-loop) (define (loop) (loop)) loop) (make-loop)").unwrap() {
+            Value::Closure(closure) => Rc::downgrade(&closure),
+            other => panic!("expected a closure, got {}", other),
+        };
+        assert_eq!(closure.strong_count(), 1);
+        collect();
+        assert!(closure.upgrade().is_none());
+        assert_eq!(vm.eval_str("(procedure? (make-loop))").unwrap().to_string(), "#t");
+    }
+}
@@ -0,0 +1,227 @@
+//! Syntax highlighting for terminals and web pages
+//!
+//! The highlighter lexes code losslessly and gives every token a `Style`,
+//! so nothing from the input is lost, not even comments or mistakes. The
+//! styled code can then be written out with ANSI color codes for a
+//! terminal, or as HTML where every token is a `<span>` with a CSS class.
+//!
+//! Parens get a different color at each depth of nesting (rainbow parens),
+//! which makes it much easier to see where each list ends.
+
+use crate::eval::SPECIAL_FORMS;
+use crate::lexer::{Lexer, TokenType};
+use std::fmt::Write;
+
+/// How many different colors the parens cycle through
+const PAREN_COLORS: usize = 6;
+
+/// How a piece of code should look
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    /// A paren, with how deeply nested it is
+    Paren(usize),
+    /// The name of a special form like `define` or `if`
+    SpecialForm,
+    Identifier,
+    Number,
+    Str,
+    Boolean,
+    Comment,
+    /// A quote-like prefix, such as `'` or `,@`
+    Quote,
+    /// Something that could not be lexed
+    Error,
+    /// Whitespace, which has no style at all
+    Plain,
+}
+
+impl Style {
+    /// The CSS class for this style, which is empty for plain text
+    pub fn class(self) -> String {
+        match self {
+            Style::Paren(depth) => format!("paren-{}", depth % PAREN_COLORS),
+            Style::SpecialForm => "special-form".to_string(),
+            Style::Identifier => "identifier".to_string(),
+            Style::Number => "number".to_string(),
+            Style::Str => "string".to_string(),
+            Style::Boolean => "boolean".to_string(),
+            Style::Comment => "comment".to_string(),
+            Style::Quote => "quote".to_string(),
+            Style::Error => "error".to_string(),
+            Style::Plain => String::new(),
+        }
+    }
+
+    /// The ANSI escape code that starts this style in a terminal
+    pub fn ansi(self) -> &'static str {
+        match self {
+            Style::Paren(depth) => ["\x1b[33m", "\x1b[35m", "\x1b[36m", "\x1b[32m", "\x1b[34m", "\x1b[31m"][depth % PAREN_COLORS],
+            Style::SpecialForm => "\x1b[1;35m",
+            Style::Identifier => "",
+            Style::Number | Style::Boolean => "\x1b[36m",
+            Style::Str => "\x1b[32m",
+            Style::Comment => "\x1b[2;3m",
+            Style::Quote => "\x1b[1;33m",
+            Style::Error => "\x1b[4;31m",
+            Style::Plain => "",
+        }
+    }
+}
+
+/// Splits the code into pieces and gives each one a style
+///
+/// Gluing the pieces back together always gives back the input exactly.
+pub fn styles(source: &str) -> Vec<(Style, &str)> {
+    let mut depth: usize = 0;
+    Lexer::new(source)
+        .lossless()
+        .recovering()
+        .map(|token| {
+            let style = match &token.token {
+                TokenType::LeftParen => {
+                    depth += 1;
+                    Style::Paren(depth - 1)
+                }
+                TokenType::RightParen => {
+                    // A stray `)` stays at the outermost depth
+                    depth = depth.saturating_sub(1);
+                    Style::Paren(depth)
+                }
+                TokenType::Identifier(ident) if SPECIAL_FORMS.contains(&ident.0.as_ref()) => Style::SpecialForm,
+                TokenType::Identifier(_) => Style::Identifier,
+                TokenType::Number(_) => Style::Number,
+                TokenType::Str(_) => Style::Str,
+                TokenType::Boolean(_) => Style::Boolean,
+                TokenType::Comment(_) => Style::Comment,
+                TokenType::Quote | TokenType::Quasiquote | TokenType::Unquote | TokenType::UnquoteSplicing => Style::Quote,
+                TokenType::Error(_) => Style::Error,
+                TokenType::Whitespace => Style::Plain,
+            };
+            (style, &source[token.span.start..token.span.end])
+        })
+        .collect()
+}
+
+/// Highlights code with ANSI escape codes, for printing to a terminal
+pub fn to_ansi(source: &str) -> String {
+    let mut output = String::new();
+    for (style, text) in styles(source) {
+        match style.ansi() {
+            "" => output.push_str(text),
+            code => {
+                let _ = write!(output, "{}{}\x1b[0m", code, text);
+            }
+        }
+    }
+    output
+}
+
+/// Highlights code as a `<pre>` element, for putting into a web page
+///
+/// Every token is a `<span>` with a CSS class from `Style::class`, and
+/// `STYLESHEET` has some colors for those classes.
+pub fn to_html_fragment(source: &str) -> String {
+    let mut output = String::from("<pre class=\"lisp\"><code>");
+    for (style, text) in styles(source) {
+        match style {
+            Style::Plain => escape_html(&mut output, text),
+            style => {
+                let _ = write!(output, "<span class=\"{}\">", style.class());
+                escape_html(&mut output, text);
+                output.push_str("</span>");
+            }
+        }
+    }
+    output.push_str("</code></pre>");
+    output
+}
+
+/// Highlights code as a whole HTML page, with the styles built in
+pub fn to_html(source: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<style>\n{}</style>\n</head>\n<body>\n{}\n</body>\n</html>\n",
+        STYLESHEET,
+        to_html_fragment(source),
+    )
+}
+
+/// CSS for the classes used by `to_html_fragment`
+pub const STYLESHEET: &str = "\
+.lisp .paren-0 { color: #b58900; }
+.lisp .paren-1 { color: #d33682; }
+.lisp .paren-2 { color: #2aa198; }
+.lisp .paren-3 { color: #859900; }
+.lisp .paren-4 { color: #268bd2; }
+.lisp .paren-5 { color: #dc322f; }
+.lisp .special-form { color: #6c71c4; font-weight: bold; }
+.lisp .number, .lisp .boolean { color: #2aa198; }
+.lisp .string { color: #859900; }
+.lisp .comment { color: #93a1a1; font-style: italic; }
+.lisp .quote { color: #b58900; font-weight: bold; }
+.lisp .error { color: #dc322f; text-decoration: underline wavy; }
+";
+
+fn escape_html(output: &mut String, text: &str) {
+    for ch in text.chars() {
+        match ch {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            ch => output.push(ch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_styles() {
+        let source = "(define (f x) '(x \"<s>\" 1.5 #t)) ; done\n) 1x";
+        let pieces = styles(source);
+        assert_eq!(pieces.iter().map(|(_, text)| *text).collect::<String>(), source);
+
+        let styled: Vec<_> = pieces.into_iter().filter(|(style, _)| *style != Style::Plain).collect();
+        assert_eq!(styled, vec![
+            (Style::Paren(0), "("),
+            (Style::SpecialForm, "define"),
+            (Style::Paren(1), "("),
+            (Style::Identifier, "f"),
+            (Style::Identifier, "x"),
+            (Style::Paren(1), ")"),
+            (Style::Quote, "'"),
+            (Style::Paren(1), "("),
+            (Style::Identifier, "x"),
+            (Style::Str, "\"<s>\""),
+            (Style::Number, "1.5"),
+            (Style::Boolean, "#t"),
+            (Style::Paren(1), ")"),
+            (Style::Paren(0), ")"),
+            (Style::Comment, "; done"),
+            (Style::Paren(0), ")"),
+            (Style::Error, "1x"),
+        ]);
+    }
+
+    #[test]
+    fn test_ansi() {
+        assert_eq!(to_ansi("(if a \"b\")"), "\x1b[33m(\x1b[0m\x1b[1;35mif\x1b[0m a \x1b[32m\"b\"\x1b[0m\x1b[33m)\x1b[0m");
+        assert_eq!(Style::Paren(7).ansi(), Style::Paren(1).ansi());
+    }
+
+    #[test]
+    fn test_html() {
+        let html = to_html_fragment("(< a \"&\")");
+        assert_eq!(html, "<pre class=\"lisp\"><code>\
+<span class=\"paren-0\">(</span><span class=\"identifier\">&lt;</span> \
+<span class=\"identifier\">a</span> <span class=\"string\">&quot;&amp;&quot;</span>\
+<span class=\"paren-0\">)</span></code></pre>");
+
+        let page = to_html("x");
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains(".lisp .paren-5"));
+        assert!(page.contains("<span class=\"identifier\">x</span>"));
+    }
+}
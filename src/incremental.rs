@@ -0,0 +1,265 @@
+//! Re-lexing only the part of the input that an edit changed
+//!
+//! An editor changes a file one keystroke at a time, and lexing the whole
+//! file again after every keystroke gets slow for big files. Luckily, an
+//! edit usually only changes the tokens right around it.
+//!
+//! The lexer doesn't need to know anything about what came before a token
+//! to lex it. So after an edit, we start lexing again just before the
+//! edit, and stop as soon as a new token ends exactly where an old token
+//! after the edit started. From there on the tokens are the same as they
+//! were, so we reuse them and only move their spans:
+//!
+//! ```text
+//! old:    (define  foo  (bar  baz))
+//! edit:                   ^^^ -> qux
+//! new:    (define  foo  (qux  baz))
+//!         reused------|  lexed|reused-|
+//! ```
+//!
+//! This needs every part of the input to be in some token, so the tokens
+//! must come from a `Lexer` that is both `lossless` and `recovering`. They
+//! may have been made owned with `Token::into_owned`, so an editor can keep
+//! the tokens of a file around between edits.
+
+use crate::lexer::{Comment, CommentKind, Ident, Lexer, Position, Span, StrLiteral, Token, TokenType};
+use std::borrow::Cow;
+use std::ops::Range;
+
+/// A change to some text: the bytes in `range` are replaced with `text`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+impl Edit {
+    pub fn new(range: Range<usize>, text: impl Into<String>) -> Edit {
+        Edit { range, text: text.into() }
+    }
+
+    /// Makes the edit to `input`, giving the new text
+    pub fn apply(&self, input: &str) -> String {
+        let mut output = input.to_string();
+        output.replace_range(self.range.clone(), &self.text);
+        output
+    }
+}
+
+/// Updates the tokens of some text after an edit
+///
+/// `tokens` are every token of the text before the edit, as returned by
+/// `Lexer::new(old).lossless().recovering()`, and `input` is the text after
+/// the edit. The result is exactly what lexing all of `input` would give.
+///
+/// ```
+/// use csh_seminar_feb_2021::incremental::{relex, Edit};
+/// use csh_seminar_feb_2021::lexer::Lexer;
+///
+/// let old = "(define x 1)";
+/// let tokens: Vec<_> = Lexer::new(old).lossless().recovering().collect();
+///
+/// let edit = Edit::new(8..9, "count");
+/// let new = edit.apply(old);
+/// let tokens = relex(&tokens, &edit, &new);
+/// assert_eq!(tokens[3].source, "count");
+/// assert_eq!(tokens[6].span.start, 15);
+/// ```
+pub fn relex<'b>(tokens: &[Token<'_>], edit: &Edit, input: &'b str) -> Vec<Token<'b>> {
+    relex_counting(tokens, edit, input).0
+}
+
+/// Does the work of `relex`, also returning how many tokens had to be lexed again
+fn relex_counting<'b>(tokens: &[Token<'_>], edit: &Edit, input: &'b str) -> (Vec<Token<'b>>, usize) {
+    // Tokens that end before the edit stay as they are. A token that ends
+    // right where the edit starts might not, since the lexer looked at the
+    // character after it to find its end (think of typing `2` after `1`).
+    let unchanged = tokens.iter().take_while(|token| token.span.end < edit.range.start).count();
+    let mut result: Vec<Token<'b>> = tokens[..unchanged]
+        .iter()
+        .map(|token| move_token(token, input, &Shift::NONE))
+        .collect();
+
+    let (restart, restart_pos) = result.last().map_or((0, Position::START), |token| (token.span.end, token.span.end_pos));
+    let edit_end = edit.range.start + edit.text.len();
+    let removed = edit.range.end - edit.range.start;
+
+    let mut old = tokens[unchanged..].iter().peekable();
+    let mut lexed = 0;
+    let mut shift = None;
+    for token in Lexer::new(&input[restart..]).lossless().recovering() {
+        let token = Token {
+            source: token.source,
+            token: match token.token {
+                TokenType::Error(error) => TokenType::Error(error.rebase(restart, restart_pos)),
+                token => token,
+            },
+            span: token.span.rebase(restart, restart_pos),
+        };
+        let (end, end_pos) = (token.span.end, token.span.end_pos);
+        result.push(token);
+        lexed += 1;
+
+        // Once we are past the edit, check whether an old token starts here
+        if end >= edit_end {
+            let old_end = end + removed - edit.text.len();
+            while old.peek().is_some_and(|token| token.span.start < old_end) {
+                old.next();
+            }
+            if let Some(token) = old.peek().filter(|token| token.span.start == old_end) {
+                shift = Some(Shift { from: old_end, from_pos: token.span.start_pos, to: end, to_pos: end_pos });
+                break;
+            }
+        }
+    }
+
+    if let Some(shift) = shift {
+        result.extend(old.map(|token| move_token(token, input, &shift)));
+    }
+    (result, lexed)
+}
+
+/// How the places after an edit move when the text before them changes
+struct Shift {
+    from: usize,
+    from_pos: Position,
+    to: usize,
+    to_pos: Position,
+}
+
+impl Shift {
+    /// Nothing moves at all
+    const NONE: Shift = Shift { from: 0, from_pos: Position::START, to: 0, to_pos: Position::START };
+
+    /// Moves a span that starts at or after `from`
+    ///
+    /// Places on the same line as `from` move along that line with it, and
+    /// places on later lines only move up or down.
+    fn span(&self, span: Span) -> Span {
+        let position = |position: Position| match position.line == self.from_pos.line {
+            true => Position { line: self.to_pos.line, column: position.column - self.from_pos.column + self.to_pos.column },
+            false => Position { line: position.line - self.from_pos.line + self.to_pos.line, ..position },
+        };
+        Span {
+            start: span.start - self.from + self.to,
+            end: span.end - self.from + self.to,
+            start_pos: position(span.start_pos),
+            end_pos: position(span.end_pos),
+        }
+    }
+}
+
+/// Copies a token that didn't change into the new input, without lexing it again
+///
+/// The old token may borrow from the old input or own its text (after
+/// `Token::into_owned`), so the text inside it is found again by where it
+/// has to be in the token, rather than by where it was in memory.
+fn move_token<'b>(token: &Token<'_>, input: &'b str, shift: &Shift) -> Token<'b> {
+    let span = shift.span(token.span);
+    let source = &input[span.start..span.end];
+    let moved = match &token.token {
+        TokenType::LeftParen => TokenType::LeftParen,
+        TokenType::RightParen => TokenType::RightParen,
+        TokenType::Identifier(_) => TokenType::Identifier(Ident(source.into())),
+        TokenType::Number(number) => TokenType::Number(*number),
+        TokenType::Str(string) => {
+            // The raw text is everything between the quotes, and the value
+            // only borrows it when there were no escapes to replace
+            let raw = &source[1..source.len() - 1];
+            let value = match string.value == raw {
+                true => Cow::Borrowed(raw),
+                false => Cow::Owned(string.value.to_string()),
+            };
+            TokenType::Str(StrLiteral { raw: raw.into(), value })
+        }
+        TokenType::Boolean(boolean) => TokenType::Boolean(*boolean),
+        TokenType::Quote => TokenType::Quote,
+        TokenType::Quasiquote => TokenType::Quasiquote,
+        TokenType::Unquote => TokenType::Unquote,
+        TokenType::UnquoteSplicing => TokenType::UnquoteSplicing,
+        TokenType::Comment(comment) => {
+            let text = match comment.kind {
+                CommentKind::Line => &source[1..],
+                CommentKind::Block => &source[2..source.len() - 2],
+                // The commented-out expression runs to the end of the comment
+                CommentKind::Datum => &source[source.len() - comment.text.len()..],
+            };
+            TokenType::Comment(Comment { kind: comment.kind, text: text.into() })
+        }
+        TokenType::Whitespace => TokenType::Whitespace,
+        TokenType::Error(error) => TokenType::Error(error.clone().map_span(|span| shift.span(span))),
+    };
+    Token { source: source.into(), token: moved, span }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes the edit, checking that re-lexing gives the same tokens as
+    /// lexing everything again, and returns how many tokens were re-lexed
+    fn check_edit(old: &str, edit: Edit) -> usize {
+        let new = edit.apply(old);
+        let tokens: Vec<_> = Lexer::new(old).lossless().recovering().collect();
+        let (relexed, lexed) = relex_counting(&tokens, &edit, &new);
+        let expected: Vec<_> = Lexer::new(&new).lossless().recovering().collect();
+        assert_eq!(relexed, expected, "{:?} after {:?}", new, edit);
+        lexed
+    }
+
+    #[test]
+    fn test_edits() {
+        let input = "(define (f x)\n  ; squares\n  (* x x)) \"日本\" 12 #| c |#";
+        // Typing into, after and before tokens
+        check_edit(input, Edit::new(9..10, "g"));
+        check_edit(input, Edit::new(10..10, "oo"));
+        check_edit(input, Edit::new(0..0, "'"));
+        check_edit(input, Edit::new(48..48, "3"));
+        // Changes that change the meaning of everything after them
+        check_edit(input, Edit::new(2..2, "\""));
+        check_edit(input, Edit::new(14..14, "#|"));
+        check_edit(input, Edit::new(16..17, ""));
+        // Adding and removing lines
+        check_edit(input, Edit::new(13..14, " "));
+        check_edit(input, Edit::new(8..8, "\n\n"));
+        check_edit(input, Edit::new(29..29, "日\n本 "));
+        // Edits at the very start and end, and replacing everything
+        check_edit(input, Edit::new(0..1, ""));
+        check_edit(input, Edit::new(input.len()..input.len(), " (more"));
+        check_edit(input, Edit::new(0..input.len(), "(all new)"));
+        check_edit("", Edit::new(0..0, "x"));
+    }
+
+    #[test]
+    fn test_every_position() {
+        let input = "(a \"b\\n\" ,@c) ; d\n#;(e 1.5)\n日 #|f|#";
+        let boundaries: Vec<_> = input.char_indices().map(|(index, _)| index).chain(Some(input.len())).collect();
+        for window in boundaries.windows(2) {
+            for text in &["", "x", "1", "\"", "\n", "(", "#|", ";", " "] {
+                check_edit(input, Edit::new(window[0]..window[0], *text));
+                check_edit(input, Edit::new(window[0]..window[1], *text));
+            }
+        }
+    }
+
+    #[test]
+    fn test_relex_owned_tokens() {
+        let edit = Edit::new(1..2, "aa");
+        let (tokens, new) = {
+            let old = String::from("(a \"b\\n\" \"c\" ;d\n #;(e) #|f|#)");
+            let tokens: Vec<_> = Lexer::new(&old).lossless().recovering().map(Token::into_owned).collect();
+            (tokens, edit.apply(&old))
+        };
+        let expected: Vec<_> = Lexer::new(&new).lossless().recovering().collect();
+        assert_eq!(relex(&tokens, &edit, &new), expected);
+    }
+
+    #[test]
+    fn test_only_nearby_tokens_are_lexed() {
+        let input = "(define (f x) (+ x 1))\n".repeat(1000);
+        let middle = input.len() / 2 + 15;
+        assert_eq!(&input[middle..middle + 1], "+");
+        assert!(check_edit(&input, Edit::new(middle..middle + 1, "max")) <= 3);
+        assert!(check_edit(&input, Edit::new(middle..middle, "\n\n")) <= 3);
+    }
+}
@@ -0,0 +1,1406 @@
+//! A Lexer for a little lisp-like language
+//!
+//! We will create a `Lexer` struct which takes in a string and then
+//! iterates over the tokens that it recognizes. The Lexer will capture
+//! information such as the type of each token, the location (span) where it came
+//! from in the input string, and any add-on data that each token may require.
+
+use crate::symbol::Symbol;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use unicode_xid::UnicodeXID;
+
+/// A human-friendly location in the input, as a line and column number
+///
+/// Both numbers start counting at 1, like they do in text editors. Columns
+/// count characters rather than bytes, so `"日本"` is two columns wide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// The position of the very first character of the input
+    pub(crate) const START: Position = Position { line: 1, column: 1 };
+
+    /// The position we arrive at after reading `text`, starting from this one
+    ///
+    /// A `'\n'` moves us to the start of the next line. In a `"\r\n"` line
+    /// ending the `'\r'` counts as a column, but that column disappears as
+    /// soon as we move to the next line, so CRLF and LF files behave the same.
+    pub(crate) fn advance(self, text: &str) -> Position {
+        text.chars().fold(self, |position, ch| match ch {
+            '\n' => Position { line: position.line + 1, column: 1 },
+            _ => Position { column: position.column + 1, ..position },
+        })
+    }
+
+    /// Moves a position that was counted from the start of some piece of
+    /// text to where it really is, given that the text starts at `base`
+    ///
+    /// Only the first line of the text gets its columns moved, since every
+    /// later line starts at column 1 no matter where the text began.
+    pub(crate) fn rebase(self, base: Position) -> Position {
+        match self.line {
+            1 => Position { line: base.line, column: base.column + self.column - 1 },
+            line => Position { line: base.line + line - 1, column: self.column },
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The region of the input that a token (or error) came from
+///
+/// `start` and `end` are byte offsets, so `&input[span.start..span.end]`
+/// is always the exact text of the token. `start_pos` and `end_pos` are the
+/// same two places as lines and columns. Like `end`, `end_pos` points just
+/// past the last character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub start_pos: Position,
+    pub end_pos: Position,
+}
+
+impl Span {
+    /// The span that starts where this one starts and ends where `end` ends
+    ///
+    /// This is handy for building the span of something made of several
+    /// tokens, like a whole list from its `(` to its `)`.
+    pub fn to(self, end: Span) -> Span {
+        Span { end: end.end, end_pos: end.end_pos, ..self }
+    }
+
+    /// Attaches a file name to this span, for displaying as `file:line:column`
+    pub fn in_file(self, file: &str) -> Location<'_> {
+        Location { file, span: self }
+    }
+
+    /// Moves a span that was measured from the start of some piece of text to
+    /// where it really is, given that the text starts at `offset` and `base`
+    pub(crate) fn rebase(self, offset: usize, base: Position) -> Span {
+        Span {
+            start: self.start + offset,
+            end: self.end + offset,
+            start_pos: self.start_pos.rebase(base),
+            end_pos: self.end_pos.rebase(base),
+        }
+    }
+}
+
+/// Displays the line and column where the span starts, such as `3:14`
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.start_pos)
+    }
+}
+
+/// A span in a named file, which displays as `file:line:column`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location<'f> {
+    file: &'f str,
+    span: Span,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.span)
+    }
+}
+
+/// Everything that can go wrong while lexing
+///
+/// Each error remembers the span of the input that caused it, so that we
+/// can point the user at exactly the right place. The `Display` message
+/// leaves the location out, so that callers can print it however they like.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    /// A character that cannot start or be part of any token
+    UnexpectedChar { ch: char, span: Span },
+    /// A string literal that is missing its closing quote
+    UnterminatedString { span: Span },
+    /// A backslash in a string literal that is not followed by a known escape
+    InvalidEscape { reason: String, span: Span },
+    /// Something that starts like a number but is not shaped like one
+    InvalidNumber { reason: String, span: Span },
+    /// A well-formed number that is too large to be represented
+    NumberOverflow { span: Span },
+    /// A `#|` block comment that is missing its closing `|#`
+    UnterminatedComment { span: Span },
+    /// A `#;` datum comment with nothing after it to comment out
+    MissingDatum { span: Span },
+}
+
+impl LexError {
+    /// The part of the input that this error is about
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::UnexpectedChar { span, .. }
+            | LexError::UnterminatedString { span }
+            | LexError::InvalidEscape { span, .. }
+            | LexError::InvalidNumber { span, .. }
+            | LexError::NumberOverflow { span }
+            | LexError::UnterminatedComment { span }
+            | LexError::MissingDatum { span } => *span,
+        }
+    }
+
+    /// Moves the span of this error, like `Span::rebase`
+    pub(crate) fn rebase(self, offset: usize, base: Position) -> LexError {
+        self.map_span(|span| span.rebase(offset, base))
+    }
+
+    /// Changes the span of this error, keeping everything else the same
+    pub(crate) fn map_span(mut self, f: impl FnOnce(Span) -> Span) -> LexError {
+        match &mut self {
+            LexError::UnexpectedChar { span, .. }
+            | LexError::UnterminatedString { span }
+            | LexError::InvalidEscape { span, .. }
+            | LexError::InvalidNumber { span, .. }
+            | LexError::NumberOverflow { span }
+            | LexError::UnterminatedComment { span }
+            | LexError::MissingDatum { span } => *span = f(*span),
+        }
+        self
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::UnexpectedChar { ch, .. } => write!(f, "unexpected character `{}`", ch),
+            LexError::UnterminatedString { .. } => write!(f, "unterminated string literal"),
+            LexError::InvalidEscape { reason, .. } => write!(f, "invalid escape sequence: {}", reason),
+            LexError::InvalidNumber { reason, .. } => write!(f, "invalid number literal: {}", reason),
+            LexError::NumberOverflow { .. } => write!(f, "number literal does not fit in 64 bits"),
+            LexError::UnterminatedComment { .. } => write!(f, "unterminated block comment"),
+            LexError::MissingDatum { .. } => write!(f, "expected something after `#;` to comment out"),
+        }
+    }
+}
+
+impl std::error::Error for LexError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ident<'a>(pub Cow<'a, str>);
+
+impl Ident<'_> {
+    /// The interned symbol for this identifier, which is quicker to compare than the text
+    pub fn symbol(&self) -> Symbol {
+        Symbol::intern(&self.0)
+    }
+
+    /// Copies the name out of the input, so that it can outlive it
+    pub fn into_owned(self) -> Ident<'static> {
+        Ident(Cow::Owned(self.0.into_owned()))
+    }
+}
+
+/// The value of a numeric literal such as `42`, `-0x1f` or `6.02e23`
+///
+/// Literals without a fractional part or exponent are read as integers,
+/// everything else is read as a floating-point number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Integer(i64),
+    Float(f64),
+}
+
+/// Displays the number so that lexing it again gives back the same number
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Integer(integer) => write!(f, "{}", integer),
+            // The debug format always includes a `.` or exponent, so `1.0`
+            // does not turn into the integer `1`
+            Number::Float(float) => write!(f, "{:?}", float),
+        }
+    }
+}
+
+/// A double-quoted string literal such as `"hello\n"`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrLiteral<'a> {
+    /// The text between the quotes, exactly as it was written
+    pub raw: Cow<'a, str>,
+    /// The text with every escape sequence replaced by the character it stands for
+    ///
+    /// Most strings have no escapes at all, so this only allocates a new
+    /// `String` when it has to. Otherwise it just borrows `raw`.
+    pub value: Cow<'a, str>,
+}
+
+impl StrLiteral<'_> {
+    /// Copies the text out of the input, so that it can outlive it
+    pub fn into_owned(self) -> StrLiteral<'static> {
+        StrLiteral { raw: Cow::Owned(self.raw.into_owned()), value: Cow::Owned(self.value.into_owned()) }
+    }
+}
+
+/// The three ways of writing a comment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommentKind {
+    /// `; runs until the end of the line`
+    Line,
+    /// `#| may span many lines, and #| nest |# inside each other |#`
+    Block,
+    /// `#; (comments out the whole expression that follows)`
+    Datum,
+}
+
+/// A comment, which the lexer only returns when asked to with `Lexer::with_comments`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment<'a> {
+    pub kind: CommentKind,
+    /// The commented text, without the `;`, `#|` and `|#` or `#;` markers
+    pub text: Cow<'a, str>,
+}
+
+impl Comment<'_> {
+    /// Copies the text out of the input, so that it can outlive it
+    pub fn into_owned(self) -> Comment<'static> {
+        Comment { kind: self.kind, text: Cow::Owned(self.text.into_owned()) }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenType<'a> {
+    LeftParen,
+    RightParen,
+    Identifier(Ident<'a>),
+    Number(Number),
+    Str(StrLiteral<'a>),
+    /// `#t` or `#f`, which may also be spelled out as `#true` and `#false`
+    Boolean(bool),
+    /// `'`, the short way of writing `(quote ...)`
+    Quote,
+    /// `` ` ``, the short way of writing `(quasiquote ...)`
+    Quasiquote,
+    /// `,`, the short way of writing `(unquote ...)`
+    Unquote,
+    /// `,@`, the short way of writing `(unquote-splicing ...)`
+    UnquoteSplicing,
+    Comment(Comment<'a>),
+    /// A run of whitespace, which the lexer only returns in `Lexer::lossless` mode
+    Whitespace,
+    /// A part of the input that could not be lexed, which is only returned
+    /// by `Lexer::recovering`
+    ///
+    /// The token covers the whole bad region (such as an entire string with
+    /// a bad escape in it), while the error points at the exact problem.
+    Error(LexError),
+}
+
+impl TokenType<'_> {
+    /// Whitespace and comments are "trivia": they matter to people reading
+    /// the code, but not to the meaning of the program
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, TokenType::Comment(_) | TokenType::Whitespace)
+    }
+
+    /// For the quote-like prefixes, the name of the form they stand for
+    ///
+    /// Lisp readers expand these prefixes so that `'x` means exactly the
+    /// same thing as `(quote x)`, and `,@x` the same as `(unquote-splicing x)`.
+    pub fn quote_name(&self) -> Option<&'static str> {
+        match self {
+            TokenType::Quote => Some("quote"),
+            TokenType::Quasiquote => Some("quasiquote"),
+            TokenType::Unquote => Some("unquote"),
+            TokenType::UnquoteSplicing => Some("unquote-splicing"),
+            _ => None,
+        }
+    }
+
+    /// Copies any text out of the input, so that the token type can outlive it
+    pub fn into_owned(self) -> TokenType<'static> {
+        match self {
+            TokenType::LeftParen => TokenType::LeftParen,
+            TokenType::RightParen => TokenType::RightParen,
+            TokenType::Identifier(ident) => TokenType::Identifier(ident.into_owned()),
+            TokenType::Number(number) => TokenType::Number(number),
+            TokenType::Str(string) => TokenType::Str(string.into_owned()),
+            TokenType::Boolean(boolean) => TokenType::Boolean(boolean),
+            TokenType::Quote => TokenType::Quote,
+            TokenType::Quasiquote => TokenType::Quasiquote,
+            TokenType::Unquote => TokenType::Unquote,
+            TokenType::UnquoteSplicing => TokenType::UnquoteSplicing,
+            TokenType::Comment(comment) => TokenType::Comment(comment.into_owned()),
+            TokenType::Whitespace => TokenType::Whitespace,
+            TokenType::Error(error) => TokenType::Error(error),
+        }
+    }
+}
+
+/// A token, along with the part of the input it came from
+///
+/// Tokens from the `Lexer` borrow their text from the input, which makes
+/// lexing fast since nothing gets copied. Every bit of text is a `Cow`
+/// though, so `into_owned` can copy it all out of the input when a token
+/// needs to live longer than the input does:
+///
+/// ```
+/// use csh_seminar_feb_2021::lexer::{Lexer, OwnedToken};
+///
+/// let tokens: Vec<OwnedToken> = {
+///     let input = String::from("(hello \"world\")");
+///     Lexer::new(&input).map(|token| token.unwrap().into_owned()).collect()
+/// };
+/// assert_eq!(tokens[1].source, "hello");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token<'a> {
+    /// The slice of the input string that this token was parsed from
+    pub source: Cow<'a, str>,
+    /// The type of token that this is
+    pub token: TokenType<'a>,
+    /// The starting and ending indices of this token
+    pub span: Span,
+}
+
+/// A token that owns all of its text, so it can be kept around after the
+/// input is gone, or sent to another thread
+pub type OwnedToken = Token<'static>;
+
+impl Token<'_> {
+    /// Copies the token's text out of the input, so that it can outlive it
+    pub fn into_owned(self) -> OwnedToken {
+        Token { source: Cow::Owned(self.source.into_owned()), token: self.token.into_owned(), span: self.span }
+    }
+}
+
+/// A Lexer that will take an input string and return Tokens of that input
+///
+/// Tokens are a way to simplify an input string. Instead of remembering
+/// every single character of the input, we just remember whether it was
+/// a parenthesis, or an identifier (e.g. a function name) or some kind of
+/// literal (like a number or string).
+///
+/// This Lexer returns tokens that reference the original string that they
+/// were read from. The lifetime `'a` represents the scope where the input
+/// string lives. Therefore, this lexer and any tokens it produces may not
+/// outlive the original string, unless they are copied out of it with
+/// `Token::into_owned`.
+///
+/// Think about the memory of the program in terms of the stack frames of the functions:
+///
+/// ```text
+/// +-----------main------------------+
+/// | ...                             |
+/// +-----get_some_string()-----------+
+/// |                                 |
+/// | let input = " ( + 1 2 ) "       |  <-- Think of lifetime 'a as this stack frame
+/// |                                 |
+/// +---lex_that_string(s: &'a str)---+
+/// ```
+pub struct Lexer<'a> {
+    /// The input string that we are lexing tokens from
+    input: &'a str,
+    /// The index into the string that has been lexed so far
+    ///
+    /// For example, here is where the offset will point at the beginning
+    /// of lexing and after lexing each token in the input string:
+    ///
+    /// ```text
+    /// input:     "the quick brown fox jumped over the lazy dog"
+    /// offset: 0   ^   ^     ^     ^   ^      ^    ^   ^    ^
+    /// offset: 4   ----|     |     |   |      |    |   |    |
+    /// offset: 10  ----------|     |   |      |    |   |    |
+    /// offset: 16  ----------------|   |      |    |   |    |
+    /// offset: 20  --------------------|      |    |   |    |
+    /// offset: 27  ---------------------------|    |   |    |
+    /// offset: 32  --------------------------------|   |    |
+    /// offset: 36  ------------------------------------|    |
+    /// offset: 41  -----------------------------------------|
+    /// ```
+    offset: usize,
+    /// The line and column that `offset` points at
+    position: Position,
+    /// Whether to return comments as tokens rather than skipping over them
+    keep_comments: bool,
+    /// Whether to return whitespace as tokens rather than skipping over it
+    keep_whitespace: bool,
+    /// Tokens that have been peeked at but not returned yet, each with the
+    /// checkpoint from just before it
+    lookahead: VecDeque<(Checkpoint, Token<'a>)>,
+}
+
+/// A saved place in the input, which a `Lexer` can rewind back to
+///
+/// This is only a couple of numbers, so checkpoints are cheap to make and
+/// to keep around. A checkpoint only makes sense for the lexer that made it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    offset: usize,
+    position: Position,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Lexer<'a> {
+        Lexer {
+            input,
+            offset: 0,
+            position: Position::START,
+            keep_comments: false,
+            keep_whitespace: false,
+            lookahead: VecDeque::new(),
+        }
+    }
+
+    /// Makes this lexer return comments as `TokenType::Comment` tokens
+    ///
+    /// Normally comments are skipped just like whitespace, which is what a
+    /// parser wants. Tools like documentation generators may want to see them.
+    pub fn with_comments(mut self) -> Lexer<'a> {
+        self.keep_comments = true;
+        self
+    }
+
+    /// Makes this lexer return every comment and every run of whitespace as a token
+    ///
+    /// In this mode no part of the input is thrown away, so gluing the
+    /// `source` of every token back together gives back the exact input:
+    ///
+    /// ```text
+    /// input:   "(one  ; hi\n two)"
+    /// tokens:  "(" "one" "  " "; hi" "\n " "two" ")"
+    /// ```
+    ///
+    /// Lexing errors are returned as `Err` and have no `source` of their
+    /// own, so to round-trip input that may contain mistakes, use this
+    /// together with `Lexer::recovering`.
+    ///
+    /// Tools that rewrite code, like formatters, need this so that they can
+    /// change one part of a file without destroying the formatting of the rest.
+    pub fn lossless(mut self) -> Lexer<'a> {
+        self.keep_comments = true;
+        self.keep_whitespace = true;
+        self
+    }
+
+    /// Turns this lexer into one that returns errors as `TokenType::Error` tokens
+    ///
+    /// After an error the lexer skips to the end of the bad region, which is
+    /// usually the next delimiter, and carries on from there. This way every
+    /// mistake in a file can be reported in one go, and every part of the
+    /// input still ends up in some token:
+    ///
+    /// ```text
+    /// input:   "(a 1x \"b\\q\" c)"
+    /// tokens:  "(" "a" Error("1x") Error("\"b\\q\"") "c" ")"
+    /// ```
+    pub fn recovering(self) -> Recovering<'a> {
+        Recovering { lexer: self }
+    }
+
+    /// Builds the span between two byte offsets that are at or after `offset`
+    fn span(&self, start: usize, end: usize) -> Span {
+        let start_pos = self.position.advance(&self.input[self.offset..start]);
+        let end_pos = start_pos.advance(&self.input[start..end]);
+        Span { start, end, start_pos, end_pos }
+    }
+
+    /// Moves `offset` forward to `end`, keeping track of lines and columns on the way
+    fn advance_to(&mut self, end: usize) {
+        self.position = self.position.advance(&self.input[self.offset..end]);
+        self.offset = end;
+    }
+
+    /// Builds an `Error` token covering `start..end` and moves past it
+    fn error(&mut self, start: usize, end: usize, error: LexError) -> Token<'a> {
+        let source = self.input[start..end].into();
+        let span = self.span(start, end);
+        self.advance_to(end);
+        Token { source, token: TokenType::Error(error), span }
+    }
+
+    /// Builds an `Error` token that runs from the start of `from` up to `offset`,
+    /// for errors that we only find after moving past the start of the bad region
+    fn error_since(&self, from: Span, error: LexError) -> Token<'a> {
+        let span = Span { end: self.offset, end_pos: self.position, ..from };
+        Token { source: self.input[span.start..span.end].into(), token: TokenType::Error(error), span }
+    }
+
+    /// Looks at the next token without moving past it
+    pub fn peek(&mut self) -> Option<Result<&Token<'a>, &LexError>> {
+        self.peek_nth(0)
+    }
+
+    /// Looks at the token `n` places ahead without moving past anything, so
+    /// that `peek_nth(0)` is the token that `next` would return
+    pub fn peek_nth(&mut self, n: usize) -> Option<Result<&Token<'a>, &LexError>> {
+        while self.lookahead.len() <= n {
+            let checkpoint = Checkpoint { offset: self.offset, position: self.position };
+            let token = self.lex_next()?;
+            self.lookahead.push_back((checkpoint, token));
+        }
+        self.lookahead.get(n).map(|(_, token)| match &token.token {
+            TokenType::Error(error) => Err(error),
+            _ => Ok(token),
+        })
+    }
+
+    /// Saves the current place in the input, so that we can `rewind` to it later
+    ///
+    /// Peeking does not move the checkpoint: it is always the place just
+    /// before the token that `next` will return.
+    pub fn checkpoint(&self) -> Checkpoint {
+        match self.lookahead.front() {
+            Some((checkpoint, _)) => *checkpoint,
+            None => Checkpoint { offset: self.offset, position: self.position },
+        }
+    }
+
+    /// Goes back to a checkpoint, so that the tokens after it are lexed again
+    ///
+    /// This lets a parser try one way of reading the input, and go back and
+    /// try another way if the first one doesn't work out.
+    pub fn rewind(&mut self, checkpoint: Checkpoint) {
+        self.lookahead.clear();
+        self.offset = checkpoint.offset;
+        self.position = checkpoint.position;
+    }
+
+    /// Returns the next token, which may be one that we have already peeked at
+    fn next_token(&mut self) -> Option<Token<'a>> {
+        match self.lookahead.pop_front() {
+            Some((_, token)) => Some(token),
+            None => self.lex_next(),
+        }
+    }
+
+    /// Lexes the next token, skipping comments unless we were asked to keep them
+    fn lex_next(&mut self) -> Option<Token<'a>> {
+        loop {
+            let token = self.lex_token()?;
+            // Whitespace tokens are only made in lossless mode, so
+            // comments are the only thing we may need to skip here
+            match token.token {
+                TokenType::Comment(_) if !self.keep_comments => continue,
+                _ => return Some(token),
+            }
+        }
+    }
+}
+
+impl<'a> Lexer<'a> {
+    /// Skips whitespace and lexes the next token, including comments
+    fn lex_token(&mut self) -> Option<Token<'a>> {
+        // Ignore whitespace characters, including tabs and newlines
+        let rest = &self.input[self.offset..];
+        let whitespace = rest.len() - rest.trim_start().len();
+        if self.keep_whitespace && whitespace > 0 {
+            let end = self.offset + whitespace;
+            let source = self.input[self.offset..end].into();
+            let span = self.span(self.offset, end);
+            self.advance_to(end);
+            return Some(Token { source, token: TokenType::Whitespace, span });
+        }
+        self.advance_to(self.offset + whitespace);
+
+        // The offset always sits on a character boundary, so it is safe to
+        // look at the next whole character (which may be several bytes long)
+        let rest = &self.input[self.offset..];
+        let ch = rest.chars().next()?;
+
+        // Easy cases: check if the first character is '(' or ')'
+        match ch {
+            '(' | ')' => {
+                let source = self.input[self.offset..self.offset+1].into();
+                let token = if ch == '(' { TokenType::LeftParen } else { TokenType::RightParen };
+                let span = self.span(self.offset, self.offset + 1);
+                self.advance_to(self.offset + 1);
+                return Some(Token {
+                    source,
+                    token,
+                    span,
+                })
+            }
+            '\'' | '`' | ',' => {
+                let (token, len) = match ch {
+                    '\'' => (TokenType::Quote, 1),
+                    '`' => (TokenType::Quasiquote, 1),
+                    _ if rest.starts_with(",@") => (TokenType::UnquoteSplicing, 2),
+                    _ => (TokenType::Unquote, 1),
+                };
+                let source = self.input[self.offset..self.offset + len].into();
+                let span = self.span(self.offset, self.offset + len);
+                self.advance_to(self.offset + len);
+                return Some(Token { source, token, span });
+            }
+            '"' => return Some(self.lex_string()),
+            ';' => return Some(self.lex_line_comment()),
+            '#' if rest.starts_with("#|") => return Some(self.lex_block_comment()),
+            '#' if rest.starts_with("#;") => return Some(self.lex_datum_comment()),
+            // Anything else needs to be collected as a number or identifier
+            _other => (),
+        }
+
+        Some(self.lex_atom())
+    }
+
+    /// Lexes a `;` comment, which ends just before the next newline
+    fn lex_line_comment(&mut self) -> Token<'a> {
+        let start = self.offset;
+        let rest = &self.input[start..];
+        let end = start + rest.find('\n').unwrap_or(rest.len());
+        self.comment(CommentKind::Line, start, start + 1, end, end)
+    }
+
+    /// Lexes a `#| ... |#` comment, which may contain other block comments
+    fn lex_block_comment(&mut self) -> Token<'a> {
+        let start = self.offset;
+        let mut depth = 0;
+        let mut index = start;
+        while index < self.input.len() {
+            let rest = &self.input[index..];
+            if rest.starts_with("#|") {
+                depth += 1;
+                index += 2;
+            } else if rest.starts_with("|#") {
+                depth -= 1;
+                index += 2;
+                if depth == 0 {
+                    return self.comment(CommentKind::Block, start, start + 2, index - 2, index);
+                }
+            } else {
+                index += rest.chars().next().map_or(1, char::len_utf8);
+            }
+        }
+
+        let end = self.input.len();
+        let span = self.span(start, end);
+        self.error(start, end, LexError::UnterminatedComment { span })
+    }
+
+    /// Lexes a `#;` comment, which comments out the next whole expression
+    ///
+    /// We don't have a parser in the lexer, but we can still find the end of
+    /// the expression by counting parentheses. If the expression does not
+    /// start with `(` then it is just a single token.
+    ///
+    /// If something goes wrong, the error token covers the `#;` and
+    /// everything after it that we have looked at.
+    fn lex_datum_comment(&mut self) -> Token<'a> {
+        let start = self.offset;
+        let marker = self.span(start, start + 2);
+        self.advance_to(start + 2);
+
+        let mut depth = 0;
+        let mut datum_start = None;
+        loop {
+            let (offset, position) = (self.offset, self.position);
+            let token = match self.lex_token() {
+                Some(Token { token: TokenType::Error(error), .. }) => return self.error_since(marker, error),
+                Some(token) => token,
+                None => return self.error_since(marker, LexError::MissingDatum { span: marker }),
+            };
+            match token.token {
+                // Comments inside the commented-out expression are part of it
+                TokenType::Comment(_) | TokenType::Whitespace => continue,
+                // A `)` here means there is nothing left in the enclosing
+                // list to comment out. Leave it for whoever reads next.
+                TokenType::RightParen if depth == 0 => {
+                    self.offset = offset;
+                    self.position = position;
+                    return self.error_since(marker, LexError::MissingDatum { span: marker });
+                }
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen => depth -= 1,
+                _ => (),
+            }
+            datum_start.get_or_insert(token.span.start);
+            // A quote prefix is only the beginning of the expression
+            if depth == 0 && token.token.quote_name().is_none() {
+                let end = token.span.end;
+                let datum_start = datum_start.unwrap_or(start);
+                let source = self.input[start..end].into();
+                let text = self.input[datum_start..end].into();
+                let span = Span { end, end_pos: token.span.end_pos, ..marker };
+                let token = TokenType::Comment(Comment { kind: CommentKind::Datum, text });
+                return Token { source, token, span };
+            }
+        }
+    }
+
+    /// Builds a comment token and moves past it
+    ///
+    /// `start..end` is the whole comment and `text_start..text_end` is the
+    /// text inside its markers.
+    fn comment(&mut self, kind: CommentKind, start: usize, text_start: usize, text_end: usize, end: usize) -> Token<'a> {
+        let source = self.input[start..end].into();
+        let text = self.input[text_start..text_end].into();
+        let span = self.span(start, end);
+        self.advance_to(end);
+        Token { source, token: TokenType::Comment(Comment { kind, text }), span }
+    }
+
+    /// Lexes a string literal, starting from its opening quote
+    ///
+    /// If the string contains a bad escape sequence we still skip over the
+    /// rest of it, so that lexing can carry on after the closing quote.
+    fn lex_string(&mut self) -> Token<'a> {
+        let start = self.offset;
+        let body = &self.input[start + 1..];
+        let mut unescaped: Option<String> = None;
+        let mut error = None;
+
+        let mut chars = body.char_indices();
+        let close = loop {
+            let (index, ch) = match chars.next() {
+                Some(next) => next,
+                None => break None,
+            };
+            match ch {
+                '"' => break Some(index),
+                '\\' => {
+                    let escaped = read_escape(&mut chars);
+                    let value = unescaped.get_or_insert_with(|| body[..index].to_string());
+                    match escaped {
+                        Ok(ch) => value.push(ch),
+                        Err(reason) if error.is_none() => {
+                            let span = self.span(start + 1 + index, start + 1 + chars.offset());
+                            error = Some(LexError::InvalidEscape { reason, span });
+                        }
+                        Err(_) => (),
+                    }
+                }
+                ch => if let Some(value) = &mut unescaped {
+                    value.push(ch);
+                },
+            }
+        };
+
+        let close = match close {
+            Some(close) => start + 1 + close,
+            None => {
+                let end = self.input.len();
+                let span = self.span(start, end);
+                return self.error(start, end, LexError::UnterminatedString { span });
+            }
+        };
+        if let Some(error) = error {
+            return self.error(start, close + 1, error);
+        }
+
+        // Move past the closing quote
+        let span = self.span(start, close + 1);
+        self.advance_to(close + 1);
+
+        let raw = &self.input[start + 1..close];
+        let value = match unescaped {
+            Some(value) => Cow::Owned(value),
+            None => Cow::Borrowed(raw),
+        };
+        Token {
+            source: self.input[start..close + 1].into(),
+            token: TokenType::Str(StrLiteral { raw: raw.into(), value }),
+            span,
+        }
+    }
+
+    /// Lexes a number, boolean or identifier
+    ///
+    /// A bad atom becomes a single `Error` token that runs up to the next
+    /// delimiter, so one typo only ever causes one error.
+    fn lex_atom(&mut self) -> Token<'a> {
+        // Numbers and identifiers both run until the next delimiter, so
+        // find the end of this "atom" first and then decide what it is
+        let start = self.offset;
+        let slice = &self.input[start..];
+        let end = start + slice.find(is_delimiter).unwrap_or(slice.len());
+        let source = &self.input[start..end];
+        let span = self.span(start, end);
+
+        let mut chars = source.char_indices();
+        let bad = chars.next()
+            .filter(|&(_, ch)| !is_identifier_start(ch))
+            .or_else(|| chars.find(|&(_, ch)| !is_identifier_continue(ch)));
+        let unexpected = bad.map(|(index, ch)| LexError::UnexpectedChar {
+            ch,
+            span: self.span(start + index, start + index + ch.len_utf8()),
+        });
+
+        // Whatever happens next, we never want to look at this atom again
+        self.advance_to(end);
+
+        let boolean = match source {
+            "#t" | "#true" => Some(true),
+            "#f" | "#false" => Some(false),
+            _ => None,
+        };
+        let token = if looks_like_number(source) {
+            match parse_number(source, span) {
+                Ok(number) => TokenType::Number(number),
+                Err(error) => TokenType::Error(error),
+            }
+        } else if let Some(boolean) = boolean {
+            TokenType::Boolean(boolean)
+        } else if let Some(error) = unexpected {
+            TokenType::Error(error)
+        } else {
+            TokenType::Identifier(Ident(source.into()))
+        };
+        Token { source: source.into(), token, span }
+    }
+}
+
+/// Reads the rest of an escape sequence after its backslash
+///
+/// The supported escapes are `\n`, `\t`, `\r`, `\"`, `\\` and `\u{...}`,
+/// where the braces hold the hexadecimal code of any unicode character.
+fn read_escape(chars: &mut std::str::CharIndices) -> Result<char, String> {
+    match chars.next().map(|(_, ch)| ch) {
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some('r') => Ok('\r'),
+        Some('"') => Ok('"'),
+        Some('\\') => Ok('\\'),
+        Some('u') => {
+            // Only consume characters that belong to the escape, so that a
+            // closing quote right after a broken escape still ends the string
+            let mut take = |accept: &dyn Fn(char) -> bool| {
+                let mut lookahead = chars.clone();
+                match lookahead.next() {
+                    Some((_, ch)) if accept(ch) => {
+                        *chars = lookahead;
+                        Some(ch)
+                    }
+                    _ => None,
+                }
+            };
+            if take(&|ch| ch == '{').is_none() {
+                return Err("expected `{` after `\\u`".to_string());
+            }
+            let mut code = String::new();
+            while code.len() < 6 {
+                match take(&|ch| ch.is_ascii_hexdigit()) {
+                    Some(digit) => code.push(digit),
+                    None => break,
+                }
+            }
+            if code.is_empty() || take(&|ch| ch == '}').is_none() {
+                return Err("expected 1 to 6 hex digits followed by `}`".to_string());
+            }
+            u32::from_str_radix(&code, 16)
+                .ok()
+                .and_then(std::char::from_u32)
+                .ok_or_else(|| "not a valid unicode character".to_string())
+        }
+        Some(other) => Err(format!("unknown escape `\\{}`", other)),
+        None => Err("expected an escape character after `\\`".to_string()),
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token<'a>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_token()? {
+            Token { token: TokenType::Error(error), .. } => Some(Err(error)),
+            token => Some(Ok(token)),
+        }
+    }
+}
+
+/// A lexer that returns errors as `TokenType::Error` tokens, made by `Lexer::recovering`
+pub struct Recovering<'a> {
+    lexer: Lexer<'a>,
+}
+
+impl<'a> Iterator for Recovering<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.lexer.next_token()
+    }
+}
+
+/// Characters that end a number or identifier
+pub(crate) fn is_delimiter(ch: char) -> bool {
+    ch.is_whitespace() || "()\";'`,".contains(ch)
+}
+
+/// Punctuation that lisps like to use in names, such as `+`, `set!` or `string->list`
+const IDENTIFIER_SYMBOLS: &str = "!$%&*/:<=>?^_~+-.@";
+
+/// Identifiers start with a letter in any language (a unicode `XID_Start`
+/// character), or with one of the `IDENTIFIER_SYMBOLS`
+fn is_identifier_start(ch: char) -> bool {
+    ch.is_xid_start() || IDENTIFIER_SYMBOLS.contains(ch)
+}
+
+/// After the first character, identifiers may also contain digits and
+/// combining marks (unicode `XID_Continue` characters)
+fn is_identifier_continue(ch: char) -> bool {
+    ch.is_xid_continue() || IDENTIFIER_SYMBOLS.contains(ch)
+}
+
+/// An atom is a number if it starts with a digit, optionally preceded by a
+/// sign and/or a decimal point
+///
+/// This means `-` and `+` on their own are still identifiers, but `-1`, `.5`
+/// and `+.5` are numbers.
+fn looks_like_number(atom: &str) -> bool {
+    let atom = atom.strip_prefix(['+', '-']).unwrap_or(atom);
+    let atom = atom.strip_prefix('.').unwrap_or(atom);
+    atom.starts_with(|ch: char| ch.is_ascii_digit())
+}
+
+/// Parses the text of a numeric literal found at `span` in the input
+///
+/// Supported forms are:
+///
+/// ```text
+/// 42  -17  +3         decimal integers
+/// 0xff  0b1010  0o17  hexadecimal, binary and octal integers (may be signed)
+/// 1.5  -.25  6.02e23  floating-point numbers
+/// ```
+fn parse_number(text: &str, span: Span) -> Result<Number, LexError> {
+    let invalid = |reason: String| Err(LexError::InvalidNumber { reason, span });
+    let overflow = || LexError::NumberOverflow { span };
+
+    let (negative, unsigned) = match text.as_bytes()[0] {
+        b'-' => (true, &text[1..]),
+        b'+' => (false, &text[1..]),
+        _ => (false, text),
+    };
+
+    let radix = match unsigned.get(..2) {
+        Some("0x") | Some("0X") => Some(16),
+        Some("0o") | Some("0O") => Some(8),
+        Some("0b") | Some("0B") => Some(2),
+        _ => None,
+    };
+
+    if let Some(radix) = radix {
+        let digits = &unsigned[2..];
+        if digits.is_empty() {
+            return invalid(format!("expected digits after `{}`", &unsigned[..2]));
+        }
+        if let Some(bad) = digits.chars().find(|ch| !ch.is_digit(radix)) {
+            return invalid(format!("`{}` is not a valid base {} digit", bad, radix));
+        }
+        return u64::from_str_radix(digits, radix)
+            .ok()
+            .and_then(|magnitude| apply_sign(negative, magnitude))
+            .map(Number::Integer)
+            .ok_or_else(overflow);
+    }
+
+    // Walk through the decimal literal to check that it has the shape
+    // `digits [. digits] [e [sign] digits]`, remembering whether it
+    // turned out to be a float along the way
+    let bytes = unsigned.as_bytes();
+    let mut index = 0;
+    let count_digits = |from: usize| bytes[from..].iter().take_while(|b| b.is_ascii_digit()).count();
+
+    let integer_digits = count_digits(index);
+    index += integer_digits;
+    let mut is_float = false;
+
+    if bytes.get(index) == Some(&b'.') {
+        is_float = true;
+        index += 1;
+        let fraction_digits = count_digits(index);
+        if integer_digits == 0 && fraction_digits == 0 {
+            return invalid("expected digits around `.`".to_string());
+        }
+        index += fraction_digits;
+    }
+
+    if let Some(b'e') | Some(b'E') = bytes.get(index) {
+        is_float = true;
+        index += 1;
+        if let Some(b'+') | Some(b'-') = bytes.get(index) {
+            index += 1;
+        }
+        let exponent_digits = count_digits(index);
+        if exponent_digits == 0 {
+            return invalid("expected digits in exponent".to_string());
+        }
+        index += exponent_digits;
+    }
+
+    if let Some(bad) = unsigned[index..].chars().next() {
+        return invalid(format!("unexpected `{}` in number", bad));
+    }
+
+    if is_float {
+        // The shape has already been checked, so the standard library
+        // parser will accept it. Anything too large comes back as infinity.
+        let float: f64 = match text.parse() {
+            Ok(float) => float,
+            Err(_) => return invalid("malformed float".to_string()),
+        };
+        if float.is_infinite() {
+            return Err(overflow());
+        }
+        return Ok(Number::Float(float));
+    }
+
+    unsigned.parse::<u64>()
+        .ok()
+        .and_then(|magnitude| apply_sign(negative, magnitude))
+        .map(Number::Integer)
+        .ok_or_else(overflow)
+}
+
+/// Negates a magnitude if needed, checking that it fits in an `i64`
+///
+/// The magnitude is unsigned so that `-9223372036854775808` can be read.
+fn apply_sign(negative: bool, magnitude: u64) -> Option<i64> {
+    let value = if negative { -(magnitude as i128) } else { magnitude as i128 };
+    i64::try_from(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lexer() {
+        let input = "  ( one two )  ";
+        println!("String: \"{}\"", input);
+        let lexer = Lexer::new(input);
+
+        for token in lexer {
+            println!("{:?}", token);
+        }
+    }
+
+    /// The byte offsets of a span, which are easier to write out in tests
+    fn offsets(span: Span) -> (usize, usize) {
+        (span.start, span.end)
+    }
+
+    fn lex_numbers(input: &str) -> Vec<Number> {
+        Lexer::new(input)
+            .map(|token| match token.unwrap().token {
+                TokenType::Number(number) => number,
+                other => panic!("expected a number, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lex_numbers() {
+        assert_eq!(lex_numbers("0 42 -17 +3"), vec![
+            Number::Integer(0),
+            Number::Integer(42),
+            Number::Integer(-17),
+            Number::Integer(3),
+        ]);
+        assert_eq!(lex_numbers("0xff -0x10 0b1010 0o17"), vec![
+            Number::Integer(255),
+            Number::Integer(-16),
+            Number::Integer(10),
+            Number::Integer(15),
+        ]);
+        assert_eq!(lex_numbers("1.5 -.25 2. 6.02e23 1E-3"), vec![
+            Number::Float(1.5),
+            Number::Float(-0.25),
+            Number::Float(2.0),
+            Number::Float(6.02e23),
+            Number::Float(1e-3),
+        ]);
+        assert_eq!(lex_numbers("-9223372036854775808"), vec![Number::Integer(i64::MIN)]);
+    }
+
+    #[test]
+    fn test_lex_arithmetic() {
+        let tokens: Vec<_> = Lexer::new(" ( + 1 2 ) ")
+            .map(|token| token.unwrap())
+            .collect();
+        let summary: Vec<_> = tokens.iter()
+            .map(|token| (token.source.as_ref(), &token.token, offsets(token.span)))
+            .collect();
+        assert_eq!(summary, vec![
+            ("(", &TokenType::LeftParen, (1, 2)),
+            ("+", &TokenType::Identifier(Ident("+".into())), (3, 4)),
+            ("1", &TokenType::Number(Number::Integer(1)), (5, 6)),
+            ("2", &TokenType::Number(Number::Integer(2)), (7, 8)),
+            (")", &TokenType::RightParen, (9, 10)),
+        ]);
+    }
+
+    #[test]
+    fn test_bad_numbers() {
+        for input in &["0x", "0b102", "1.2.3", "12abc", "1e", "-0x1g"] {
+            let result = Lexer::new(input).next().unwrap();
+            assert!(matches!(result, Err(LexError::InvalidNumber { .. })), "{} should not lex", input);
+        }
+        for input in &["9223372036854775808", "0x10000000000000000", "1e999"] {
+            let error = Lexer::new(input).next().unwrap().unwrap_err();
+            assert!(matches!(error, LexError::NumberOverflow { .. }), "{} should overflow", input);
+            assert_eq!(offsets(error.span()), (0, input.len()));
+        }
+        // The lexer carries on after a bad literal
+        let mut lexer = Lexer::new("(0x 1)");
+        assert!(lexer.next().unwrap().is_ok());
+        assert!(lexer.next().unwrap().is_err());
+        assert_eq!(lexer.next().unwrap().unwrap().token, TokenType::Number(Number::Integer(1)));
+        assert_eq!(lexer.next().unwrap().unwrap().token, TokenType::RightParen);
+    }
+
+    fn lex_string(input: &str) -> Result<StrLiteral<'_>, LexError> {
+        let token = Lexer::new(input).next().unwrap()?;
+        match token.token {
+            TokenType::Str(string) => Ok(string),
+            other => panic!("expected a string, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lex_strings() {
+        let plain = lex_string(r#""hello world""#).unwrap();
+        assert_eq!(plain.raw, "hello world");
+        assert!(matches!(plain.value, Cow::Borrowed("hello world")));
+
+        let escaped = lex_string(r#""tab\there \"quoted\" back\\slash\n\u{1F600}""#).unwrap();
+        assert_eq!(escaped.raw, r#"tab\there \"quoted\" back\\slash\n\u{1F600}"#);
+        assert_eq!(escaped.value, "tab\there \"quoted\" back\\slash\n\u{1F600}");
+
+        let tokens: Vec<_> = Lexer::new(r#"(print "a b")"#)
+            .map(|token| token.unwrap().span)
+            .collect();
+        assert_eq!(offsets(tokens[2]), (7, 12));
+    }
+
+    #[test]
+    fn test_bad_strings() {
+        let error = lex_string(r#""abc"#).unwrap_err();
+        assert!(matches!(error, LexError::UnterminatedString { .. }));
+        assert_eq!(offsets(error.span()), (0, 4));
+
+        let error = lex_string(r#""a\qb" x"#).unwrap_err();
+        assert_eq!(error.to_string(), "invalid escape sequence: unknown escape `\\q`");
+        assert_eq!(offsets(error.span()), (2, 4));
+
+        let error = lex_string(r#""\u{110000}""#).unwrap_err();
+        assert!(matches!(error, LexError::InvalidEscape { .. }));
+        assert_eq!(offsets(error.span()), (1, 11));
+
+        let error = lex_string(r#""\u{12" 1"#).unwrap_err();
+        assert_eq!(offsets(error.span()), (1, 6));
+
+        // Lexing resumes after the closing quote of a bad string
+        let mut lexer = Lexer::new(r#""\x" 1"#);
+        assert!(lexer.next().unwrap().is_err());
+        assert_eq!(lexer.next().unwrap().unwrap().token, TokenType::Number(Number::Integer(1)));
+    }
+
+    #[test]
+    fn test_unicode_input() {
+        let input = "(définir größe \"日本語\" λ π2) 🦀";
+        let tokens: Vec<_> = Lexer::new(input).collect();
+
+        let sources: Vec<_> = tokens.iter()
+            .filter_map(|token| token.as_ref().ok())
+            .map(|token| {
+                // Spans must always be valid places to slice the input
+                assert_eq!(&input[token.span.start..token.span.end], token.source);
+                token.source.as_ref()
+            })
+            .collect();
+        assert_eq!(sources, vec!["(", "définir", "größe", "\"日本語\"", "λ", "π2", ")"]);
+
+        // The crab is not a letter, so it is not allowed in an identifier
+        let error = tokens.last().unwrap().as_ref().unwrap_err();
+        assert!(matches!(error, LexError::UnexpectedChar { ch: '🦀', .. }));
+        assert_eq!(offsets(error.span()), (input.len() - 4, input.len()));
+    }
+
+    #[test]
+    fn test_lines_and_columns() {
+        let input = "(define x\r\n\t\"日本\")\n\n  (print x)\u{3000}?";
+        let tokens: Vec<_> = Lexer::new(input).map(|token| token.unwrap()).collect();
+        let positions: Vec<_> = tokens.iter()
+            .map(|token| (token.source.as_ref(), token.span.start_pos.to_string(), token.span.end_pos.to_string()))
+            .collect();
+        assert_eq!(positions, vec![
+            ("(", "1:1".to_string(), "1:2".to_string()),
+            ("define", "1:2".to_string(), "1:8".to_string()),
+            ("x", "1:9".to_string(), "1:10".to_string()),
+            ("\"日本\"", "2:2".to_string(), "2:6".to_string()),
+            (")", "2:6".to_string(), "2:7".to_string()),
+            ("(", "4:3".to_string(), "4:4".to_string()),
+            ("print", "4:4".to_string(), "4:9".to_string()),
+            ("x", "4:10".to_string(), "4:11".to_string()),
+            (")", "4:11".to_string(), "4:12".to_string()),
+            ("?", "4:13".to_string(), "4:14".to_string()),
+        ]);
+
+        let error = Lexer::new("(ok)\n  (bad #)").find_map(Result::err).unwrap();
+        assert_eq!(error.span().in_file("main.lisp").to_string(), "main.lisp:2:8");
+    }
+
+    #[test]
+    fn test_comments() {
+        let input = "; a line comment\n(one #| a #| nested |# block |# two) ; trailing\n(#; (three (four)) five #;six)";
+
+        let skipped: Vec<_> = Lexer::new(input).map(|token| token.unwrap().source).collect();
+        assert_eq!(skipped, vec!["(", "one", "two", ")", "(", "five", ")"]);
+
+        let tokens: Vec<_> = Lexer::new(input).with_comments().map(|token| token.unwrap()).collect();
+        let comments: Vec<_> = tokens.iter()
+            .filter_map(|token| match &token.token {
+                TokenType::Comment(comment) => Some((comment.kind, comment.text.as_ref(), token.source.as_ref())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(comments, vec![
+            (CommentKind::Line, " a line comment", "; a line comment"),
+            (CommentKind::Block, " a #| nested |# block ", "#| a #| nested |# block |#"),
+            (CommentKind::Line, " trailing", "; trailing"),
+            (CommentKind::Datum, "(three (four))", "#; (three (four))"),
+            (CommentKind::Datum, "six", "#;six"),
+        ]);
+    }
+
+    #[test]
+    fn test_bad_comments() {
+        let error = Lexer::new("(a #| never #| nested |# closed").find_map(Result::err).unwrap();
+        assert!(matches!(error, LexError::UnterminatedComment { .. }));
+        assert_eq!(offsets(error.span()), (3, 31));
+
+        // A datum comment with nothing to comment out leaves the `)` alone
+        let tokens: Vec<_> = Lexer::new("(a #;)").collect();
+        assert!(matches!(tokens[2], Err(LexError::MissingDatum { .. })));
+        assert_eq!(tokens[3].as_ref().unwrap().token, TokenType::RightParen);
+    }
+
+    #[test]
+    fn test_lossless_round_trip() {
+        let input = "  ; header\r\n(define (f x)\t#| why |#\r\n  (* x \"日本\" 1.5)) #; (old)\n\n";
+        let tokens: Vec<_> = Lexer::new(input).lossless().map(|token| token.unwrap()).collect();
+
+        let round_trip: String = tokens.iter().map(|token| token.source.as_ref()).collect();
+        assert_eq!(round_trip, input);
+
+        // Every token picks up exactly where the last one left off
+        let mut offset = 0;
+        for token in &tokens {
+            assert_eq!(token.span.start, offset);
+            offset = token.span.end;
+        }
+
+        let trivia = tokens.iter().filter(|token| token.token.is_trivia()).count();
+        let meaningful = Lexer::new(input).count();
+        assert_eq!(trivia + meaningful, tokens.len());
+    }
+
+    #[test]
+    fn test_owned_tokens() {
+        let tokens: Vec<OwnedToken> = {
+            let input = String::from("(greet \"hi\\n\" ; wave\n 'x)");
+            Lexer::new(&input).with_comments().map(|token| token.unwrap().into_owned()).collect()
+        };
+
+        // Owned tokens can be sent to another thread
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || sender.send(tokens).unwrap());
+        let tokens = receiver.recv().unwrap();
+
+        assert_eq!(tokens[1].token, TokenType::Identifier(Ident("greet".into())));
+        match &tokens[2].token {
+            TokenType::Str(string) => assert_eq!((string.raw.as_ref(), string.value.as_ref()), ("hi\\n", "hi\n")),
+            other => panic!("expected a string, found {:?}", other),
+        }
+        assert_eq!(tokens[3].token, TokenType::Comment(Comment { kind: CommentKind::Line, text: " wave".into() }));
+        assert_eq!(tokens[3].source, "; wave");
+        assert_eq!(offsets(tokens[5].span), (23, 24));
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let input = "(define (f x)\n  (g 1x \"a\\qb\" 🦀 x 0x)\n  #;)\n  \"never closed)";
+
+        // Every typo is reported, and everything else is still lexed
+        let tokens: Vec<_> = Lexer::new(input).collect();
+        let errors: Vec<_> = tokens.iter().filter_map(|token| token.as_ref().err()).collect();
+        assert_eq!(errors.len(), 6);
+        assert!(matches!(errors[0], LexError::InvalidNumber { .. }));
+        assert!(matches!(errors[1], LexError::InvalidEscape { .. }));
+        assert!(matches!(errors[2], LexError::UnexpectedChar { ch: '🦀', .. }));
+        assert!(matches!(errors[3], LexError::InvalidNumber { .. }));
+        assert!(matches!(errors[4], LexError::MissingDatum { .. }));
+        assert!(matches!(errors[5], LexError::UnterminatedString { .. }));
+        let sources: Vec<_> = tokens.iter().filter_map(|token| token.as_ref().ok()).map(|token| token.source.as_ref()).collect();
+        assert_eq!(sources, vec!["(", "define", "(", "f", "x", ")", "(", "g", "x", ")", ")"]);
+
+        // Error tokens cover the whole bad region, while the error itself
+        // points at exactly what is wrong
+        let bad: Vec<_> = Lexer::new(input).recovering()
+            .filter_map(|token| match token.token {
+                TokenType::Error(error) => Some((token.source, offsets(error.span()))),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bad[0], ("1x".into(), (19, 21)));
+        assert_eq!(bad[1], ("\"a\\qb\"".into(), (24, 26)));
+        assert_eq!(bad[4], ("#;".into(), (42, 44)));
+
+        // Nothing is lost, even with errors in the input
+        let round_trip: String = Lexer::new(input).lossless().recovering().map(|token| token.source).collect();
+        assert_eq!(round_trip, input);
+    }
+
+    #[test]
+    fn test_peek_and_rewind() {
+        let mut lexer = Lexer::new("(a 1x b) c");
+        assert_eq!(lexer.peek().unwrap().unwrap().source, "(");
+        assert_eq!(lexer.peek_nth(2).unwrap().unwrap_err().span().start_pos.column, 4);
+        assert_eq!(lexer.peek_nth(3).unwrap().unwrap().source, "b");
+        assert!(lexer.peek_nth(6).is_none());
+
+        // Peeking doesn't use anything up, and doesn't move the checkpoint
+        let start = lexer.checkpoint();
+        assert_eq!(lexer.next().unwrap().unwrap().source, "(");
+        assert_eq!(lexer.next().unwrap().unwrap().source, "a");
+        let after_a = lexer.checkpoint();
+        assert!(lexer.next().unwrap().is_err());
+        let rest: Vec<_> = lexer.by_ref().map(|token| token.unwrap().source).collect();
+        assert_eq!(rest, vec!["b", ")", "c"]);
+
+        // Going back gives the same tokens, with the same spans, all over again
+        lexer.rewind(after_a);
+        assert!(lexer.next().unwrap().is_err());
+        lexer.rewind(start);
+        let again: Vec<_> = lexer.map(|token| token.map(|token| token.span)).collect();
+        let fresh: Vec<_> = Lexer::new("(a 1x b) c").map(|token| token.map(|token| token.span)).collect();
+        assert_eq!(again, fresh);
+
+        // Peeked tokens are not lost when switching to a recovering lexer
+        let mut lexer = Lexer::new("x y");
+        lexer.peek_nth(1);
+        assert_eq!(lexer.recovering().map(|token| token.source).collect::<Vec<_>>(), vec!["x", "y"]);
+    }
+
+    #[test]
+    fn test_booleans() {
+        let tokens: Vec<_> = Lexer::new("#t #f #true #false").map(|token| token.unwrap().token).collect();
+        assert_eq!(tokens, vec![
+            TokenType::Boolean(true),
+            TokenType::Boolean(false),
+            TokenType::Boolean(true),
+            TokenType::Boolean(false),
+        ]);
+        assert!(Lexer::new("#yes").next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_quote_prefixes() {
+        let tokens: Vec<_> = Lexer::new("'a `(b ,c ,@d) #; 'e f").map(|token| token.unwrap().source).collect();
+        assert_eq!(tokens, vec!["'", "a", "`", "(", "b", ",", "c", ",@", "d", ")", "f"]);
+    }
+}
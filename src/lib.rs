@@ -1,4 +1,21 @@
+pub mod compiler;
+pub mod diagnostic;
+pub mod eval;
+pub mod format;
+pub mod gc;
+pub mod highlight;
+pub mod incremental;
+pub mod lexer;
+pub mod macros;
+pub mod parser;
+pub mod stream;
+pub mod symbol;
+pub mod vm;
+
+// Small examples from the seminar, which nothing else in the library uses
+#[allow(dead_code)]
 mod traffic_light;
+#[allow(dead_code)]
 mod contact_info;
 
 #[cfg(test)]
@@ -0,0 +1,998 @@
+//! Macros, which let programs add their own special forms
+//!
+//! A macro is a rule for rewriting code. Before each top-level expression
+//! is run, the expander looks through it for uses of macros and replaces
+//! each one with the code that the macro turns it into, over and over
+//! until there are no macros left.
+//!
+//! There are two ways to write a macro. `defmacro` gives a procedure that
+//! is called with the code of the arguments as data, and returns new code,
+//! usually built with a quasiquote:
+//!
+//! ```text
+//! (defmacro unless (condition . body)
+//!   `(if ,condition #f (begin ,@body)))
+//!
+//! (unless (< x 0) (display x))  =>  (if (< x 0) #f (begin (display x)))
+//! ```
+//!
+//! `syntax-rules` instead gives patterns to match the code against, along
+//! with a template to fill in for each one. A `...` matches any number of
+//! the thing before it, and repeats the thing before it in the template:
+//!
+//! ```text
+//! (define-syntax my-or
+//!   (syntax-rules ()
+//!     ((_) #f)
+//!     ((_ first rest ...) (let ((tmp first)) (if tmp tmp (my-or rest ...))))))
+//! ```
+//!
+//! `syntax-rules` macros are "hygienic": a variable that the template makes,
+//! like `tmp` above, is renamed so that it can't get mixed up with a
+//! variable in the code that uses the macro, so `(my-or #f tmp)` still works.
+//! The other names in the template, like `my-or`, mean what they meant
+//! where the macro was defined. Macros are only defined at the top level,
+//! so those names are the globals, and each one is swapped for its
+//! `Symbol::global_alias`, which a local variable in the code that uses the
+//! macro can't get in the way of. `defmacro` doesn't rename anything, so
+//! it's up to the macro to be careful.
+//!
+//! The code that a macro makes didn't come from anywhere in the input, so
+//! it is given the span of the macro's use. That way an error in the
+//! expanded code points at the code that used the macro. `syntax-rules`
+//! also keeps the spans of any code that was passed in to it.
+//!
+//! Quasiquote is expanded here too, into calls to `cons` and `append`:
+//!
+//! ```text
+//! `(a ,b ,@c)  =>  (cons 'a (cons b (append c '())))
+//! ```
+//!
+//! Those aren't the `cons` and `append` that the program sees though, since
+//! the program could have variables of its own with those names. They are
+//! the builtins under names that only the expander can write.
+
+use crate::eval::{self, Builtin, EvalError, Value};
+use crate::lexer::Span;
+use crate::parser::{Expr, ExprKind};
+use crate::symbol::Symbol;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Expands macros, and remembers the macros that have been defined so far
+///
+/// ```
+/// use csh_seminar_feb_2021::eval::Interpreter;
+///
+/// let mut interpreter = Interpreter::new();
+/// interpreter.eval_str("(define-syntax swap! (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))").unwrap();
+/// let result = interpreter.eval_str("(define tmp 1) (define other 2) (swap! tmp other) (list tmp other)").unwrap();
+/// assert_eq!(result.to_string(), "(2 1)");
+/// ```
+#[derive(Default)]
+pub struct Expander {
+    macros: HashMap<Symbol, Macro>,
+}
+
+/// Makes and calls the procedures from `defmacro`, for the interpreter or the VM
+///
+/// Each top-level expression is expanded just before it is run, by the same
+/// machine that runs it. So a macro's procedure sees the same globals as
+/// the rest of the program, whichever machine that is.
+pub trait Runtime {
+    /// Makes a procedure in the global environment
+    fn procedure(&mut self, name: Symbol, params: Vec<Symbol>, rest: Option<Symbol>, body: &[Expr], span: Span) -> Result<Value, EvalError>;
+
+    /// Calls a procedure that `procedure` made
+    fn apply(&mut self, procedure: &Value, args: &[Value], span: Span) -> Result<Value, EvalError>;
+}
+
+enum Macro {
+    /// A procedure from `defmacro`, which is called with the code of the arguments
+    Procedure(Value),
+    Rules(Rules),
+}
+
+impl Expander {
+    pub fn new() -> Expander {
+        Expander::default()
+    }
+
+    /// Expands every macro in a top-level expression
+    ///
+    /// Macros defined with `defmacro` and `define-syntax` are remembered for
+    /// later, and their definitions become `(begin)`. The procedures made by
+    /// `defmacro` are made and run by `runtime`, so they can use any global
+    /// that the program has defined by the time they are called.
+    pub fn expand(&mut self, expr: &Expr, runtime: &mut dyn Runtime) -> Result<Expr, EvalError> {
+        let expr = self.expand_head(expr.clone(), runtime)?;
+        let (head, args) = match split_form(&expr) {
+            Some(form) => form,
+            None => return self.expr(&expr, runtime),
+        };
+        match head {
+            Symbol::DEFMACRO => {
+                self.defmacro(args, runtime, expr.span)?;
+                Ok(list(vec![symbol(Symbol::BEGIN, expr.span)], expr.span))
+            }
+            Symbol::DEFINE_SYNTAX => {
+                self.define_syntax(args, expr.span)?;
+                Ok(list(vec![symbol(Symbol::BEGIN, expr.span)], expr.span))
+            }
+            // The expressions in a top-level `begin` are at the top level too
+            Symbol::BEGIN => {
+                let mut items = vec![symbol(Symbol::BEGIN, expr.span)];
+                for arg in args {
+                    items.push(self.expand(arg, runtime)?);
+                }
+                Ok(list(items, expr.span))
+            }
+            _ => self.expr(&expr, runtime),
+        }
+    }
+
+    /// Expands the macro used by an expression once, if it uses one
+    ///
+    /// This is what `macroexpand-1` does. The parts of the result aren't expanded.
+    pub fn expand_once(&self, expr: &Expr, runtime: &mut dyn Runtime) -> Result<Option<Expr>, EvalError> {
+        let (head, args) = match split_form(expr) {
+            Some(form) => form,
+            None => return Ok(None),
+        };
+        if head == Symbol::QUASIQUOTE {
+            return match args {
+                [template] => quasiquote(template, 1).map(Some),
+                _ => Err(EvalError::BadSyntax { form: "quasiquote", reason: "expected exactly one expression", span: expr.span }),
+            };
+        }
+        // A `syntax-rules` template that uses another macro has the alias of its name
+        let used = self.macros.get(&head).or_else(|| self.macros.get(&head.aliased()?));
+        match used {
+            Some(Macro::Procedure(transformer)) => {
+                let args: Vec<Value> = args.iter().map(Value::from_expr).collect();
+                let code = runtime.apply(transformer, &args, expr.span)?;
+                to_code(&code, expr.span).map(Some)
+            }
+            Some(Macro::Rules(rules)) => rules.expand(args, expr.span).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Expands the macro used by an expression until it doesn't use one any more
+    ///
+    /// This is what `macroexpand` does. The parts of the result aren't expanded.
+    pub fn expand_head(&self, mut expr: Expr, runtime: &mut dyn Runtime) -> Result<Expr, EvalError> {
+        while let Some(expanded) = self.expand_once(&expr, runtime)? {
+            expr = expanded;
+        }
+        Ok(expr)
+    }
+
+    /// Expands every macro in an expression that isn't at the top level
+    fn expr(&self, expr: &Expr, runtime: &mut dyn Runtime) -> Result<Expr, EvalError> {
+        let expr = self.expand_head(expr.clone(), runtime)?;
+        let items = match &expr.kind {
+            ExprKind::List(items) => items,
+            _ => return Ok(expr),
+        };
+        let span = expr.span;
+        let head = match items.first() {
+            Some(Expr { kind: ExprKind::Symbol(head), .. }) => Some(*head),
+            _ => None,
+        };
+        let items = match head {
+            Some(Symbol::QUOTE) => return Ok(expr),
+            Some(Symbol::DEFMACRO) => {
+                return Err(EvalError::BadSyntax { form: "defmacro", reason: "macros can only be defined at the top level", span })
+            }
+            Some(Symbol::DEFINE_SYNTAX) => {
+                return Err(EvalError::BadSyntax { form: "define-syntax", reason: "macros can only be defined at the top level", span })
+            }
+            Some(Symbol::MACROEXPAND_1) => return self.macroexpand("macroexpand-1", &items[1..], runtime, span),
+            Some(Symbol::MACROEXPAND) => return self.macroexpand("macroexpand", &items[1..], runtime, span),
+            // Leave the names that these forms define alone, and expand the rest
+            Some(Symbol::LAMBDA) | Some(Symbol::DEFINE) | Some(Symbol::SET) => self.exprs_after(items, 2, runtime)?,
+            Some(Symbol::LET) | Some(Symbol::LET_STAR) | Some(Symbol::LETREC) if items.len() > 1 => {
+                let mut expanded = self.exprs_after(items, 2, runtime)?;
+                expanded[1] = self.bindings(&items[1], runtime)?;
+                expanded
+            }
+            // Each clause is a list of expressions, rather than a call
+            Some(Symbol::COND) => {
+                let mut expanded = vec![items[0].clone()];
+                for clause in &items[1..] {
+                    expanded.push(match &clause.kind {
+                        ExprKind::List(clause_items) => list(self.exprs_after(clause_items, 0, runtime)?, clause.span),
+                        _ => clause.clone(),
+                    });
+                }
+                expanded
+            }
+            _ => self.exprs_after(items, 0, runtime)?,
+        };
+        Ok(list(items, span))
+    }
+
+    /// Keeps the first `keep` items as they are, and expands the rest
+    fn exprs_after(&self, items: &[Expr], keep: usize, runtime: &mut dyn Runtime) -> Result<Vec<Expr>, EvalError> {
+        let mut expanded: Vec<Expr> = items.iter().take(keep).cloned().collect();
+        for item in items.iter().skip(keep) {
+            expanded.push(self.expr(item, runtime)?);
+        }
+        Ok(expanded)
+    }
+
+    /// Expands the values in the `((name value) ...)` bindings of a `let`
+    fn bindings(&self, bindings: &Expr, runtime: &mut dyn Runtime) -> Result<Expr, EvalError> {
+        let items = match &bindings.kind {
+            ExprKind::List(items) => items,
+            _ => return Ok(bindings.clone()),
+        };
+        let mut expanded = Vec::new();
+        for binding in items {
+            expanded.push(match &binding.kind {
+                ExprKind::List(pair) => list(self.exprs_after(pair, 1, runtime)?, binding.span),
+                _ => binding.clone(),
+            });
+        }
+        Ok(list(expanded, bindings.span))
+    }
+
+    /// `(macroexpand-1 'code)` and `(macroexpand 'code)`, which become the expanded code, quoted
+    fn macroexpand(&self, form: &'static str, args: &[Expr], runtime: &mut dyn Runtime, span: Span) -> Result<Expr, EvalError> {
+        let code = match args {
+            [Expr { kind: ExprKind::List(quoted), .. }] => match quoted.as_slice() {
+                [Expr { kind: ExprKind::Symbol(Symbol::QUOTE), .. }, code] => code,
+                _ => return Err(EvalError::BadSyntax { form, reason: "expected a quoted expression", span }),
+            },
+            _ => return Err(EvalError::BadSyntax { form, reason: "expected a quoted expression", span }),
+        };
+        let expanded = if form == "macroexpand" {
+            self.expand_head(code.clone(), runtime)?
+        } else {
+            self.expand_once(code, runtime)?.unwrap_or_else(|| code.clone())
+        };
+        Ok(list(vec![symbol(Symbol::QUOTE, span), expanded], span))
+    }
+
+    /// `(defmacro name (params...) body...)`
+    fn defmacro(&mut self, args: &[Expr], runtime: &mut dyn Runtime, span: Span) -> Result<(), EvalError> {
+        let bad_syntax = |reason| EvalError::BadSyntax { form: "defmacro", reason, span };
+        let (name, params, body) = match args {
+            [Expr { kind: ExprKind::Symbol(name), .. }, params, body @ ..] => (*name, params, body),
+            _ => return Err(bad_syntax("expected a name, parameters and a body")),
+        };
+        let (params, rest) = match &params.kind {
+            ExprKind::List(params) => eval::parse_params(params, span)?,
+            ExprKind::Symbol(rest) => (Vec::new(), Some(*rest)),
+            _ => return Err(bad_syntax("expected a list of parameters")),
+        };
+        let body = self.exprs_after(body, 0, runtime)?;
+        let transformer = runtime.procedure(name, params, rest, &body, span)?;
+        self.macros.insert(name, Macro::Procedure(transformer));
+        Ok(())
+    }
+
+    /// `(define-syntax name (syntax-rules (literals...) (pattern template)...))`
+    fn define_syntax(&mut self, args: &[Expr], span: Span) -> Result<(), EvalError> {
+        match args {
+            [Expr { kind: ExprKind::Symbol(name), .. }, rules] => {
+                let rules = Rules::parse(rules)?;
+                self.macros.insert(*name, Macro::Rules(rules));
+                Ok(())
+            }
+            _ => Err(EvalError::BadSyntax { form: "define-syntax", reason: "expected a name and a syntax-rules", span }),
+        }
+    }
+}
+
+/// The name at the start of a list, and everything after it
+fn split_form(expr: &Expr) -> Option<(Symbol, &[Expr])> {
+    match &expr.kind {
+        ExprKind::List(items) => match items.split_first() {
+            Some((Expr { kind: ExprKind::Symbol(head), .. }, args)) => Some((*head, args)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_symbol(expr: &Expr, name: Symbol) -> bool {
+    matches!(expr.kind, ExprKind::Symbol(symbol) if symbol == name)
+}
+
+fn symbol(name: Symbol, span: Span) -> Expr {
+    Expr { kind: ExprKind::Symbol(name), span }
+}
+
+fn list(items: Vec<Expr>, span: Span) -> Expr {
+    Expr { kind: ExprKind::List(items), span }
+}
+
+/// The builtins that quasiquote calls, each with a gensym for its name
+///
+/// No program can write these names, so a local `cons` or `list` can't get
+/// in the way of a quasiquote. The interpreter and the VM define them as
+/// globals, along with the builtins under their usual names.
+pub(crate) fn quasiquote_builtins() -> &'static [(Symbol, Builtin)] {
+    static BUILTINS: OnceLock<Vec<(Symbol, Builtin)>> = OnceLock::new();
+    BUILTINS.get_or_init(|| {
+        ["cons", "append", "list"].iter()
+            .map(|name| {
+                let builtin = eval::BUILTINS.iter().find(|builtin| builtin.name == *name).expect("quasiquote only calls builtins");
+                (Symbol::intern(name).gensym(), *builtin)
+            })
+            .collect()
+    })
+}
+
+/// A call to one of the builtins that quasiquote uses
+fn call(name: &str, args: Vec<Expr>, span: Span) -> Expr {
+    let (builtin, _) = quasiquote_builtins().iter().find(|(_, builtin)| builtin.name == name).expect("quasiquote only calls builtins");
+    let mut items = vec![symbol(*builtin, span)];
+    items.extend(args);
+    list(items, span)
+}
+
+fn quote(expr: Expr) -> Expr {
+    let span = expr.span;
+    list(vec![symbol(Symbol::QUOTE, span), expr], span)
+}
+
+/// Turns the data that a `defmacro` procedure returned back into code
+fn to_code(value: &Value, span: Span) -> Result<Expr, EvalError> {
+    let kind = match value {
+        Value::Nil => ExprKind::List(Vec::new()),
+        Value::Bool(boolean) => ExprKind::Bool(*boolean),
+        Value::Number(number) => ExprKind::Number(*number),
+        Value::Str(string) => ExprKind::Str(string.to_string()),
+        Value::Symbol(name) => ExprKind::Symbol(*name),
+        Value::Pair(_) => {
+            let mut items = Vec::new();
+            let mut rest = value;
+            while let Value::Pair(pair) = rest {
+                items.push(to_code(&pair.0, span)?);
+                rest = &pair.1;
+            }
+            if !matches!(rest, Value::Nil) {
+                items.push(symbol(Symbol::DOT, span));
+                items.push(to_code(rest, span)?);
+            }
+            ExprKind::List(items)
+        }
+        other => return Err(EvalError::TypeMismatch { expected: "piece of code", found: other.type_name(), span }),
+    };
+    Ok(Expr { kind, span })
+}
+
+/// The code that builds the value of a quasiquoted template
+///
+/// `depth` counts how many quasiquotes we are inside of, since only an
+/// unquote inside of a single quasiquote is evaluated.
+fn quasiquote(template: &Expr, depth: usize) -> Result<Expr, EvalError> {
+    let span = template.span;
+    let items = match &template.kind {
+        ExprKind::List(items) => items,
+        ExprKind::Symbol(_) => return Ok(quote(template.clone())),
+        _ => return Ok(template.clone()),
+    };
+    let unquote_depth = |name| if name == Symbol::QUASIQUOTE { depth + 1 } else { depth - 1 };
+    match items.as_slice() {
+        [Expr { kind: ExprKind::Symbol(Symbol::UNQUOTE), .. }, value] if depth == 1 => return Ok(value.clone()),
+        [Expr { kind: ExprKind::Symbol(Symbol::UNQUOTE_SPLICING), .. }, _] if depth == 1 => {
+            return Err(EvalError::BadSyntax { form: "unquote-splicing", reason: "can only be used inside of a list", span })
+        }
+        [head @ Expr { kind: ExprKind::Symbol(name @ (Symbol::QUASIQUOTE | Symbol::UNQUOTE | Symbol::UNQUOTE_SPLICING)), .. }, value] => {
+            let value = quasiquote(value, unquote_depth(*name))?;
+            return Ok(call("list", vec![quote(head.clone()), value], span));
+        }
+        _ => (),
+    }
+    if !has_unquote(template) {
+        return Ok(quote(template.clone()));
+    }
+
+    // Build the list from the back, starting with whatever comes after a `.`
+    let (items, mut result) = match items.as_slice() {
+        [items @ .., dot, last] if is_symbol(dot, Symbol::DOT) => (items, quasiquote(last, depth)?),
+        items => (items, quote(list(Vec::new(), span))),
+    };
+    for item in items.iter().rev() {
+        result = match &item.kind {
+            ExprKind::List(spliced) if depth == 1 && spliced.len() == 2 && is_symbol(&spliced[0], Symbol::UNQUOTE_SPLICING) => {
+                call("append", vec![spliced[1].clone(), result], item.span)
+            }
+            _ => call("cons", vec![quasiquote(item, depth)?, result], item.span),
+        };
+    }
+    Ok(result)
+}
+
+/// Whether there is an unquote anywhere in a quasiquoted template
+fn has_unquote(template: &Expr) -> bool {
+    match &template.kind {
+        ExprKind::List(items) => items.iter().any(|item| {
+            is_symbol(item, Symbol::UNQUOTE) || is_symbol(item, Symbol::UNQUOTE_SPLICING) || has_unquote(item)
+        }),
+        _ => false,
+    }
+}
+
+/// The rules of a `syntax-rules` macro
+struct Rules {
+    /// Names in the patterns that match only themselves, like `else`
+    literals: Vec<Symbol>,
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    /// Everything in the pattern after the macro's name
+    pattern: Vec<Expr>,
+    template: Expr,
+}
+
+/// What a pattern variable matched
+#[derive(Clone)]
+enum Binding {
+    One(Expr),
+    /// The matches for a variable that was followed by a `...`, one for each repetition
+    Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<Symbol, Binding>;
+
+impl Rules {
+    /// Reads `(syntax-rules (literals...) (pattern template)...)`
+    fn parse(expr: &Expr) -> Result<Rules, EvalError> {
+        let bad_syntax = |reason| EvalError::BadSyntax { form: "syntax-rules", reason, span: expr.span };
+        let (literals, rules) = match split_form(expr) {
+            Some((Symbol::SYNTAX_RULES, [Expr { kind: ExprKind::List(literals), .. }, rules @ ..])) => (literals, rules),
+            _ => return Err(bad_syntax("expected (syntax-rules (literals...) (pattern template)...)")),
+        };
+        let literals = literals.iter()
+            .map(|literal| match literal.kind {
+                ExprKind::Symbol(literal) => Ok(literal),
+                _ => Err(bad_syntax("literals must be names")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut parsed = Rules { literals, rules: Vec::new() };
+        for rule in rules {
+            let (pattern, template) = match &rule.kind {
+                ExprKind::List(rule) => match rule.as_slice() {
+                    [Expr { kind: ExprKind::List(pattern), .. }, template] if !pattern.is_empty() => (&pattern[1..], template),
+                    _ => return Err(bad_syntax("each rule must be a pattern list and a template")),
+                },
+                _ => return Err(bad_syntax("each rule must be a pattern list and a template")),
+            };
+            parsed.rules.push(Rule { pattern: pattern.to_vec(), template: template.clone() });
+        }
+        Ok(parsed)
+    }
+
+    /// Finds the first rule that matches the arguments of a use of the macro, and fills in its template
+    fn expand(&self, args: &[Expr], span: Span) -> Result<Expr, EvalError> {
+        for rule in &self.rules {
+            let mut bindings = Bindings::new();
+            if self.match_list(&rule.pattern, args, span, &mut bindings) {
+                return Template { span }.fill(&rule.template, &bindings, &Renames::new());
+            }
+        }
+        Err(EvalError::BadSyntax { form: "syntax-rules", reason: "no rule matches this use of the macro", span })
+    }
+
+    /// The pattern variables in a pattern
+    fn variables(&self, pattern: &Expr, variables: &mut Vec<Symbol>) {
+        match &pattern.kind {
+            ExprKind::Symbol(Symbol::UNDERSCORE) | ExprKind::Symbol(Symbol::ELLIPSIS) | ExprKind::Symbol(Symbol::DOT) => (),
+            ExprKind::Symbol(name) if !self.literals.contains(name) => variables.push(*name),
+            ExprKind::List(items) => {
+                for item in items {
+                    self.variables(item, variables);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn matches(&self, pattern: &Expr, form: &Expr, bindings: &mut Bindings) -> bool {
+        match (&pattern.kind, &form.kind) {
+            (ExprKind::Symbol(Symbol::UNDERSCORE), _) => true,
+            (ExprKind::Symbol(literal), _) if self.literals.contains(literal) => {
+                matches!(form.kind, ExprKind::Symbol(name) if name.aliased().unwrap_or(name) == *literal)
+            }
+            (ExprKind::Symbol(name), _) => {
+                bindings.insert(*name, Binding::One(form.clone()));
+                true
+            }
+            (ExprKind::List(patterns), ExprKind::List(forms)) => self.match_list(patterns, forms, form.span, bindings),
+            (pattern, form) => pattern == form,
+        }
+    }
+
+    /// Matches a list of patterns, which may have a `...` or a `. rest` in it
+    fn match_list(&self, patterns: &[Expr], forms: &[Expr], span: Span, bindings: &mut Bindings) -> bool {
+        if let [before @ .., dot, rest] = patterns {
+            if is_symbol(dot, Symbol::DOT) {
+                return forms.len() >= before.len()
+                    && self.match_list(before, &forms[..before.len()], span, bindings)
+                    && self.matches(rest, &list(forms[before.len()..].to_vec(), span), bindings);
+            }
+        }
+        let ellipsis = match patterns.iter().position(|pattern| is_symbol(pattern, Symbol::ELLIPSIS)) {
+            Some(ellipsis) if ellipsis > 0 => ellipsis,
+            _ => {
+                return patterns.len() == forms.len()
+                    && patterns.iter().zip(forms).all(|(pattern, form)| self.matches(pattern, form, bindings));
+            }
+        };
+
+        let (before, repeated, after) = (&patterns[..ellipsis - 1], &patterns[ellipsis - 1], &patterns[ellipsis + 1..]);
+        if forms.len() < before.len() + after.len() {
+            return false;
+        }
+        let end = forms.len() - after.len();
+        if !self.match_list(before, &forms[..before.len()], span, bindings) || !self.match_list(after, &forms[end..], span, bindings) {
+            return false;
+        }
+        let mut matches = Vec::new();
+        for form in &forms[before.len()..end] {
+            let mut inner = Bindings::new();
+            if !self.matches(repeated, form, &mut inner) {
+                return false;
+            }
+            matches.push(inner);
+        }
+        let mut variables = Vec::new();
+        self.variables(repeated, &mut variables);
+        for variable in variables {
+            let each = matches.iter_mut().filter_map(|inner| inner.remove(&variable)).collect();
+            bindings.insert(variable, Binding::Many(each));
+        }
+        true
+    }
+}
+
+/// The new names for the variables that a template makes, in the part of the template that can see them
+type Renames = HashMap<Symbol, Symbol>;
+
+/// Fills in a template for one use of a `syntax-rules` macro
+///
+/// The variables that the template makes with `lambda`, `define` and the
+/// `let`s are given new names, but only inside the code that can see them.
+/// In `(let ((list (list 1 2))) list)`, the second `list` is still the
+/// procedure, because a `let` variable can't be seen by its own value.
+/// Every other name becomes its global alias, apart from the names of the
+/// special forms and the name a top-level `define` makes.
+struct Template {
+    /// The span of the macro's use, which everything from the template gets
+    span: Span,
+}
+
+impl Template {
+    fn fill(&self, template: &Expr, bindings: &Bindings, renames: &Renames) -> Result<Expr, EvalError> {
+        let items = match &template.kind {
+            ExprKind::Symbol(name) => {
+                return match bindings.get(name) {
+                    Some(Binding::One(expr)) => Ok(expr.clone()),
+                    Some(Binding::Many(_)) => Err(self.bad_syntax("a pattern variable that was matched with `...` needs a `...` after it")),
+                    None => Ok(symbol(renames.get(name).copied().unwrap_or_else(|| global(*name)), self.span)),
+                };
+            }
+            ExprKind::List(items) => items,
+            kind => return Ok(Expr { kind: kind.clone(), span: self.span }),
+        };
+
+        let head = match items.first().map(|head| &head.kind) {
+            Some(ExprKind::Symbol(head)) if !bindings.contains_key(head) && !renames.contains_key(head) => Some(*head),
+            _ => None,
+        };
+        let filled = match (head, items.get(1)) {
+            // `(lambda (params...) body...)` and `(define (name params...) body...)`
+            (Some(head @ Symbol::LAMBDA), Some(params)) | (Some(head @ Symbol::DEFINE), Some(params)) => {
+                let params = match &params.kind {
+                    ExprKind::List(params) if head == Symbol::DEFINE => params.get(1..).unwrap_or_default(),
+                    ExprKind::List(params) => params,
+                    _ if head == Symbol::LAMBDA => std::slice::from_ref(params),
+                    _ => &[],
+                };
+                let mut inner = self.rename(params, bindings, renames);
+                if head == Symbol::DEFINE {
+                    // A `define` in a body was renamed by `body`, so this one makes a global
+                    let name = match &items[1].kind {
+                        ExprKind::List(target) => target.first(),
+                        _ => Some(&items[1]),
+                    };
+                    if let Some(Expr { kind: ExprKind::Symbol(name), .. }) = name {
+                        inner.entry(*name).or_insert(*name);
+                    }
+                }
+                let mut filled = vec![self.fill(&items[0], bindings, renames)?, self.fill(&items[1], bindings, &inner)?];
+                filled.extend(self.body(&items[2..], bindings, &inner)?);
+                filled
+            }
+            (Some(head @ Symbol::LET), Some(Expr { kind: ExprKind::List(pairs), .. }))
+            | (Some(head @ Symbol::LET_STAR), Some(Expr { kind: ExprKind::List(pairs), .. }))
+            | (Some(head @ Symbol::LETREC), Some(Expr { kind: ExprKind::List(pairs), .. })) => {
+                let names: Vec<Expr> = pairs.iter().filter_map(|pair| match &pair.kind {
+                    ExprKind::List(pair) => pair.first().cloned(),
+                    _ => None,
+                }).collect();
+                let all = self.rename(&names, bindings, renames);
+                // The names that the values can see so far, which for `let*` grows one variable at a time
+                let mut seen = if head == Symbol::LETREC { all.clone() } else { renames.clone() };
+                let pairs = self.each(pairs, bindings, |pair, bindings| {
+                    let (name, values) = match &pair.kind {
+                        ExprKind::List(pair) if !pair.is_empty() => (&pair[0], &pair[1..]),
+                        _ => return self.fill(pair, bindings, &seen),
+                    };
+                    let names = match head {
+                        Symbol::LET_STAR => self.rename(std::slice::from_ref(name), bindings, &seen),
+                        _ => all.clone(),
+                    };
+                    let mut filled = vec![self.fill(name, bindings, &names)?];
+                    filled.extend(self.each(values, bindings, |value, bindings| self.fill(value, bindings, &seen))?);
+                    if head == Symbol::LET_STAR {
+                        seen = names;
+                    }
+                    Ok(list(filled, self.span))
+                })?;
+                let inner = if head == Symbol::LET_STAR { seen } else { all };
+                let mut filled = vec![self.fill(&items[0], bindings, renames)?, list(pairs, self.span)];
+                filled.extend(self.body(&items[2..], bindings, &inner)?);
+                filled
+            }
+            (Some(Symbol::BEGIN), _) => {
+                let mut filled = vec![self.fill(&items[0], bindings, renames)?];
+                filled.extend(self.body(&items[1..], bindings, renames)?);
+                filled
+            }
+            _ => self.each(items, bindings, |item, bindings| self.fill(item, bindings, renames))?,
+        };
+        Ok(list(filled, self.span))
+    }
+
+    /// Fills in the body of a `lambda` or a `let`, where the names that it `define`s can be seen all through it
+    fn body(&self, items: &[Expr], bindings: &Bindings, renames: &Renames) -> Result<Vec<Expr>, EvalError> {
+        let names: Vec<Expr> = items.iter().filter_map(|item| match split_form(item) {
+            Some((Symbol::DEFINE, [Expr { kind: ExprKind::List(target), .. }, ..])) => target.first().cloned(),
+            Some((Symbol::DEFINE, [target, ..])) => Some(target.clone()),
+            _ => None,
+        }).collect();
+        let inner = self.rename(&names, bindings, renames);
+        self.each(items, bindings, |item, bindings| self.fill(item, bindings, &inner))
+    }
+
+    /// Gives new names to the variables that the template makes, leaving out pattern variables
+    fn rename(&self, names: &[Expr], bindings: &Bindings, renames: &Renames) -> Renames {
+        let mut renames = renames.clone();
+        for name in names {
+            if let ExprKind::Symbol(name) = name.kind {
+                if ![Symbol::DOT, Symbol::ELLIPSIS, Symbol::UNDERSCORE].contains(&name) && !bindings.contains_key(&name) {
+                    renames.insert(name, name.gensym());
+                }
+            }
+        }
+        renames
+    }
+
+    /// Fills in each item of a list, repeating the ones that are followed by a `...`
+    fn each(
+        &self,
+        items: &[Expr],
+        bindings: &Bindings,
+        mut fill: impl FnMut(&Expr, &Bindings) -> Result<Expr, EvalError>,
+    ) -> Result<Vec<Expr>, EvalError> {
+        let mut filled = Vec::new();
+        let mut index = 0;
+        while index < items.len() {
+            let item = &items[index];
+            if !items.get(index + 1).is_some_and(|next| is_symbol(next, Symbol::ELLIPSIS)) {
+                filled.push(fill(item, bindings)?);
+                index += 1;
+                continue;
+            }
+
+            // Fill in the item once for each match of the repeated pattern variables in it
+            let mut names = Vec::new();
+            symbols(item, &mut names);
+            let repeated: Vec<(Symbol, &Vec<Binding>)> = names.into_iter()
+                .filter_map(|name| match bindings.get(&name) {
+                    Some(Binding::Many(each)) => Some((name, each)),
+                    _ => None,
+                })
+                .collect();
+            let count = match repeated.first() {
+                Some((_, each)) => each.len(),
+                None => return Err(self.bad_syntax("`...` must come after a pattern variable that was matched with `...`")),
+            };
+            if repeated.iter().any(|(_, each)| each.len() != count) {
+                return Err(self.bad_syntax("pattern variables matched a different number of times are used with the same `...`"));
+            }
+            for i in 0..count {
+                let mut inner = bindings.clone();
+                for (name, each) in &repeated {
+                    inner.insert(*name, each[i].clone());
+                }
+                filled.push(fill(item, &inner)?);
+            }
+            index += 2;
+        }
+        Ok(filled)
+    }
+
+    fn bad_syntax(&self, reason: &'static str) -> EvalError {
+        EvalError::BadSyntax { form: "syntax-rules", reason, span: self.span }
+    }
+}
+
+/// What a name that a template uses but doesn't make turns into
+///
+/// The special forms aren't variables, so they stay as they are, and so
+/// do the names that only mean something to a special form or a pattern.
+fn global(name: Symbol) -> Symbol {
+    let keywords = [Symbol::ELSE, Symbol::DOT, Symbol::ELLIPSIS, Symbol::UNDERSCORE, Symbol::UNQUOTE, Symbol::UNQUOTE_SPLICING];
+    if eval::SPECIAL_FORMS.contains(&name.as_str()) || keywords.contains(&name) {
+        name
+    } else {
+        name.global_alias()
+    }
+}
+
+/// Every symbol in an expression, without repeats
+fn symbols(expr: &Expr, found: &mut Vec<Symbol>) {
+    match &expr.kind {
+        ExprKind::Symbol(name) if !found.contains(name) => found.push(*name),
+        ExprKind::List(items) => {
+            for item in items {
+                symbols(item, found);
+            }
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::Interpreter;
+    use crate::vm::Vm;
+
+    fn run(input: &str) -> Result<String, EvalError> {
+        Interpreter::new().eval_str(input).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn test_quasiquote() {
+        assert_eq!(run("(define b 2) (define c '(3 4)) `(a ,b ,@c)").unwrap(), "(a 2 3 4)");
+        assert_eq!(run("`(1 ,@'() 2)").unwrap(), "(1 2)");
+        assert_eq!(run("`(1 . ,(+ 1 1))").unwrap(), "(1 . 2)");
+        assert_eq!(run("`(x `(y ,(z ,(+ 1 2))))").unwrap(), "(x (quasiquote (y (unquote (z 3)))))");
+        assert_eq!(run("`plain").unwrap(), "plain");
+        assert!(matches!(run("`,@(list 1)").unwrap_err(), EvalError::BadSyntax { form: "unquote-splicing", .. }));
+
+        // The program's own `cons`, `append` and `list` don't get in the way
+        let program = "
+            (define (f cons) `(1 ,cons))
+            (define (g append) `(,@append 2))
+            (define (h list) `(,list `(,list)))
+            (list (f 5) (g '(1)) (h 3))";
+        assert_eq!(run(program).unwrap(), "((1 5) (1 2) (3 (quasiquote ((unquote list)))))");
+        assert_eq!(Vm::new().eval_str(program).unwrap().to_string(), "((1 5) (1 2) (3 (quasiquote ((unquote list)))))");
+        assert_eq!(run("(define (cons a b) 'mine) `(1 ,(+ 1 1))").unwrap(), "(1 2)");
+    }
+
+    #[test]
+    fn test_defmacro() {
+        let program = "
+            (defmacro unless (condition . body)
+              `(if ,condition #f (begin ,@body)))
+            (define x 0)
+            (unless (< x 0) (set! x 10) (+ x 1))";
+        assert_eq!(run(program).unwrap(), "11");
+
+        // Macros can use procedures defined before them, and expand into other macros
+        let program = "
+            (define (wrap name) (list 'list (list 'quote name)))
+            (defmacro names args `(list ,@(map-names args)))
+            (define (map-names args) (if (null? args) '() (cons (wrap (car args)) (map-names (cdr args)))))
+            (defmacro twice (form) `(names ,form ,form))
+            (twice hello)";
+        assert_eq!(run(program).unwrap(), "((hello) (hello))");
+
+        // `defmacro` isn't hygienic, so its variables can capture the caller's
+        let program = "
+            (defmacro with-it (value body) `(let ((it ,value)) ,body))
+            (with-it 42 (+ it 1))";
+        assert_eq!(run(program).unwrap(), "43");
+
+        assert!(matches!(run("(defmacro m () (lambda () 1)) (m)").unwrap_err(), EvalError::TypeMismatch { .. }));
+        assert!(matches!(run("(define (f) (defmacro m () 1))").unwrap_err(), EvalError::BadSyntax { form: "defmacro", .. }));
+    }
+
+    #[test]
+    fn test_syntax_rules() {
+        let program = "
+            (define-syntax my-or
+              (syntax-rules ()
+                ((_) #f)
+                ((_ first rest ...) (let ((tmp first)) (if tmp tmp (my-or rest ...))))))
+            (define tmp 5)
+            (list (my-or) (my-or #f 2) (my-or #f tmp))";
+        assert_eq!(run(program).unwrap(), "(#f 2 5)");
+
+        let program = "
+            (define-syntax my-let
+              (syntax-rules ()
+                ((_ ((name value) ...) body1 body2 ...) ((lambda (name ...) body1 body2 ...) value ...))))
+            (my-let ((a 1) (b 2)) (+ a b))";
+        assert_eq!(run(program).unwrap(), "3");
+
+        let program = "
+            (define-syntax my-cond
+              (syntax-rules (else)
+                ((_ (else result)) result)
+                ((_ (test result) clause ...) (if test result (my-cond clause ...)))))
+            (list (my-cond (#f 1) (else 2)) (my-cond (#t 1) (else 2)))";
+        assert_eq!(run(program).unwrap(), "(2 1)");
+
+        let program = "
+            (define-syntax ends
+              (syntax-rules ()
+                ((_ first middle ... last) '(first last))
+                ((_ head . tail) 'tail)))
+            (list (ends 1 2 3 4) (ends 1))";
+        assert_eq!(run(program).unwrap(), "((1 4) ())");
+
+        let program = "(define-syntax two (syntax-rules () ((_ a b) (list a b)))) (two 1)";
+        assert!(matches!(run(program).unwrap_err(), EvalError::BadSyntax { form: "syntax-rules", .. }));
+        assert!(matches!(run("(define-syntax bad (rules))").unwrap_err(), EvalError::BadSyntax { form: "syntax-rules", .. }));
+    }
+
+    #[test]
+    fn test_hygiene() {
+        let program = "
+            (define-syntax swap!
+              (syntax-rules ()
+                ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+            (define tmp 1)
+            (define other 2)
+            (swap! tmp other)
+            (list tmp other)";
+        assert_eq!(run(program).unwrap(), "(2 1)");
+
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("(define-syntax capture (syntax-rules () ((_ body) (let ((x 1)) body))))").unwrap();
+        let error = interpreter.eval_str("(capture x)").unwrap_err();
+        assert_eq!(error.to_string(), "unbound variable `x`");
+        assert_eq!(interpreter.eval_str("(define x 'outer) (capture x)").unwrap().to_string(), "outer");
+
+        // A variable can't be seen by its own value in a `let`, so the second `list` is the procedure
+        let program = "(define-syntax m (syntax-rules () ((_) (let ((list (list 1 2))) list)))) (m)";
+        assert_eq!(run(program).unwrap(), "(1 2)");
+        let program = "(define-syntax m (syntax-rules () ((_) (let* ((x 1) (y (+ x 1))) (list x y))))) (m)";
+        assert_eq!(run(program).unwrap(), "(1 2)");
+        let program = "
+            (define-syntax m
+              (syntax-rules ()
+                ((_ n) (letrec ((even? (lambda (k) (if (= k 0) #t (odd? (- k 1)))))
+                                (odd? (lambda (k) (if (= k 0) #f (even? (- k 1))))))
+                         (even? n)))))
+            (define (even? n) 'outer)
+            (list (m 10) (even? 3))";
+        assert_eq!(run(program).unwrap(), "(#t outer)");
+        let program = "
+            (define-syntax m (syntax-rules () ((_ x) (begin (define (helper) (* x 2)) (helper)))))
+            (define (helper) 'outer)
+            (list ((lambda () (m 21))) (helper))";
+        assert_eq!(run(program).unwrap(), "(42 outer)");
+
+        // Names that the template uses but doesn't make are looked up where the macro was defined
+        let program = "
+            (define-syntax my-list (syntax-rules () ((_ x) (list x))))
+            (let ((list (lambda (a) 'captured))) (my-list 1))";
+        assert_eq!(run(program).unwrap(), "(1)");
+        assert_eq!(Vm::new().eval_str(program).unwrap().to_string(), "(1)");
+        let program = "
+            (define count 0)
+            (define-syntax bump! (syntax-rules () ((_) (set! count (+ count 1)))))
+            (define-syntax my-or (syntax-rules () ((_) #f) ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))
+            (define (f count + t) (bump!) (bump!) (my-or #f t))
+            (list (f 10 - 'mine) count)";
+        assert_eq!(run(program).unwrap(), "(mine 2)");
+        assert_eq!(Vm::new().eval_str(program).unwrap().to_string(), "(mine 2)");
+
+        // Names in quoted parts of the template are still just names
+        let program = "
+            (define-syntax names (syntax-rules () ((_) (list 'list `(cons ,'car)))))
+            (define (f list) (names))
+            (define result (f 1))
+            (list result (eq? (car result) 'list) (eq? (car (car (cdr result))) 'cons))";
+        assert_eq!(run(program).unwrap(), "((list (cons car)) #t #t)");
+
+        // Literals match even when another macro's template passes them on, and a template can define a global
+        let program = "
+            (define-syntax arrow (syntax-rules (=>) ((_ a => b) (list a b)) ((_ . rest) 'no-arrow)))
+            (define-syntax call-arrow (syntax-rules () ((_ a b) (arrow a => b))))
+            (define-syntax define-one (syntax-rules () ((_) (define one 1))))
+            (define-one)
+            (call-arrow one 2)";
+        assert_eq!(run(program).unwrap(), "(1 2)");
+    }
+
+    #[test]
+    fn test_macroexpand() {
+        let program = "
+            (defmacro unless (condition . body) `(if ,condition #f (begin ,@body)))
+            (defmacro unless-zero (n . body) `(unless (= ,n 0) ,@body))";
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str(program).unwrap();
+        let expand = |interpreter: &mut Interpreter, code| interpreter.eval_str(code).unwrap().to_string();
+        assert_eq!(expand(&mut interpreter, "(macroexpand-1 '(unless-zero x (f x)))"), "(unless (= x 0) (f x))");
+        assert_eq!(expand(&mut interpreter, "(macroexpand '(unless-zero x (f x)))"), "(if (= x 0) #f (begin (f x)))");
+        assert_eq!(expand(&mut interpreter, "(macroexpand '(+ 1 2))"), "(+ 1 2)");
+        assert_eq!(expand(&mut interpreter, "(macroexpand-1 '`(a ,b))"), "(cons (quote a) (cons b (quote ())))");
+        assert!(matches!(interpreter.eval_str("(macroexpand 1)").unwrap_err(), EvalError::BadSyntax { form: "macroexpand", .. }));
+    }
+
+    #[test]
+    fn test_errors_point_at_the_use_of_the_macro() {
+        let input = "(defmacro broken () '(car 1))\n(define x 1)\n(broken)";
+        let error = run(input).unwrap_err();
+        assert!(matches!(error, EvalError::TypeMismatch { .. }));
+        assert_eq!(&input[error.span().start..error.span().end], "(broken)");
+
+        let input = "(define-syntax call (syntax-rules () ((_ f arg) (begin (f arg)))))\n(call car 1)";
+        let error = run(input).unwrap_err();
+        assert_eq!(&input[error.span().start..error.span().end], "(call car 1)");
+
+        // Code passed in to a `syntax-rules` macro keeps its own spans
+        let input = "(define-syntax call (syntax-rules () ((_ f arg) (begin (f arg)))))\n(call car (+ 1 #t))";
+        let error = run(input).unwrap_err();
+        assert_eq!(&input[error.span().start..error.span().end], "(+ 1 #t)");
+    }
+
+    #[test]
+    fn test_macros_on_the_vm() {
+        let program = "
+            (defmacro unless (condition . body) `(if ,condition #f (begin ,@body)))
+            (define-syntax swap!
+              (syntax-rules ()
+                ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+            (define (f tmp other) (swap! tmp other) (unless #f (list tmp other)))
+            (f 1 2)";
+        assert_eq!(Vm::new().eval_str(program).unwrap().to_string(), "(2 1)");
+        assert_eq!(run(program).unwrap(), "(2 1)");
+
+        // Macros can use anything that the program defines before them
+        let program = "
+            (define (wrap name) (list 'quote name))
+            (define twice (lambda (x) (list 'begin x x)))
+            (defmacro q (x) (wrap x))
+            (defmacro do-twice (x) (twice x))
+            (define n 0)
+            (do-twice (set! n (+ n 1)))
+            (list (q hello) n)";
+        assert_eq!(Vm::new().eval_str(program).unwrap().to_string(), "(hello 2)");
+        assert_eq!(run(program).unwrap(), "(hello 2)");
+
+        let program = "(define name 'hello) (defmacro q () (list 'quote name)) (q)";
+        assert_eq!(run(program).unwrap(), "hello");
+        assert_eq!(Vm::new().eval_str(program).unwrap().to_string(), "hello");
+
+        // and each definition is only run once
+        let program = "
+            (define n 0)
+            (define (next!) (set! n (+ n 1)) n)
+            (define first (next!))
+            (defmacro at-expansion () (next!))
+            (list first (at-expansion) n)";
+        assert_eq!(run(program).unwrap(), "(1 2 2)");
+        assert_eq!(Vm::new().eval_str(program).unwrap().to_string(), "(1 2 2)");
+
+        // A macro defined on one machine is expanded with that machine's procedures
+        let mut vm = Vm::new();
+        vm.eval_str("(defmacro m (x) `(quote ,(car x)))").unwrap();
+        assert!(matches!(vm.eval_str("(m 1)").unwrap_err(), EvalError::TypeMismatch { .. }));
+        assert_eq!(vm.eval_str("(m (a b))").unwrap().to_string(), "a");
+    }
+}
@@ -0,0 +1,420 @@
+//! A Parser that turns the tokens of the little lisp into a tree
+//!
+//! Lisp code is made of "s-expressions". An s-expression is either an atom,
+//! like a number or a name, or a list of more s-expressions in parentheses.
+//! That makes every program a tree:
+//!
+//! ```text
+//! (define (square x) (* x x))
+//!
+//!          List
+//!   /       |        \
+//! define   List       List
+//!         /   \     /  |  \
+//!    square    x   *   x   x
+//! ```
+//!
+//! The `Lexer` has already done the hard work of finding the tokens, so all
+//! the parser has to do is match up each `(` with its `)`.
+//!
+//! The parser also expands the quote-like prefixes, which are shorthand
+//! for some common lists:
+//!
+//! ```text
+//! 'x   =>  (quote x)
+//! `x   =>  (quasiquote x)
+//! ,x   =>  (unquote x)
+//! ,@x  =>  (unquote-splicing x)
+//! ```
+
+use crate::lexer::{LexError, Lexer, Number, Span, Token, TokenType};
+use crate::symbol::Symbol;
+use std::fmt;
+
+/// One node of the tree, along with the part of the input it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    /// For a list, this covers everything from the `(` to the `)`
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    /// A parenthesized list such as `(+ 1 2)`
+    List(Vec<Expr>),
+    /// A name such as `define` or `+`
+    Symbol(Symbol),
+    Number(Number),
+    /// A string, with its escape sequences already replaced
+    Str(String),
+    Bool(bool),
+}
+
+/// Dropping a list drops its items, so dropping a deeply nested tree the
+/// usual way would recurse once for every level. Instead, the lists are
+/// taken apart onto a stack of our own, like the parser builds them.
+impl Drop for Expr {
+    fn drop(&mut self) {
+        let mut unfinished = match &mut self.kind {
+            ExprKind::List(items) => std::mem::take(items),
+            _ => return,
+        };
+        while let Some(mut expr) = unfinished.pop() {
+            if let ExprKind::List(items) = &mut expr.kind {
+                unfinished.append(items);
+            }
+        }
+    }
+}
+
+/// Displays the expression as lisp code, which parses back into the same tree
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExprKind::List(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+            ExprKind::Symbol(name) => write!(f, "{}", name),
+            ExprKind::Number(number) => write!(f, "{}", number),
+            ExprKind::Str(string) => write_string_literal(f, string),
+            ExprKind::Bool(true) => write!(f, "#t"),
+            ExprKind::Bool(false) => write!(f, "#f"),
+        }
+    }
+}
+
+/// Writes a string in double quotes, escaping anything the lexer would not read back as itself
+pub(crate) fn write_string_literal(f: &mut fmt::Formatter<'_>, string: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for ch in string.chars() {
+        match ch {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            ch => write!(f, "{}", ch)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Everything that can go wrong while parsing
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// The lexer could not make sense of the input
+    Lex(LexError),
+    /// The input ended while a list was still open. The span is the `(`
+    /// that was never closed.
+    UnclosedParen { open: Span },
+    /// A `)` that does not close any list
+    UnexpectedCloseParen { span: Span },
+    /// A quote-like prefix such as `'` with no expression after it
+    MissingQuotedExpr { prefix: Span },
+}
+
+impl ParseError {
+    /// The part of the input that this error is about
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::Lex(error) => error.span(),
+            ParseError::UnclosedParen { open } => *open,
+            ParseError::UnexpectedCloseParen { span } => *span,
+            ParseError::MissingQuotedExpr { prefix } => *prefix,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Lex(error) => write!(f, "{}", error),
+            ParseError::UnclosedParen { .. } => write!(f, "this `(` is never closed"),
+            ParseError::UnexpectedCloseParen { .. } => write!(f, "unexpected `)` with no matching `(`"),
+            ParseError::MissingQuotedExpr { .. } => write!(f, "expected an expression to quote"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<LexError> for ParseError {
+    fn from(error: LexError) -> ParseError {
+        ParseError::Lex(error)
+    }
+}
+
+/// A Parser that reads top-level expressions from a stream of tokens
+///
+/// Each call to `next` returns one whole top-level expression, such as a
+/// `(define ...)`. That means the parser can start handing out expressions
+/// before it has seen the end of the input. After an error, the parser
+/// skips the rest of the top-level expression that the error was in, and
+/// carries on with the one after it.
+///
+/// The tokens can come from any iterator, but most of the time that will
+/// be a `Lexer`. Comment and whitespace tokens are skipped, so a lexer in
+/// `lossless` mode works too.
+pub struct Parser<I> {
+    tokens: I,
+}
+
+impl<'a, I> Parser<I>
+where
+    I: Iterator<Item = Result<Token<'a>, LexError>>,
+{
+    pub fn new(tokens: I) -> Parser<I> {
+        Parser { tokens }
+    }
+
+    /// Skips tokens until every list that is still open has been closed, so
+    /// that the rest of a broken list isn't read as more top-level expressions
+    fn skip_open_lists(&mut self, frames: &[Frame]) {
+        let mut depth = frames.iter().filter(|frame| matches!(frame, Frame::List { .. })).count();
+        while depth > 0 {
+            match self.tokens.next().map(|token| token.map(|token| token.token)) {
+                Some(Ok(TokenType::LeftParen)) => depth += 1,
+                Some(Ok(TokenType::RightParen)) => depth -= 1,
+                Some(_) => (),
+                None => break,
+            }
+        }
+    }
+}
+
+impl<'a, I> Iterator for Parser<I>
+where
+    I: Iterator<Item = Result<Token<'a>, LexError>>,
+{
+    type Item = Result<Expr, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Rather than calling ourselves recursively for each nested list, we
+        // keep our own stack of the things that are still unfinished. This
+        // way, even very deeply nested input can't overflow the real stack.
+        let mut frames: Vec<Frame> = Vec::new();
+
+        loop {
+            let token = match self.tokens.next() {
+                Some(Ok(token)) => token,
+                Some(Err(error)) => {
+                    self.skip_open_lists(&frames);
+                    return Some(Err(error.into()));
+                }
+                // The innermost unfinished thing is the most likely culprit
+                None => return frames.pop().map(|frame| Err(frame.unfinished())),
+            };
+
+            let mut expr = match token.token {
+                TokenType::LeftParen => {
+                    frames.push(Frame::List { open: token.span, items: Vec::new() });
+                    continue;
+                }
+                TokenType::RightParen => match frames.pop() {
+                    Some(Frame::List { open, items }) => {
+                        Expr { kind: ExprKind::List(items), span: open.to(token.span) }
+                    }
+                    Some(frame) => {
+                        // The `)` closes the list that the prefix was in
+                        if let Some(list) = frames.iter().rposition(|frame| matches!(frame, Frame::List { .. })) {
+                            frames.remove(list);
+                        }
+                        self.skip_open_lists(&frames);
+                        return Some(Err(frame.unfinished()));
+                    }
+                    None => return Some(Err(ParseError::UnexpectedCloseParen { span: token.span })),
+                },
+                TokenType::Quote | TokenType::Quasiquote | TokenType::Unquote | TokenType::UnquoteSplicing => {
+                    let name = token.token.quote_name().unwrap_or("quote");
+                    frames.push(Frame::Prefix { prefix: token.span, name });
+                    continue;
+                }
+                TokenType::Comment(_) | TokenType::Whitespace => continue,
+                TokenType::Error(error) => {
+                    self.skip_open_lists(&frames);
+                    return Some(Err(error.into()));
+                }
+                TokenType::Identifier(ident) => Expr { kind: ExprKind::Symbol(ident.symbol()), span: token.span },
+                TokenType::Number(number) => Expr { kind: ExprKind::Number(number), span: token.span },
+                TokenType::Str(string) => Expr { kind: ExprKind::Str(string.value.into_owned()), span: token.span },
+                TokenType::Boolean(boolean) => Expr { kind: ExprKind::Bool(boolean), span: token.span },
+            };
+
+            // Wrap the finished expression in any prefixes that were waiting
+            // for it, then add it to the list it belongs in
+            loop {
+                match frames.pop() {
+                    Some(Frame::Prefix { prefix, name }) => {
+                        let symbol = Expr { kind: ExprKind::Symbol(Symbol::intern(name)), span: prefix };
+                        let span = prefix.to(expr.span);
+                        expr = Expr { kind: ExprKind::List(vec![symbol, expr]), span };
+                    }
+                    Some(Frame::List { open, mut items }) => {
+                        items.push(expr);
+                        frames.push(Frame::List { open, items });
+                        break;
+                    }
+                    None => return Some(Ok(expr)),
+                }
+            }
+        }
+    }
+}
+
+/// Something the parser has started reading but not finished yet
+enum Frame {
+    /// A list whose `(` has been read, along with the items read so far
+    List { open: Span, items: Vec<Expr> },
+    /// A quote-like prefix such as `'`, waiting for the expression after it
+    Prefix { prefix: Span, name: &'static str },
+}
+
+impl Frame {
+    /// The error to report when the input stops before this is finished
+    fn unfinished(self) -> ParseError {
+        match self {
+            Frame::List { open, .. } => ParseError::UnclosedParen { open },
+            Frame::Prefix { prefix, .. } => ParseError::MissingQuotedExpr { prefix },
+        }
+    }
+}
+
+/// Checks whether the input stops in the middle of an expression
+///
+/// This is true when a list, string or block comment is still open, or
+/// when the input ends with a quote prefix. It lets an interactive prompt
+/// keep reading lines until the user has finished typing an expression.
+/// Other mistakes, like a stray `)`, are left for the parser to report.
+pub fn needs_more_input(input: &str) -> bool {
+    let mut depth = 0usize;
+    let mut waiting_for_quoted = false;
+    for token in Lexer::new(input) {
+        let token = match token {
+            Ok(token) => token,
+            Err(LexError::UnterminatedString { .. }) | Err(LexError::UnterminatedComment { .. }) => return true,
+            Err(LexError::MissingDatum { span }) if input[span.end..].trim().is_empty() => return true,
+            Err(_) => continue,
+        };
+        match token.token {
+            TokenType::LeftParen => depth += 1,
+            TokenType::RightParen => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+        waiting_for_quoted = token.token.quote_name().is_some();
+    }
+    depth > 0 || waiting_for_quoted
+}
+
+/// Parses every top-level expression in the input, stopping at the first error
+pub fn parse(input: &str) -> Result<Vec<Expr>, ParseError> {
+    Parser::new(Lexer::new(input)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nested_lists() {
+        let input = "(define (square x) (* x x))\n(square -1.5) \"hi\\n\" #t";
+        let exprs = parse(input).unwrap();
+        let printed: Vec<_> = exprs.iter().map(|expr| expr.to_string()).collect();
+        assert_eq!(printed, vec!["(define (square x) (* x x))", "(square -1.5)", "\"hi\\n\"", "#t"]);
+
+        // Every node's span covers exactly its own text
+        let define = &exprs[0];
+        assert_eq!(&input[define.span.start..define.span.end], "(define (square x) (* x x))");
+        match &define.kind {
+            ExprKind::List(items) => {
+                assert_eq!(items.len(), 3);
+                assert_eq!(&input[items[1].span.start..items[1].span.end], "(square x)");
+                assert_eq!(items[0].kind, ExprKind::Symbol(Symbol::DEFINE));
+            }
+            other => panic!("expected a list, got {:?}", other),
+        }
+        assert_eq!(exprs[1].span.start_pos.line, 2);
+    }
+
+    #[test]
+    fn test_unbalanced_parens() {
+        let error = parse("(define (f x)\n  (g x)").unwrap_err();
+        assert_eq!(error, ParseError::UnclosedParen { open: Lexer::new("(").next().unwrap().unwrap().span });
+
+        let error = parse("(a (b c) (d e").unwrap_err();
+        assert_eq!(error.span().start, 9);
+
+        let error = parse("(a b))").unwrap_err();
+        assert!(matches!(error, ParseError::UnexpectedCloseParen { .. }));
+        assert_eq!(error.span().start, 5);
+
+        let error = parse("(a 0x)").unwrap_err();
+        assert!(matches!(error, ParseError::Lex(LexError::InvalidNumber { .. })));
+    }
+
+    #[test]
+    fn test_recovery() {
+        // After an error, the rest of its top-level expression is skipped
+        let results: Vec<_> = Parser::new(Lexer::new("(a 0x b) (c (d 1x) e) (') 'f (g")).collect();
+        assert_eq!(results.len(), 5, "{:?}", results);
+        assert!(matches!(results[0], Err(ParseError::Lex(LexError::InvalidNumber { .. }))));
+        assert!(matches!(results[1], Err(ParseError::Lex(LexError::InvalidNumber { .. }))));
+        assert!(matches!(results[2], Err(ParseError::MissingQuotedExpr { .. })));
+        assert_eq!(results[3].as_ref().unwrap().to_string(), "(quote f)");
+        assert!(matches!(results[4], Err(ParseError::UnclosedParen { .. })));
+
+        let results: Vec<_> = Parser::new(Lexer::new("(a 0x").lossless().recovering().map(Ok)).collect();
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        let depth = 100_000;
+        let input = format!("{}{}", "(".repeat(depth), ")".repeat(depth));
+        let exprs = parse(&input).unwrap();
+        assert_eq!(exprs.len(), 1);
+        drop(exprs);
+    }
+
+    #[test]
+    fn test_quote_prefixes() {
+        let input = "'a `(b ,c ,@(d e)) ''f";
+        let exprs = parse(input).unwrap();
+        let printed: Vec<_> = exprs.iter().map(|expr| expr.to_string()).collect();
+        assert_eq!(printed, vec![
+            "(quote a)",
+            "(quasiquote (b (unquote c) (unquote-splicing (d e))))",
+            "(quote (quote f))",
+        ]);
+
+        // The span of the expansion covers both the prefix and the expression
+        let spans: Vec<_> = exprs.iter().map(|expr| &input[expr.span.start..expr.span.end]).collect();
+        assert_eq!(spans, vec!["'a", "`(b ,c ,@(d e))", "''f"]);
+        match &exprs[1].kind {
+            ExprKind::List(items) => assert_eq!(&input[items[0].span.start..items[0].span.end], "`"),
+            other => panic!("expected a list, got {:?}", other),
+        }
+
+        for input in &["(a ')", "'", "(a ,@"] {
+            let error = parse(input).unwrap_err();
+            assert!(matches!(error, ParseError::MissingQuotedExpr { .. }), "{}: {:?}", input, error);
+        }
+    }
+
+    #[test]
+    fn test_needs_more_input() {
+        for input in &["(define (f x)", "(a \"b)", "#| (a) ", "'", "(a) `", "#;"] {
+            assert!(needs_more_input(input), "{} is not finished", input);
+        }
+        for input in &["", "(a b)", "(a \"(\")", "; (", "(a))", "x"] {
+            assert!(!needs_more_input(input), "{} is finished", input);
+        }
+    }
+}
@@ -0,0 +1,395 @@
+//! Lexing input that is too big to hold in memory all at once
+//!
+//! A `Lexer` borrows the whole input as one `&str`. That is the simplest
+//! and fastest way to lex, but it means reading an entire file into memory
+//! first. A `StreamLexer` instead pulls its input from any `BufRead` one
+//! chunk at a time, and only keeps hold of the bit that it hasn't turned
+//! into tokens yet.
+//!
+//! The tricky part is that a chunk can end anywhere: in the middle of a
+//! number, a string, or even in the middle of a multi-byte character. A
+//! token is only returned once we are sure that the next chunk can't change
+//! it, and everything else waits in the buffer for more input.
+
+use crate::lexer::{self, CommentKind, LexError, Lexer, OwnedToken, Position, Token, TokenType};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead};
+
+/// Everything that can go wrong while lexing a stream
+#[derive(Debug)]
+pub enum StreamError {
+    /// The input could not be read, or it was not valid UTF-8
+    Io(io::Error),
+    Lex(LexError),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::Io(error) => write!(f, "could not read input: {}", error),
+            StreamError::Lex(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StreamError::Io(error) => Some(error),
+            StreamError::Lex(error) => Some(error),
+        }
+    }
+}
+
+impl From<io::Error> for StreamError {
+    fn from(error: io::Error) -> StreamError {
+        StreamError::Io(error)
+    }
+}
+
+impl From<LexError> for StreamError {
+    fn from(error: LexError) -> StreamError {
+        StreamError::Lex(error)
+    }
+}
+
+/// A lexer that reads its input bit by bit from a `BufRead`
+///
+/// It returns the same tokens, with the same spans, as a `Lexer` would for
+/// the whole input. Like the `Lexer`, it carries on after an error.
+///
+/// ```
+/// use csh_seminar_feb_2021::stream::StreamLexer;
+///
+/// let input = std::io::BufReader::with_capacity(2, "(+ 12 34)".as_bytes());
+/// let tokens: Vec<_> = StreamLexer::new(input).map(|token| token.unwrap().source).collect();
+/// assert_eq!(tokens, vec!["(", "+", "12", "34", ")"]);
+/// ```
+pub struct StreamLexer<R> {
+    reader: R,
+    /// Input that has been read but not turned into tokens yet
+    ///
+    /// After lexing, this only holds the start of a token that might carry
+    /// on into input we haven't read yet.
+    buffer: String,
+    /// Bytes at the end of the last read that are only part of a character
+    partial: Vec<u8>,
+    /// Where `buffer` starts in the whole input, as a byte offset
+    offset: usize,
+    /// Where `buffer` starts in the whole input, as a line and column
+    position: Position,
+    /// What the token at the start of `buffer` needs before it could be
+    /// finished, so that a long string isn't lexed again for every chunk of it
+    until: Option<Until>,
+    /// Tokens that have been lexed but not returned yet
+    ready: VecDeque<Result<OwnedToken, LexError>>,
+    /// Whether we have read everything there is to read
+    finished: bool,
+    keep_comments: bool,
+    keep_whitespace: bool,
+}
+
+impl<R: BufRead> StreamLexer<R> {
+    pub fn new(reader: R) -> StreamLexer<R> {
+        StreamLexer {
+            reader,
+            buffer: String::new(),
+            partial: Vec::new(),
+            offset: 0,
+            position: Position::START,
+            until: None,
+            ready: VecDeque::new(),
+            finished: false,
+            keep_comments: false,
+            keep_whitespace: false,
+        }
+    }
+
+    /// Makes this lexer return comments as tokens, like `Lexer::with_comments`
+    pub fn with_comments(mut self) -> StreamLexer<R> {
+        self.keep_comments = true;
+        self
+    }
+
+    /// Makes this lexer return comments and whitespace as tokens, like `Lexer::lossless`
+    pub fn lossless(mut self) -> StreamLexer<R> {
+        self.keep_comments = true;
+        self.keep_whitespace = true;
+        self
+    }
+
+    /// Reads the next chunk of input onto the end of the buffer
+    fn refill(&mut self) -> io::Result<()> {
+        let chunk = self.reader.fill_buf()?;
+        if chunk.is_empty() {
+            self.finished = true;
+            if !self.partial.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "input ends in the middle of a character"));
+            }
+            return Ok(());
+        }
+        let length = chunk.len();
+        self.partial.extend_from_slice(chunk);
+        self.reader.consume(length);
+
+        // A character may be cut in half by the end of the chunk, in which
+        // case the first half waits in `partial` for the rest of it
+        let valid = match std::str::from_utf8(&self.partial) {
+            Ok(text) => text.len(),
+            Err(error) if error.error_len().is_none() => error.valid_up_to(),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "input is not valid UTF-8")),
+        };
+        if let Ok(text) = std::str::from_utf8(&self.partial[..valid]) {
+            self.buffer.push_str(text);
+        }
+        self.partial.drain(..valid);
+        Ok(())
+    }
+
+    /// Lexes every token in the buffer that is sure to be finished
+    ///
+    /// A token that runs right up to the end of the buffer could carry on in
+    /// the next chunk, like `12` followed by `34`, or `,` followed by `@`.
+    /// It stays in the buffer until there is more input, or until we know
+    /// that there isn't any. Every other token ends at something that we
+    /// have already read, like a delimiter or a closing quote.
+    fn lex_buffer(&mut self) {
+        let mut lexed = 0;
+        self.until = None;
+        for token in Lexer::new(&self.buffer).lossless().recovering() {
+            if token.span.end == self.buffer.len() && !self.finished {
+                self.until = Some(Until::end_of(&token.token));
+                break;
+            }
+            lexed = token.span.end;
+            let token = match token.token {
+                TokenType::Whitespace if !self.keep_whitespace => continue,
+                TokenType::Comment(_) if !self.keep_comments => continue,
+                TokenType::Error(error) => Err(error.rebase(self.offset, self.position)),
+                _ => Ok(Token {
+                    span: token.span.rebase(self.offset, self.position),
+                    ..token.into_owned()
+                }),
+            };
+            self.ready.push_back(token);
+        }
+
+        self.position = self.position.advance(&self.buffer[..lexed]);
+        self.offset += lexed;
+        self.buffer.drain(..lexed);
+    }
+}
+
+/// What has to turn up in the input before an unfinished token could end
+#[derive(Debug, Clone, Copy)]
+enum Until {
+    /// The `"` at the end of a string, or the `#` at the end of a block comment
+    Char(char),
+    /// The end of a line comment
+    Newline,
+    /// Something other than whitespace, at the end of a run of whitespace
+    NotWhitespace,
+    /// A delimiter, like the space or paren after a name or a number
+    Delimiter,
+    /// Anything at all, for the few tokens that can't be sure from their own text
+    Anything,
+}
+
+impl Until {
+    fn end_of(token: &TokenType<'_>) -> Until {
+        match token {
+            TokenType::Error(LexError::UnterminatedString { .. }) => Until::Char('"'),
+            TokenType::Error(LexError::UnterminatedComment { .. }) => Until::Char('#'),
+            TokenType::Comment(comment) if comment.kind == CommentKind::Line => Until::Newline,
+            TokenType::Comment(comment) if comment.kind == CommentKind::Block => Until::Anything,
+            // A bad escape is only reported once the string's closing quote has been found
+            TokenType::Str(_) | TokenType::Error(LexError::InvalidEscape { .. }) => Until::Anything,
+            TokenType::Whitespace => Until::NotWhitespace,
+            _ => Until::Delimiter,
+        }
+    }
+
+    fn found_in(self, text: &str) -> bool {
+        match self {
+            Until::Char(end) => text.contains(end),
+            Until::Newline => text.contains('\n'),
+            Until::NotWhitespace => text.contains(|ch: char| !ch.is_whitespace()),
+            Until::Delimiter => text.contains(lexer::is_delimiter),
+            Until::Anything => !text.is_empty(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for StreamLexer<R> {
+    type Item = Result<OwnedToken, StreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(token) = self.ready.pop_front() {
+                return Some(token.map_err(StreamError::Lex));
+            }
+            if self.finished {
+                return None;
+            }
+            let read = self.buffer.len();
+            if let Err(error) = self.refill() {
+                // There is no telling what the rest of the input holds
+                self.finished = true;
+                self.buffer.clear();
+                return Some(Err(error.into()));
+            }
+            let could_finish = match self.until {
+                Some(until) => until.found_in(&self.buffer[read..]),
+                None => true,
+            };
+            if self.finished || could_finish {
+                self.lex_buffer();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Read};
+
+    /// Lexes the input a few bytes at a time, and all at once, and checks
+    /// that both ways give the same tokens and errors
+    fn assert_same_as_lexer(input: &str) {
+        let expected: Vec<_> = Lexer::new(input)
+            .lossless()
+            .map(|token| token.map(Token::into_owned))
+            .collect();
+        for capacity in 1..8 {
+            let reader = BufReader::with_capacity(capacity, input.as_bytes());
+            let streamed: Vec<_> = StreamLexer::new(reader)
+                .lossless()
+                .map(|token| match token {
+                    Ok(token) => Ok(token),
+                    Err(StreamError::Lex(error)) => Err(error),
+                    Err(error) => panic!("{}", error),
+                })
+                .collect();
+            assert_eq!(streamed, expected, "reading {} bytes at a time", capacity);
+        }
+    }
+
+    #[test]
+    fn test_tokens_split_across_chunks() {
+        assert_same_as_lexer("(define (größe x)\r\n  (* x 12345.5e3)) ; 日本語\n");
+        assert_same_as_lexer("\"a long string\\n with \\u{1F980} escapes\" ,@(a) #| nested #| block |# |#");
+        assert_same_as_lexer("#; (commented out\n (datum)) 'kept   \n\n");
+        assert_same_as_lexer("(bad 1x \"\\q\" 🦀) #; ) \"never closed");
+        assert_same_as_lexer("");
+    }
+
+    #[test]
+    fn test_token_types() {
+        let reader = BufReader::with_capacity(3, "(a \"b\\n\" 1.5)".as_bytes());
+        let tokens: Vec<_> = StreamLexer::new(reader).map(|token| token.unwrap()).collect();
+        assert_eq!(tokens[1].token, TokenType::Identifier(crate::lexer::Ident("a".into())));
+        match &tokens[2].token {
+            TokenType::Str(string) => assert_eq!(string.value, "b\n"),
+            other => panic!("expected a string, found {:?}", other),
+        }
+        assert_eq!(tokens[3].token, TokenType::Number(crate::lexer::Number::Float(1.5)));
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let reader = BufReader::with_capacity(2, &b"(a \xff)"[..]);
+        let tokens: Vec<_> = StreamLexer::new(reader).collect();
+        assert!(matches!(tokens.last(), Some(Err(StreamError::Io(_)))));
+
+        let reader = BufReader::with_capacity(2, &b"(a \xe6\x97"[..]);
+        let tokens: Vec<_> = StreamLexer::new(reader).collect();
+        assert!(matches!(tokens.last(), Some(Err(StreamError::Io(_)))));
+    }
+
+    /// A reader that produces the same text over and over
+    struct Repeat {
+        text: &'static [u8],
+        times: usize,
+        offset: usize,
+    }
+
+    impl Read for Repeat {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.times == 0 {
+                return Ok(0);
+            }
+            let rest = &self.text[self.offset..];
+            let length = rest.len().min(buf.len());
+            buf[..length].copy_from_slice(&rest[..length]);
+            self.offset += length;
+            if self.offset == self.text.len() {
+                self.offset = 0;
+                self.times -= 1;
+            }
+            Ok(length)
+        }
+    }
+
+    #[test]
+    fn test_bounded_memory() {
+        let text = b"(define (f x) \"some string\" 12345) ; a comment\n";
+        let times = 20_000;
+        let reader = BufReader::with_capacity(64, Repeat { text, times, offset: 0 });
+        let mut lexer = StreamLexer::new(reader);
+
+        let mut count = 0;
+        let mut largest_buffer = 0;
+        let mut last = None;
+        while let Some(token) = lexer.next() {
+            count += 1;
+            largest_buffer = largest_buffer.max(lexer.buffer.len());
+            last = Some(token.unwrap());
+        }
+        assert_eq!(count, 9 * times);
+        assert!(largest_buffer < 128, "buffer grew to {} bytes", largest_buffer);
+
+        let last = last.unwrap();
+        assert_eq!(last.span.end, text.len() * times - "; a comment\n".len() - 1);
+        assert_eq!(last.span.start_pos, Position { line: times, column: 34 });
+    }
+
+    #[test]
+    fn test_bad_escape_at_the_end_of_a_chunk() {
+        // The string with the bad escape fills the first chunk exactly
+        let text = b"a ";
+        let times = 20_000;
+        let reader = BufReader::with_capacity(4, "\"\\q\"".as_bytes().chain(Repeat { text, times, offset: 0 }));
+        let mut lexer = StreamLexer::new(reader);
+
+        assert!(matches!(lexer.next(), Some(Err(StreamError::Lex(LexError::InvalidEscape { .. })))));
+        assert!(!lexer.finished, "the string was held back until the end of the input");
+        let mut count = 0;
+        let mut largest_buffer = 0;
+        while let Some(token) = lexer.next() {
+            token.unwrap();
+            count += 1;
+            largest_buffer = largest_buffer.max(lexer.buffer.len());
+        }
+        assert_eq!(count, times);
+        assert!(largest_buffer < 16, "buffer grew to {} bytes", largest_buffer);
+    }
+
+    #[test]
+    fn test_long_tokens() {
+        // Lexing these again for every 16 bytes would take far too long
+        let long = "a string with spaces ".repeat(100_000);
+        for input in [format!("(\"{}\")", long), format!("; {}\n", long), format!("#| {} |#", long)] {
+            let reader = BufReader::with_capacity(16, input.as_bytes());
+            let tokens: Vec<_> = StreamLexer::new(reader).lossless().map(|token| token.unwrap()).collect();
+            assert_eq!(tokens.iter().map(|token| token.source.len()).sum::<usize>(), input.len());
+            assert!(tokens.len() <= 3);
+        }
+        let input = format!("(a{}{}b)", long.replace(' ', "-"), " ".repeat(1 << 20));
+        let reader = BufReader::with_capacity(16, input.as_bytes());
+        let tokens: Vec<_> = StreamLexer::new(reader).map(|token| token.unwrap().source).collect();
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(tokens[1].len(), long.len() + 1);
+    }
+}
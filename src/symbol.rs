@@ -0,0 +1,386 @@
+//! Interned names, so that comparing two names is as cheap as comparing two numbers
+//!
+//! Programs use the same few names over and over: every call to `+` says
+//! `+`, and every use of a variable says its name. If we kept each name as
+//! a `String`, then looking up a variable would mean comparing strings
+//! letter by letter.
+//!
+//! Instead, the first time we see a name we give it a number, and store the
+//! text in a table. Every later time we see the same name we hand out the
+//! same number. A `Symbol` is just that number, so two symbols are equal
+//! exactly when their numbers are equal:
+//!
+//! ```text
+//! (define (square x) (* x x))
+//!
+//!   "define" -> 2   "square" -> 57   "x" -> 58   "*" -> 59
+//! ```
+//!
+//! There is one table for the whole program. The lexer leaves names as
+//! text, since tools like the formatter never need anything more, and
+//! `Ident::symbol` looks a name up in the table when something asks for it.
+//! The parser does that for every name it reads, so the trees it builds,
+//! and the interpreter and compiler that run them, only ever see symbols.
+//! Names are never removed from the table, so the text of a symbol can be
+//! borrowed for as long as we like.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+
+/// A name, stored as its number in the symbol table
+///
+/// ```
+/// use csh_seminar_feb_2021::symbol::Symbol;
+///
+/// let a = Symbol::intern("hello");
+/// let b = Symbol::intern(&String::from("hello"));
+/// assert_eq!(a, b);
+/// assert_eq!(a.as_str(), "hello");
+/// assert_ne!(a, Symbol::intern("world"));
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+/// The names that are always in the table, in the order of the `Symbol` constants
+const PREDEFINED: &[&str] = &[
+    "quote",
+    "if",
+    "define",
+    "set!",
+    "lambda",
+    "begin",
+    "let",
+    "let*",
+    "letrec",
+    "quasiquote",
+    "unquote",
+    "unquote-splicing",
+    ".",
+    "cond",
+    "else",
+    "defmacro",
+    "define-syntax",
+    "syntax-rules",
+    "...",
+    "_",
+    "macroexpand-1",
+    "macroexpand",
+];
+
+impl Symbol {
+    pub const QUOTE: Symbol = Symbol(0);
+    pub const IF: Symbol = Symbol(1);
+    pub const DEFINE: Symbol = Symbol(2);
+    pub const SET: Symbol = Symbol(3);
+    pub const LAMBDA: Symbol = Symbol(4);
+    pub const BEGIN: Symbol = Symbol(5);
+    pub const LET: Symbol = Symbol(6);
+    pub const LET_STAR: Symbol = Symbol(7);
+    pub const LETREC: Symbol = Symbol(8);
+    pub const QUASIQUOTE: Symbol = Symbol(9);
+    pub const UNQUOTE: Symbol = Symbol(10);
+    pub const UNQUOTE_SPLICING: Symbol = Symbol(11);
+    /// The `.` in a parameter list like `(a . rest)`
+    pub const DOT: Symbol = Symbol(12);
+    pub const COND: Symbol = Symbol(13);
+    pub const ELSE: Symbol = Symbol(14);
+    pub const DEFMACRO: Symbol = Symbol(15);
+    pub const DEFINE_SYNTAX: Symbol = Symbol(16);
+    pub const SYNTAX_RULES: Symbol = Symbol(17);
+    /// The `...` in a `syntax-rules` pattern, which matches any number of the thing before it
+    pub const ELLIPSIS: Symbol = Symbol(18);
+    /// The `_` in a `syntax-rules` pattern, which matches anything
+    pub const UNDERSCORE: Symbol = Symbol(19);
+    pub const MACROEXPAND_1: Symbol = Symbol(20);
+    pub const MACROEXPAND: Symbol = Symbol(21);
+
+    /// The symbol for some text, adding it to the table if it is new
+    pub fn intern(text: &str) -> Symbol {
+        let mut interner = Interner::global().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        interner.intern(text)
+    }
+
+    /// A brand new symbol with the same text as this one, which is not
+    /// equal to any other symbol, not even one interned from the same text
+    ///
+    /// Macros use these to rename the variables they make, so that they can't
+    /// get mixed up with the variables of the code that uses the macro.
+    ///
+    /// A gensym needs a number of its own, so it takes up a slot in the table
+    /// for good, just like an interned name. It shares the text of this
+    /// symbol though, so nothing new is leaked. Keeping the gensym's number
+    /// somewhere other than the table would make every `Symbol` bigger, and
+    /// with it every instruction of the bytecode that names a global.
+    pub fn gensym(self) -> Symbol {
+        let mut interner = Interner::global().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        interner.add(self.as_str(), None)
+    }
+
+    /// A symbol that always means the global variable with this name, even
+    /// where a local variable has the name too
+    ///
+    /// `syntax-rules` gives these to the names that a template uses but
+    /// doesn't make, like the `list` in `(list x)`, so that they mean what
+    /// they meant where the macro was defined. Like a gensym, no local
+    /// variable can ever have one. There is only one alias for each name,
+    /// so expanding a macro over and over doesn't fill up the table.
+    pub fn global_alias(self) -> Symbol {
+        if self.aliased().is_some() {
+            return self;
+        }
+        let mut interner = Interner::global().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(&alias) = interner.aliases.get(&self) {
+            return alias;
+        }
+        let alias = interner.add(self.as_str(), Some(self));
+        interner.aliases.insert(self, alias);
+        alias
+    }
+
+    /// The name that this symbol is the `global_alias` of, if it is one
+    pub fn aliased(self) -> Option<Symbol> {
+        if (self.0 as usize) < PREDEFINED.len() {
+            return None;
+        }
+        Names::global().get(self.0).expect("a symbol's name is in the table before the symbol is handed out").alias_of
+    }
+
+    /// The text of this symbol
+    ///
+    /// This doesn't need the lock, so printing symbols never has to wait for
+    /// another thread that is interning names.
+    pub fn as_str(self) -> &'static str {
+        match PREDEFINED.get(self.0 as usize) {
+            Some(name) => name,
+            None => Names::global().get(self.0).expect("a symbol's name is in the table before the symbol is handed out").text,
+        }
+    }
+
+    /// The number of this symbol in the table
+    pub fn id(self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Symbol({:?})", self.as_str())
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(text: &str) -> Symbol {
+        Symbol::intern(text)
+    }
+}
+
+/// The table that gives every name its number
+///
+/// The text of each name is leaked, so that it lives for the rest of the
+/// program, and `Symbol::as_str` can hand out a `&'static str`. The catch
+/// is that the table only ever grows: the REPL and the language server
+/// keep every name they have ever seen, and every gensym that a macro has
+/// made, even once nothing uses them any more. A program only ever uses so
+/// many names, and uses them over and over, so that is a small price for
+/// never having to check whether a symbol is still alive.
+struct Interner {
+    ids: HashMap<&'static str, Symbol>,
+    /// The `global_alias` of each name that has one
+    aliases: HashMap<Symbol, Symbol>,
+    /// How many symbols have been handed out
+    count: u32,
+}
+
+impl Interner {
+    /// The one table that every part of the program shares
+    fn global() -> &'static Mutex<Interner> {
+        static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+        INTERNER.get_or_init(|| {
+            let mut interner = Interner { ids: HashMap::new(), aliases: HashMap::new(), count: 0 };
+            for name in PREDEFINED {
+                interner.intern(name);
+            }
+            Mutex::new(interner)
+        })
+    }
+
+    fn intern(&mut self, text: &str) -> Symbol {
+        if let Some(&symbol) = self.ids.get(text) {
+            return symbol;
+        }
+        let text: &'static str = Box::leak(text.to_string().into_boxed_str());
+        let symbol = self.add(text, None);
+        self.ids.insert(text, symbol);
+        symbol
+    }
+
+    /// Gives out the next number, with some text
+    fn add(&mut self, text: &'static str, alias_of: Option<Symbol>) -> Symbol {
+        let symbol = Symbol(self.count);
+        Names::global().set(symbol.0, Name { text, alias_of });
+        self.count = self.count.checked_add(1).expect("too many symbols");
+        symbol
+    }
+}
+
+/// How many names the first chunk of `Names` holds. Each chunk after it
+/// holds twice as many as the one before.
+const FIRST_CHUNK: usize = 64;
+/// Enough chunks for every `u32`
+const CHUNKS: usize = 27;
+
+/// The text of every symbol by its number, which can be read without the lock
+///
+/// Only the `Interner` adds names, while it holds the lock. The names go in
+/// chunks that double in size, so that a name never moves once it has been
+/// added, and each slot in a chunk is only ever filled in once.
+struct Names {
+    chunks: [OnceLock<Box<[OnceLock<Name>]>>; CHUNKS],
+}
+
+#[derive(Clone, Copy)]
+struct Name {
+    text: &'static str,
+    /// The name that this is the `global_alias` of
+    alias_of: Option<Symbol>,
+}
+
+impl Names {
+    fn global() -> &'static Names {
+        // A `OnceLock` isn't `Copy`, so the array is filled in from a constant
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: OnceLock<Box<[OnceLock<Name>]>> = OnceLock::new();
+        static NAMES: Names = Names { chunks: [EMPTY; CHUNKS] };
+        &NAMES
+    }
+
+    /// Which chunk the name with this number is in, and where in the chunk
+    fn locate(index: u32) -> (usize, usize) {
+        let index = index as usize;
+        let chunk = (index / FIRST_CHUNK + 1).ilog2() as usize;
+        (chunk, index - FIRST_CHUNK * ((1 << chunk) - 1))
+    }
+
+    fn get(&self, index: u32) -> Option<Name> {
+        let (chunk, slot) = Names::locate(index);
+        self.chunks[chunk].get()?[slot].get().copied()
+    }
+
+    fn set(&self, index: u32, name: Name) {
+        let (chunk, slot) = Names::locate(index);
+        let chunk = self.chunks[chunk].get_or_init(|| (0..FIRST_CHUNK << chunk).map(|_| OnceLock::new()).collect());
+        let _ = chunk[slot].set(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_predefined_symbols() {
+        let constants = [
+            Symbol::QUOTE,
+            Symbol::IF,
+            Symbol::DEFINE,
+            Symbol::SET,
+            Symbol::LAMBDA,
+            Symbol::BEGIN,
+            Symbol::LET,
+            Symbol::LET_STAR,
+            Symbol::LETREC,
+            Symbol::QUASIQUOTE,
+            Symbol::UNQUOTE,
+            Symbol::UNQUOTE_SPLICING,
+            Symbol::DOT,
+            Symbol::COND,
+            Symbol::ELSE,
+            Symbol::DEFMACRO,
+            Symbol::DEFINE_SYNTAX,
+            Symbol::SYNTAX_RULES,
+            Symbol::ELLIPSIS,
+            Symbol::UNDERSCORE,
+            Symbol::MACROEXPAND_1,
+            Symbol::MACROEXPAND,
+        ];
+        assert_eq!(constants.len(), PREDEFINED.len());
+        for (symbol, name) in constants.iter().zip(PREDEFINED) {
+            assert_eq!(Symbol::intern(name), *symbol);
+            assert_eq!(symbol.as_str(), *name);
+        }
+    }
+
+    #[test]
+    fn test_interning_from_many_threads() {
+        let names: Vec<String> = (0..100).map(|i| format!("name-{}", i)).collect();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let names = names.clone();
+                std::thread::spawn(move || names.iter().map(|name| Symbol::intern(name)).collect::<Vec<_>>())
+            })
+            .collect();
+        let results: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        for symbols in &results {
+            assert_eq!(symbols, &results[0]);
+        }
+        for (symbol, name) in results[0].iter().zip(&names) {
+            assert_eq!(symbol.as_str(), name);
+            assert_eq!(symbol.to_string(), *name);
+        }
+        assert_eq!(format!("{:?}", Symbol::intern("a b")), "Symbol(\"a b\")");
+    }
+
+    #[test]
+    fn test_names_chunks() {
+        assert_eq!(Names::locate(0), (0, 0));
+        assert_eq!(Names::locate(63), (0, 63));
+        assert_eq!(Names::locate(64), (1, 0));
+        assert_eq!(Names::locate(191), (1, 127));
+        assert_eq!(Names::locate(192), (2, 0));
+        assert_eq!(Names::locate(u32::MAX).0, CHUNKS - 1);
+
+        // Reading names while another thread adds more
+        let writer = std::thread::spawn(|| (0..1000).map(|i| Symbol::intern(&format!("chunk-{}", i))).collect::<Vec<_>>());
+        for _ in 0..1000 {
+            assert_eq!(Symbol::QUOTE.as_str(), "quote");
+            assert_eq!(Symbol::intern("chunk-reader").as_str(), "chunk-reader");
+        }
+        for (i, symbol) in writer.join().unwrap().into_iter().enumerate() {
+            assert_eq!(symbol.as_str(), format!("chunk-{}", i));
+        }
+    }
+
+    #[test]
+    fn test_gensym() {
+        let tmp = Symbol::intern("tmp");
+        let renamed = tmp.gensym();
+        assert_ne!(renamed, tmp);
+        assert_ne!(renamed, tmp.gensym());
+        assert_eq!(renamed.as_str(), "tmp");
+        assert_eq!(Symbol::intern("tmp"), tmp);
+        // Gensyms share the text of the symbol they were made from
+        assert!(std::ptr::eq(renamed.as_str(), tmp.as_str()));
+    }
+
+    #[test]
+    fn test_global_alias() {
+        let list = Symbol::intern("list");
+        let alias = list.global_alias();
+        assert_ne!(alias, list);
+        assert_eq!(alias.as_str(), "list");
+        assert_eq!(alias.aliased(), Some(list));
+        assert_eq!(list.aliased(), None);
+        assert_eq!(list.gensym().aliased(), None);
+        assert_eq!(Symbol::QUOTE.aliased(), None);
+        // Every alias of a name is the same symbol, and an alias is its own alias
+        assert_eq!(list.global_alias(), alias);
+        assert_eq!(alias.global_alias(), alias);
+    }
+}
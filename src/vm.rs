@@ -0,0 +1,450 @@
+//! A stack-based virtual machine that runs the bytecode from the `compiler`
+//!
+//! The machine has one stack of values, shared by every call. Each call
+//! gets its own part of the stack, starting with its arguments and then
+//! its local variables, and works on whatever is above them:
+//!
+//! ```text
+//!           (f 1 2) calls f      f calls (g x)
+//! stack:    ... f 1 2            ... f 1 2 y g x
+//!                 ^ base                     ^ base of g's call
+//! ```
+//!
+//! When a call returns, its part of the stack is thrown away and the
+//! result is left where the procedure used to be.
+//!
+//! A closure can outlive the call that made it, but the variables it
+//! captured live in that call's part of the stack. So each captured
+//! variable is an "upvalue", which points into the stack while the call is
+//! still running, and gets its own copy of the value (is "closed") when
+//! the variable goes away.
+
+use crate::compiler::{self, Capture, Function, Op};
+use crate::eval::{self, EvalError, Value};
+use crate::gc;
+use crate::lexer::{Lexer, Span};
+use crate::macros::{self, Expander, Runtime};
+use crate::parser::{Expr, Parser};
+use crate::symbol::Symbol;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A compiled function together with the variables it captured
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub(crate) upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+/// A variable captured by a closure
+pub(crate) enum Upvalue {
+    /// The variable is still on the stack, at this index
+    Open(usize),
+    /// The variable's call has returned, so the upvalue keeps the value itself
+    Closed(Value),
+}
+
+/// A call that is running, or waiting for a call it made to return
+struct Frame {
+    closure: Rc<Closure>,
+    /// The index of the next instruction to run
+    ip: usize,
+    /// Where the call's part of the stack starts, which is its first argument
+    base: usize,
+}
+
+/// A virtual machine, which holds on to the global variables between runs
+///
+/// ```
+/// use csh_seminar_feb_2021::vm::Vm;
+///
+/// let mut vm = Vm::new();
+/// vm.eval_str("(define (square x) (* x x))").unwrap();
+/// let result = vm.eval_str("(square 12)").unwrap();
+/// assert_eq!(result.to_string(), "144");
+/// ```
+pub struct Vm {
+    globals: HashMap<Symbol, Value>,
+    stack: Vec<Value>,
+    /// The calls that are waiting for the current one to return
+    frames: Vec<Frame>,
+    /// Upvalues that still point into the stack, so closures made later can share them
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    /// Expands macros before the code is compiled. The procedures that
+    /// `defmacro` makes are compiled and run on this machine, like the rest
+    /// of the program.
+    macros: Expander,
+}
+
+impl Vm {
+    /// A new machine with all of the builtin procedures defined
+    pub fn new() -> Vm {
+        let globals = eval::BUILTINS.iter()
+            .map(|builtin| (Symbol::intern(builtin.name), Value::Builtin(*builtin)))
+            .chain(macros::quasiquote_builtins().iter().map(|(name, builtin)| (*name, Value::Builtin(*builtin))))
+            .collect();
+        Vm { globals, stack: Vec::new(), frames: Vec::new(), open_upvalues: Vec::new(), macros: Expander::new() }
+    }
+
+    /// Parses, expands, compiles and runs every expression in the input, returning the value of the last one
+    pub fn eval_str(&mut self, input: &str) -> Result<Value, EvalError> {
+        let mut result = Value::Unspecified;
+        for expr in Parser::new(Lexer::new(input)) {
+            result = self.eval(&expr?)?;
+        }
+        Ok(result)
+    }
+
+    /// Expands, compiles and runs a single top-level expression
+    ///
+    /// Each expression is expanded just before it runs, so the macros in it
+    /// can use everything that the expressions before it defined.
+    pub fn eval(&mut self, expr: &Expr) -> Result<Value, EvalError> {
+        let expr = self.expand(expr)?;
+        let function = compiler::compile(std::slice::from_ref(&expr))?;
+        self.run(function)
+    }
+
+    /// Expands every macro in a top-level expression, remembering any macros it defines
+    pub fn expand(&mut self, expr: &Expr) -> Result<Expr, EvalError> {
+        // The expander runs the procedures from `defmacro` on this machine,
+        // so it can't be borrowed from the machine while it does
+        let mut macros = std::mem::take(&mut self.macros);
+        let expanded = macros.expand(expr, self);
+        self.macros = macros;
+        expanded
+    }
+
+    /// Runs a program from `compiler::compile`
+    pub fn run(&mut self, function: Rc<Function>) -> Result<Value, EvalError> {
+        let closure = gc::alloc(Closure { function, upvalues: Vec::new() });
+        self.stack.push(Value::Closure(closure.clone()));
+        let base = self.stack.len();
+        let result = self.execute(Frame { closure, ip: 0, base });
+        self.recover(result)
+    }
+
+    /// Throws away whatever the program was in the middle of, if it stopped with an error
+    fn recover(&mut self, result: Result<Value, EvalError>) -> Result<Value, EvalError> {
+        if result.is_err() {
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+        result
+    }
+
+    fn execute(&mut self, mut frame: Frame) -> Result<Value, EvalError> {
+        loop {
+            let chunk = &frame.closure.function.chunk;
+            let (op, span) = (chunk.code[frame.ip], chunk.spans[frame.ip]);
+            frame.ip += 1;
+            match op {
+                Op::Constant(index) => {
+                    let value = frame.closure.function.chunk.constants[index as usize].clone();
+                    self.stack.push(value);
+                }
+                Op::Unspecified => self.stack.push(Value::Unspecified),
+                Op::GetLocal(slot) => self.stack.push(self.stack[frame.base + slot as usize].clone()),
+                Op::SetLocal(slot) => self.stack[frame.base + slot as usize] = self.pop(),
+                Op::GetUpvalue(index) => {
+                    let value = match &*frame.closure.upvalues[index as usize].borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                Op::SetUpvalue(index) => {
+                    let value = self.pop();
+                    match &mut *frame.closure.upvalues[index as usize].borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                Op::GetGlobal(name) => match self.globals.get(&name) {
+                    Some(value) => self.stack.push(value.clone()),
+                    None => return Err(EvalError::UnboundVariable { name: name.to_string(), span }),
+                },
+                Op::DefineGlobal(name) => {
+                    let value = self.pop();
+                    self.globals.insert(name, value);
+                }
+                Op::SetGlobal(name) => {
+                    let value = self.pop();
+                    match self.globals.get_mut(&name) {
+                        Some(slot) => *slot = value,
+                        None => return Err(EvalError::UnboundVariable { name: name.to_string(), span }),
+                    }
+                }
+                Op::Dup => {
+                    let value = self.stack.last().expect("there is a value to copy").clone();
+                    self.stack.push(value);
+                }
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::EndScope(count) => {
+                    let value = self.pop();
+                    let end = self.stack.len() - count as usize;
+                    self.close_upvalues(end);
+                    self.stack.truncate(end);
+                    self.stack.push(value);
+                }
+                Op::Jump(target) => frame.ip = target as usize,
+                Op::JumpIfFalse(target) => {
+                    if !self.pop().is_truthy() {
+                        frame.ip = target as usize;
+                    }
+                }
+                Op::Call(count) => {
+                    if let Some(callee) = self.call(count as usize, span)? {
+                        let base = self.stack.len() - callee.function.arity - callee.function.variadic as usize;
+                        let caller = std::mem::replace(&mut frame, Frame { closure: callee, ip: 0, base });
+                        self.frames.push(caller);
+                    }
+                }
+                Op::TailCall(count) => {
+                    if let Some(callee) = self.call(count as usize, span)? {
+                        // Slide the procedure and its arguments down over the
+                        // current call, which has nothing left to do
+                        let slots = callee.function.arity + callee.function.variadic as usize;
+                        let start = self.stack.len() - slots - 1;
+                        self.close_upvalues(frame.base);
+                        self.stack.drain(frame.base - 1..start);
+                        frame = Frame { closure: callee, ip: 0, base: frame.base };
+                    }
+                }
+                Op::Closure(index) => {
+                    let function = frame.closure.function.chunk.functions[index as usize].clone();
+                    let upvalues = function.captures.iter()
+                        .map(|capture| match *capture {
+                            Capture::Local(slot) => self.capture(frame.base + slot as usize),
+                            Capture::Upvalue(index) => frame.closure.upvalues[index as usize].clone(),
+                        })
+                        .collect();
+                    self.stack.push(Value::Closure(gc::alloc(Closure { function, upvalues })));
+                }
+                Op::Return => {
+                    let result = self.pop();
+                    self.close_upvalues(frame.base);
+                    // Also throw away the procedure, which sits just below the arguments
+                    self.stack.truncate(frame.base - 1);
+                    match self.frames.pop() {
+                        Some(caller) => {
+                            frame = caller;
+                            self.stack.push(result);
+                        }
+                        None => return Ok(result),
+                    }
+                }
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("the compiler never pops more than it pushed")
+    }
+
+    /// Starts a call to the procedure under the top `count` values
+    ///
+    /// Builtins are called right away, leaving their result on the stack.
+    /// For a closure, the arguments are checked and made ready, and the
+    /// closure is returned so that the caller can start running it.
+    fn call(&mut self, count: usize, span: Span) -> Result<Option<Rc<Closure>>, EvalError> {
+        let start = self.stack.len() - count;
+        match &self.stack[start - 1] {
+            Value::Builtin(builtin) => {
+                let result = (builtin.func)(&self.stack[start..], span)?;
+                self.stack.truncate(start - 1);
+                self.stack.push(result);
+                Ok(None)
+            }
+            Value::Closure(closure) => {
+                let closure = closure.clone();
+                let (expected, variadic) = (closure.function.arity, closure.function.variadic);
+                if count < expected || (!variadic && count > expected) {
+                    return Err(EvalError::WrongArgCount { expected, variadic, got: count, span });
+                }
+                if variadic {
+                    let rest = self.stack.split_off(start + expected);
+                    self.stack.push(Value::list(rest));
+                }
+                Ok(Some(closure))
+            }
+            other => Err(EvalError::NotAProcedure { value: other.to_string(), span }),
+        }
+    }
+
+    /// The upvalue for the variable at this index in the stack, shared with
+    /// any closure that has already captured it
+    fn capture(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let existing = self.open_upvalues.iter().find(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(open) if open == slot));
+        if let Some(upvalue) = existing {
+            return upvalue.clone();
+        }
+        let upvalue = gc::alloc(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    /// Closes every upvalue for a variable at or above this index in the
+    /// stack, since those variables are about to go away
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                Upvalue::Open(slot) if slot >= from => {
+                    *upvalue = Upvalue::Closed(stack[slot].clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+}
+
+/// The VM compiles the procedures from `defmacro`, and runs them itself
+impl Runtime for Vm {
+    fn procedure(&mut self, name: Symbol, params: Vec<Symbol>, rest: Option<Symbol>, body: &[Expr], span: Span) -> Result<Value, EvalError> {
+        let function = compiler::compile_procedure(name, params, rest, body, span)?;
+        self.run(function)
+    }
+
+    fn apply(&mut self, procedure: &Value, args: &[Value], span: Span) -> Result<Value, EvalError> {
+        self.stack.push(procedure.clone());
+        self.stack.extend_from_slice(args);
+        let result = match self.call(args.len(), span) {
+            Ok(Some(closure)) => {
+                let base = self.stack.len() - closure.function.arity - closure.function.variadic as usize;
+                self.execute(Frame { closure, ip: 0, base })
+            }
+            Ok(None) => Ok(self.pop()),
+            Err(error) => Err(error),
+        };
+        self.recover(result)
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::Interpreter;
+
+    fn run(input: &str) -> Result<String, EvalError> {
+        Vm::new().eval_str(input).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn test_same_results_as_interpreter() {
+        let programs = [
+            "(+ 1 2 (* 3 4))",
+            "(cons 1 (list 2 \"three\" 'four))",
+            "(if (< 1 2) 'yes 'no)",
+            "(if #f 1)",
+            "(define x 1) (let ((x 2) (y x)) (+ x y))",
+            "(define x 1) (let* ((x 2) (y x)) (+ x y))",
+            "(letrec ((even? (lambda (n) (if (= n 0) #t (odd? (- n 1)))))
+                      (odd? (lambda (n) (if (= n 0) #f (even? (- n 1))))))
+               (even? 101))",
+            "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1))))) (fact 20)",
+            "((lambda args args) 1 2 3)",
+            "((lambda (a . rest) (list a rest)) 1 2 3)",
+            "(begin (define x 1) (set! x (+ x 1)) x)",
+            "(define (f) (define a 1) (define (g) (+ a 1)) (g)) (f)",
+            "(let ((f (lambda (x) x))) (f 'done))",
+            "(define (outer) (define (even? n) (if (= n 0) #t (odd? (- n 1)))) (define (odd? n) (if (= n 0) #f (even? (- n 1)))) (even? 10)) (outer)",
+            "(quote (a (b . c)))",
+            "(begin)",
+            "(define f (lambda (x) x)) f",
+            "(cond ((< 2 1) 'a) ((< 1 2) 'b) (else 'c))",
+            "(cond (#f 'a) (else 'c))",
+            "(cond ((+ 1 2)) (else 'c))",
+            "(cond (#f 'a) ((car '(#f))))",
+            "(cond (#f 'a))",
+            "(define (f x) (cond ((= x 0) 'zero) ((< x 0)) (else (f (- x 1))))) (list (f 3) (f -1))",
+            "(cond (else 1) (#t 2))",
+            "(define b 2) `(a ,b ,@(list 3 4) . 5)",
+        ];
+        for program in &programs {
+            let expected = Interpreter::new().eval_str(program).map(|value| value.to_string());
+            assert_eq!(run(program), expected, "{}", program);
+        }
+    }
+
+    #[test]
+    fn test_closures_share_captured_variables() {
+        let program = "
+            (define (make-counter)
+              (define count 0)
+              (list (lambda () (set! count (+ count 1)) count)
+                    (lambda () count)))
+            (define counter (make-counter))
+            ((car counter))
+            ((car counter))
+            (list ((car counter)) ((car (cdr counter))))";
+        assert_eq!(run(program).unwrap(), "(3 3)");
+
+        // Each run of a `let` gets its own variables
+        let program = "
+            (define (make x) (let ((y (* x 10))) (lambda () (set! y (+ y 1)) y)))
+            (define a (make 1))
+            (define b (make 2))
+            (a) (b) (a)
+            (list (a) (b))";
+        assert_eq!(run(program).unwrap(), "(13 22)");
+    }
+
+    #[test]
+    fn test_tail_calls_and_deep_recursion() {
+        // A loop of tail calls runs in constant stack space
+        let mut vm = Vm::new();
+        let program = "
+            (define (loop i total) (if (= i 0) total (loop (- i 1) (+ total i))))
+            (loop 100000 0)";
+        assert_eq!(vm.eval_str(program).unwrap().to_string(), "5000050000");
+        assert!(vm.stack.capacity() < 100);
+
+        // Calls that aren't in tail position don't use up the Rust stack either
+        let program = "(define (count n) (if (= n 0) 0 (+ 1 (count (- n 1))))) (count 100000)";
+        assert_eq!(run(program).unwrap(), "100000");
+
+        // Nor does throwing away a long list
+        let program = "
+            (define (build n acc) (if (= n 0) acc (build (- n 1) (cons n acc))))
+            (build 100000 '())
+            (define xs (build 100000 '()))
+            (set! xs #f)
+            (equal? (build 100000 '()) (build 100000 '()))";
+        assert_eq!(run(program).unwrap(), "#t");
+    }
+
+    #[test]
+    fn test_errors() {
+        let input = "(define (f x) (+ x y))\n(f 1)";
+        let error = run(input).unwrap_err();
+        assert_eq!(error, EvalError::UnboundVariable { name: "y".to_string(), span: error.span() });
+        assert_eq!(&input[error.span().start..error.span().end], "y");
+
+        let error = run("(define (f x) x) (f)").unwrap_err();
+        assert_eq!(error.to_string(), "expected 1 argument, got 0");
+        assert_eq!(error.span().start_pos.column, 18);
+
+        assert!(matches!(run("(1 2)").unwrap_err(), EvalError::NotAProcedure { .. }));
+        assert!(matches!(run("(/ 1 0)").unwrap_err(), EvalError::DivisionByZero { .. }));
+        assert!(matches!(run("(/ -9223372036854775808 -1)").unwrap_err(), EvalError::IntegerOverflow { .. }));
+        assert!(matches!(run("(set! nope 1)").unwrap_err(), EvalError::UnboundVariable { .. }));
+        assert!(matches!(run("(f").unwrap_err(), EvalError::Parse(_)));
+
+        // The machine can still be used after an error
+        let mut vm = Vm::new();
+        assert!(vm.eval_str("(define x 1) (car x)").is_err());
+        assert_eq!(vm.eval_str("(+ x 1)").unwrap().to_string(), "2");
+    }
+}